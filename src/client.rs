@@ -0,0 +1,200 @@
+use crate::config::Config;
+use crate::crypto::{Signed,Verifier};
+use crate::dto::{ID,NodeID,Timestamp,Request,Reply};
+use crate::state_machine::StateMachine;
+use crate::sufficiency::SufficiencyChecker;
+use crate::view::primary;
+use std::collections::{HashMap,HashSet};
+use std::sync::Arc;
+
+// Client side of the protocol: issues requests and waits for f+1 matching replies,
+// at least one of them comes from a correct replica.
+// Read-only requests skip ordering and tentative replies come before commit,
+// both need 2f+1 matching replies instead
+#[derive(Debug)]
+pub struct Client<S: StateMachine> {
+    id: ID,
+    timestamp: Timestamp, // last used t
+    view_id: ID, // latest view seen in replies
+    all_nodes: HashSet<ID>,
+    replies: HashMap<Timestamp, HashMap<NodeID, Reply<S::Result>>>,
+    results: HashMap<Timestamp, S::Result>,
+    reads: HashMap<Timestamp, Request<S::Operation>>, // read-only requests still waiting for 2f+1
+    requests: HashMap<Timestamp, Request<S::Operation>>, // ordered requests still waiting for a result
+    fallen_back: HashMap<Timestamp, Timestamp>, // ordered request -> the read it stands in for
+    quorum: Arc<dyn SufficiencyChecker>, // 2f+1, counted like the replicas count it
+    weak_certificate: Arc<dyn SufficiencyChecker>, // f+1
+    verifier: Arc<dyn Verifier>, // a reply counts for the replica that signed it, not the one it names
+}
+
+impl<S: StateMachine> Client<S> {
+    pub fn new(id: ID, all_nodes: HashSet<ID>, verifier: Arc<dyn Verifier>) -> Client<S> {
+        Client::from_config(id, all_nodes, &Config::default(), verifier)
+    }
+
+    // f+1 and 2f+1 follow from the f and the weights the replicas are configured with,
+    // not from |R|: 6 replicas with f = 1 take 2 matching committed replies
+    pub fn from_config(id: ID, all_nodes: HashSet<ID>, config: &Config, verifier: Arc<dyn Verifier>) -> Client<S> {
+        Client::with_checkers(id, all_nodes, config.quorum(), config.weak_certificate(), verifier)
+    }
+
+    // With voting weights a handful of light replicas must not outvote the heavy ones
+    pub fn with_checkers(id: ID, all_nodes: HashSet<ID>, quorum: Arc<dyn SufficiencyChecker>, weak_certificate: Arc<dyn SufficiencyChecker>, verifier: Arc<dyn Verifier>) -> Client<S> {
+        Client{
+            id,
+            timestamp: 0,
+            view_id: 0,
+            all_nodes,
+            replies: HashMap::new(),
+            results: HashMap::new(),
+            reads: HashMap::new(),
+            requests: HashMap::new(),
+            fallen_back: HashMap::new(),
+            quorum,
+            weak_certificate,
+            verifier,
+        }
+    }
+
+    pub fn get_id(&self) -> ID {
+        self.id
+    }
+
+    // Replicas of the current configuration, told by the administrator that changed it
+    pub fn set_nodes(&mut self, all_nodes: HashSet<ID>) {
+        self.all_nodes = all_nodes;
+    }
+
+    // Keys of the replicas after one of them joined or recovered
+    pub fn set_verifier(&mut self, verifier: Arc<dyn Verifier>) {
+        self.verifier = verifier;
+    }
+
+    // Requests are sent to the primary of the latest view the client knows about
+    pub fn get_primary(&self) -> Option<NodeID> {
+        primary(self.view_id, &self.all_nodes)
+    }
+
+    pub fn new_request(&mut self, op: S::Operation) -> Request<S::Operation> {
+        self.timestamp += 1;
+        let request = Request::new(op, self.timestamp, self.id);
+        self.requests.insert(self.timestamp, request.clone());
+        request
+    }
+
+    // The same request again, same timestamp included: replicas that executed it
+    // answer from their reply cache instead of executing it twice
+    pub fn retry(&self, timestamp: Timestamp) -> Option<Request<S::Operation>> {
+        self.requests.get(&timestamp).cloned()
+    }
+
+    // Multicast to every replica
+    pub fn new_read_request(&mut self, op: S::Operation) -> Request<S::Operation> {
+        self.timestamp += 1;
+        let request = Request::read_only(op, self.timestamp, self.id);
+        self.reads.insert(self.timestamp, request.clone());
+        request
+    }
+
+    // Returns the result once f+1 replicas have sent the same one (2f+1 for reads)
+    pub fn handle_reply(&mut self, reply: &Reply<S::Result>) -> Option<S::Result> {
+        if reply.get_client_id() != self.id || !self.all_nodes.contains(&reply.get_replica_id()) || !reply.verify(&*self.verifier) {
+            return None;
+        }
+        // late answers to a read that already went through ordering
+        if reply.is_read_only() && !self.reads.contains_key(&reply.get_timestamp()) {
+            return self.results.get(&reply.get_timestamp()).cloned();
+        }
+        if let Some(result) = self.results.get(&reply.get_timestamp()) {
+            return Some(result.clone());
+        }
+        self.replies.entry(reply.get_timestamp()).or_default().insert(reply.get_replica_id(), reply.clone());
+        self.follow_view(reply.get_timestamp());
+        let replies = &self.replies[&reply.get_timestamp()];
+        let matching: HashSet<ID> = replies.values()
+            .filter(|r| r.get_result() == reply.get_result())
+            .map(|r| r.get_replica_id())
+            .collect();
+        let committed: HashSet<ID> = replies.values()
+            .filter(|r| r.get_result() == reply.get_result() && !r.is_tentative() && !r.is_read_only())
+            .map(|r| r.get_replica_id())
+            .collect();
+        if !self.weak_certificate.is_sufficient(&self.all_nodes, &committed) && !self.quorum.is_sufficient(&self.all_nodes, &matching) {
+            return None;
+        }
+        self.replies.remove(&reply.get_timestamp());
+        self.reads.remove(&reply.get_timestamp());
+        self.requests.remove(&reply.get_timestamp());
+        self.results.insert(reply.get_timestamp(), reply.get_result());
+        if let Some(read_timestamp) = self.fallen_back.remove(&reply.get_timestamp()) {
+            self.results.insert(read_timestamp, reply.get_result());
+        }
+        Some(reply.get_result())
+    }
+
+    // A later view counts once f+1 replicas report it or one after it, a faulty
+    // replica alone can't point the client at a primary of its choice
+    fn follow_view(&mut self, timestamp: Timestamp) {
+        let replies = match self.replies.get(&timestamp) {
+            Some(replies) => replies,
+            None => return,
+        };
+        let mut views: Vec<ID> = replies.values()
+            .map(|r| r.get_view_id())
+            .filter(|view_id| *view_id > self.view_id)
+            .collect();
+        views.sort_unstable();
+        views.dedup();
+        let followed = views.into_iter().rev().find(|view_id| {
+            let reporting: HashSet<ID> = replies.values()
+                .filter(|r| r.get_view_id() >= *view_id)
+                .map(|r| r.get_replica_id())
+                .collect();
+            self.weak_certificate.is_sufficient(&self.all_nodes, &reporting)
+        });
+        if let Some(view_id) = followed {
+            self.view_id = view_id;
+        }
+    }
+
+    pub fn get_result(&self, timestamp: Timestamp) -> Option<S::Result> {
+        self.results.get(&timestamp).cloned()
+    }
+
+    // A read can't succeed once the replicas that haven't answered
+    // are too few to make the most popular result reach 2f+1
+    pub fn is_read_stuck(&self, timestamp: Timestamp) -> bool {
+        if !self.reads.contains_key(&timestamp) {
+            return false;
+        }
+        let replies = match self.replies.get(&timestamp) {
+            Some(replies) => replies,
+            None => return false,
+        };
+        let silent: HashSet<ID> = self.all_nodes.iter()
+            .filter(|id| !replies.contains_key(id))
+            .copied()
+            .collect();
+        !replies.values().any(|reply| {
+            let mut possible: HashSet<ID> = replies.values()
+                .filter(|r| r.get_result() == reply.get_result())
+                .map(|r| r.get_replica_id())
+                .collect();
+            possible.extend(silent.iter());
+            self.quorum.is_sufficient(&self.all_nodes, &possible)
+        })
+    }
+
+    // Gives up on the fast path: the read is sent again to the primary to be ordered.
+    // A write of ours may have executed since, so it needs a timestamp after that one.
+    // Its result counts for the read too
+    pub fn fall_back(&mut self, timestamp: Timestamp) -> Option<Request<S::Operation>> {
+        let read = self.reads.remove(&timestamp)?;
+        self.replies.remove(&timestamp);
+        self.timestamp += 1;
+        let request = Request::new(read.get_op().clone(), self.timestamp, self.id);
+        self.requests.insert(self.timestamp, request.clone());
+        self.fallen_back.insert(self.timestamp, timestamp);
+        Some(request)
+    }
+}
@@ -0,0 +1,166 @@
+#[cfg(test)]
+mod reply_counting_test {
+    use crate::client::Client;
+    use crate::config::Config;
+    use crate::crypto::{KeyRing,Signed};
+    use crate::dto::{ID,Tip,Request,Reply};
+    use crate::test_util::{new_nodes,CLIENT_ID};
+    use crate::state_machine::TipMachine;
+
+    // 7 has a key but isn't a replica of the client's configuration
+    fn key_ring() -> KeyRing {
+        KeyRing::generate(&new_nodes(8), 0)
+    }
+
+    fn client(size: usize, config: &Config) -> Client<TipMachine> {
+        Client::from_config(CLIENT_ID, new_nodes(size), config, key_ring().get_verifier())
+    }
+
+    fn signed_by(signer: ID, mut reply: Reply<Tip>) -> Reply<Tip> {
+        reply.sign(&*key_ring().get_signer(signer).unwrap());
+        reply
+    }
+
+    fn reply_from(request: &Request<Tip>, replica: ID, result: &str) -> Reply<Tip> {
+        signed_by(replica, request.make_reply(0, replica, result.to_owned()))
+    }
+
+    fn tentative_from(request: &Request<Tip>, replica: ID, result: &str) -> Reply<Tip> {
+        signed_by(replica, request.make_reply(0, replica, result.to_owned()).into_tentative())
+    }
+
+    #[test]
+    fn should_accept_f_plus_one_matching_replies() {
+        let mut client = client(4, &Config::default());
+        let request = client.new_request("op".to_owned());
+        assert_eq!(client.handle_reply(&reply_from(&request, 0, "op")), None);
+        assert_eq!(client.handle_reply(&reply_from(&request, 1, "op")), Some("op".to_owned()));
+        assert_eq!(client.get_result(request.get_timestamp()), Some("op".to_owned()));
+    }
+
+    #[test]
+    fn retry_should_repeat_request_until_answered() {
+        let mut client = client(4, &Config::default());
+        let request = client.new_request("op".to_owned());
+        assert_eq!(client.retry(request.get_timestamp()), Some(request.clone()));
+        client.handle_reply(&reply_from(&request, 0, "op"));
+        client.handle_reply(&reply_from(&request, 1, "op"));
+        assert_eq!(client.retry(request.get_timestamp()), None);
+    }
+
+    #[test]
+    fn should_not_count_mismatching_or_repeated_replies() {
+        let mut client = client(4, &Config::default());
+        let request = client.new_request("op".to_owned());
+        assert_eq!(client.handle_reply(&reply_from(&request, 0, "op")), None);
+        assert_eq!(client.handle_reply(&reply_from(&request, 0, "op")), None);
+        assert_eq!(client.handle_reply(&reply_from(&request, 1, "evil")), None);
+        assert_eq!(client.handle_reply(&reply_from(&request, 7, "op")), None);
+        assert_eq!(client.get_result(request.get_timestamp()), None);
+    }
+
+    #[test]
+    fn read_should_need_two_thirds_matching_replies() {
+        let mut client = client(4, &Config::default());
+        let request = client.new_read_request("op".to_owned());
+        assert!(request.is_read_only());
+        assert_eq!(client.handle_reply(&reply_from(&request, 0, "op")), None);
+        assert_eq!(client.handle_reply(&reply_from(&request, 1, "op")), None);
+        assert_eq!(client.handle_reply(&reply_from(&request, 2, "op")), Some("op".to_owned()));
+        assert!(!client.is_read_stuck(request.get_timestamp()));
+        assert_eq!(client.fall_back(request.get_timestamp()), None);
+    }
+
+    #[test]
+    fn diverging_read_should_fall_back_to_ordering() {
+        let mut client = client(4, &Config::default());
+        let request = client.new_read_request("op".to_owned());
+        client.handle_reply(&reply_from(&request, 0, "a"));
+        client.handle_reply(&reply_from(&request, 1, "b"));
+        assert!(!client.is_read_stuck(request.get_timestamp()));
+        client.handle_reply(&reply_from(&request, 2, "c"));
+        // 3 alone can't make any result reach 2f+1 anymore
+        assert!(client.is_read_stuck(request.get_timestamp()));
+        let ordered = client.fall_back(request.get_timestamp()).unwrap();
+        assert!(!ordered.is_read_only());
+        assert!(ordered.get_timestamp() > request.get_timestamp());
+        // late fast path replies don't count towards the ordered result
+        assert_eq!(client.handle_reply(&reply_from(&request, 3, "a")), None);
+        assert_eq!(client.handle_reply(&reply_from(&ordered, 0, "a")), None);
+        assert_eq!(client.handle_reply(&reply_from(&ordered, 2, "a")), Some("a".to_owned()));
+        assert_eq!(client.get_result(request.get_timestamp()), Some("a".to_owned()));
+    }
+
+    #[test]
+    fn tentative_replies_should_need_two_thirds() {
+        let mut client = client(4, &Config::default());
+        let request = client.new_request("op".to_owned());
+        assert_eq!(client.handle_reply(&tentative_from(&request, 0, "op")), None);
+        assert_eq!(client.handle_reply(&tentative_from(&request, 1, "op")), None);
+        assert_eq!(client.handle_reply(&tentative_from(&request, 2, "op")), Some("op".to_owned()));
+        // committed replies still need only f+1, even next to tentative ones
+        let request = client.new_request("op".to_owned());
+        assert_eq!(client.handle_reply(&tentative_from(&request, 0, "op")), None);
+        assert_eq!(client.handle_reply(&reply_from(&request, 1, "op")), None);
+        assert_eq!(client.handle_reply(&reply_from(&request, 0, "op")), Some("op".to_owned()));
+    }
+
+    #[test]
+    fn thresholds_should_follow_configured_fault_threshold() {
+        let config = Config{fault_threshold: Some(1), ..Config::default()};
+        let mut client = client(6, &config);
+        // f+1 = 2 committed replies, not a third of 6
+        let request = client.new_request("op".to_owned());
+        assert_eq!(client.handle_reply(&reply_from(&request, 0, "op")), None);
+        assert_eq!(client.handle_reply(&reply_from(&request, 1, "op")), Some("op".to_owned()));
+        // a read needs a quorum: half of 6 plus f+1
+        let read = client.new_read_request("read".to_owned());
+        for id in 0..3 {
+            assert_eq!(client.handle_reply(&reply_from(&read, id, "read")), None);
+        }
+        assert_eq!(client.handle_reply(&reply_from(&read, 3, "read")), Some("read".to_owned()));
+    }
+
+    #[test]
+    fn weighted_replies_should_count_votes_not_heads() {
+        // 0 holds 5 of the 8 votes
+        let config = Config{voting_weights: Some(vec![(0 as ID, 5)].into_iter().collect()), ..Config::default()};
+        let mut client = client(4, &config);
+        let request = client.new_request("op".to_owned());
+        // f+1 heads, but 2 of 8 votes could all be faulty
+        assert_eq!(client.handle_reply(&reply_from(&request, 1, "forged")), None);
+        assert_eq!(client.handle_reply(&reply_from(&request, 2, "forged")), None);
+        assert_eq!(client.handle_reply(&reply_from(&request, 0, "op")), Some("op".to_owned()));
+        // reads need more than two thirds of the votes, three light replicas aren't
+        let read = client.new_read_request("read".to_owned());
+        for id in 1..4 {
+            assert_eq!(client.handle_reply(&reply_from(&read, id, "forged")), None);
+        }
+        assert!(!client.is_read_stuck(read.get_timestamp()));
+    }
+
+    #[test]
+    fn should_follow_view_reported_by_f_plus_one() {
+        let mut client = client(4, &Config::default());
+        assert_eq!(client.get_primary(), Some(0));
+        let request = client.new_request("op".to_owned());
+        // a single replica may be the faulty one
+        client.handle_reply(&signed_by(1, request.make_reply(3, 1, "op".to_owned())));
+        assert_eq!(client.get_primary(), Some(0));
+        client.handle_reply(&signed_by(2, request.make_reply(2, 2, "op".to_owned())));
+        assert_eq!(client.get_primary(), Some(2));
+    }
+
+    #[test]
+    fn should_drop_replies_signed_by_someone_else() {
+        let mut client = client(4, &Config::default());
+        let request = client.new_request("op".to_owned());
+        // 0 forges a reply in the name of 1
+        assert_eq!(client.handle_reply(&reply_from(&request, 0, "forged")), None);
+        assert_eq!(client.handle_reply(&signed_by(0, request.make_reply(0, 1, "forged".to_owned()))), None);
+        assert_eq!(client.handle_reply(&request.make_reply(0, 2, "forged".to_owned())), None);
+        assert_eq!(client.get_result(request.get_timestamp()), None);
+        assert_eq!(client.handle_reply(&reply_from(&request, 1, "op")), None);
+        assert_eq!(client.handle_reply(&reply_from(&request, 2, "op")), Some("op".to_owned()));
+    }
+}
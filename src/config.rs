@@ -0,0 +1,65 @@
+use crate::dto::{ID};
+use crate::sufficiency::{SufficiencyChecker,WeightedQuorum,quorum,weak_certificate};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+// How nodes convince each other who sent a message
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Authentication {
+    Signatures, // every message is checked against the sender's signature
+    Authenticators, // messages between nodes are checked against a vector of pairwise MACs
+}
+
+// Tunables shared by every node of the network
+#[derive(Debug,Clone)]
+pub struct Config {
+    pub checkpoint_interval: ID, // K -- a checkpoint is taken every K sequence numbers
+    pub log_size: ID, // L -- accepted seqs are h < n <= h + L, h is the last stable checkpoint
+    pub authentication: Authentication,
+    pub max_batch_size: usize, // the primary orders pending requests once this many are waiting
+    pub max_batch_delay: Duration, // ... or once the oldest of them has waited this long
+    pub tentative_execution: bool, // execute and reply once prepared, roll back if a view change discards it
+    pub recovery_period: Option<Duration>, // every replica recovers proactively once per period, at most f at a time
+    pub view_change_timeout: Option<Duration>, // a backup whose request didn't execute this long moves to the next view, twice as long for every view after
+    pub fault_threshold: Option<usize>, // f -- replicas that may be faulty at once, |R| >= 3f+1; none means the most |R| tolerates
+    pub voting_weights: Option<HashMap<ID, u64>>, // quorums need more than two thirds of the total weight and f+1 more than a third, a replica left out weighs 1; none counts heads
+    pub wal_dir: Option<PathBuf>, // every replica logs what it receives and sends to a file here and replays it on restart; none keeps it all in memory
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config{
+            checkpoint_interval: 100,
+            log_size: 200,
+            authentication: Authentication::Signatures,
+            max_batch_size: 10,
+            max_batch_delay: Duration::from_millis(10),
+            tentative_execution: false,
+            recovery_period: None,
+            view_change_timeout: Some(Duration::from_secs(1)),
+            fault_threshold: None,
+            voting_weights: None,
+            wal_dir: None,
+        }
+    }
+}
+
+impl Config {
+    // 2f+1, by voting weight if there are weights. Replicas and clients count the same way
+    pub fn quorum(&self) -> Arc<dyn SufficiencyChecker> {
+        match &self.voting_weights {
+            Some(weights) => Arc::new(WeightedQuorum::new(weights.clone())),
+            None => Arc::new(quorum(self.fault_threshold)),
+        }
+    }
+
+    // f+1, by voting weight if there are weights
+    pub fn weak_certificate(&self) -> Arc<dyn SufficiencyChecker> {
+        match &self.voting_weights {
+            Some(weights) => Arc::new(WeightedQuorum::weak(weights.clone())),
+            None => Arc::new(weak_certificate(self.fault_threshold)),
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use crate::dto::{ID,NodeID,Sig};
+use crate::encoding::Encode;
+use crate::hash::{hmac_sha256,sha256,to_hex};
+use std::collections::{HashMap,HashSet};
+use std::fmt;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+pub type Key = Vec<u8>;
+
+// sigma(i) -- produced by node i over the canonical bytes of a message
+pub trait Signer: Debug + Send + Sync {
+    fn get_id(&self) -> NodeID;
+    fn sign(&self, bytes: &[u8]) -> Sig;
+}
+
+// Checks sigma(i) against what is known about node i
+pub trait Verifier: Debug + Send + Sync {
+    fn verify(&self, signer_id: NodeID, bytes: &[u8], signature: &Sig) -> bool;
+}
+
+// A message that carries the signature of its sender
+pub trait Signed: Encode {
+    fn get_signer_id(&self) -> NodeID;
+    fn get_signature(&self) -> Sig;
+    fn set_signature(&mut self, signature: Sig);
+
+    fn sign(&mut self, signer: &dyn Signer) {
+        let signature = signer.sign(&self.to_bytes());
+        self.set_signature(signature)
+    }
+
+    fn verify(&self, verifier: &dyn Verifier) -> bool {
+        verifier.verify(self.get_signer_id(), &self.to_bytes(), &self.get_signature())
+    }
+}
+
+// HMAC based scheme for tests and simulations: the verification key of a node
+// is its signing key, so whoever holds the key ring could sign for anybody.
+// Real deployments plug in an asymmetric scheme through the same traits.
+pub struct HmacSigner {
+    id: NodeID,
+    key: Key,
+}
+
+pub struct HmacVerifier {
+    keys: HashMap<NodeID, Key>,
+}
+
+// Keys stay out of the state dumps
+impl Debug for HmacSigner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HmacSigner {{ id: {:?} }}", self.id)
+    }
+}
+
+impl Debug for HmacVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HmacVerifier {{ nodes: {:?} }}", self.keys.keys().collect::<Vec<&NodeID>>())
+    }
+}
+
+impl Signer for HmacSigner {
+    fn get_id(&self) -> NodeID {
+        self.id
+    }
+    fn sign(&self, bytes: &[u8]) -> Sig {
+        to_hex(&hmac_sha256(&self.key, bytes))
+    }
+}
+
+impl Verifier for HmacVerifier {
+    fn verify(&self, signer_id: NodeID, bytes: &[u8], signature: &Sig) -> bool {
+        match self.keys.get(&signer_id) {
+            Some(key) => constant_time_eq(to_hex(&hmac_sha256(key, bytes)).as_bytes(), signature.as_bytes()),
+            None => false,
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// <alpha(i,1), ..., alpha(i,n)> -- one MAC per recipient, each recipient checks only its own
+pub type Authenticator = HashMap<NodeID, Sig>;
+
+// Keys node i shares with every other node j: k(i,j) == k(j,i)
+pub struct SessionKeys {
+    id: NodeID,
+    keys: HashMap<NodeID, Key>,
+}
+
+impl Debug for SessionKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SessionKeys {{ id: {:?}, peers: {:?} }}", self.id, self.keys.keys().collect::<Vec<&NodeID>>())
+    }
+}
+
+impl SessionKeys {
+    pub fn authenticate<'a, I>(&self, bytes: &[u8], recipients: I) -> Authenticator
+    where I: Iterator<Item = &'a NodeID> {
+        recipients
+            .filter_map(|recipient| {
+                self.keys.get(recipient).map(|key| (*recipient, to_hex(&hmac_sha256(key, bytes))))
+            })
+            .collect()
+    }
+
+    pub fn check(&self, sender_id: NodeID, bytes: &[u8], authenticator: &Authenticator) -> bool {
+        match (self.keys.get(&sender_id), authenticator.get(&self.id)) {
+            (Some(key), Some(mac)) => constant_time_eq(to_hex(&hmac_sha256(key, bytes)).as_bytes(), mac.as_bytes()),
+            _ => false,
+        }
+    }
+}
+
+// Everything a node needs to authenticate what it sends and receives
+#[derive(Debug,Clone)]
+pub struct Credentials {
+    signer: Arc<dyn Signer>,
+    verifier: Arc<dyn Verifier>,
+    session_keys: Arc<SessionKeys>,
+}
+
+impl Credentials {
+    pub fn get_signer(&self) -> Arc<dyn Signer> {
+        self.signer.clone()
+    }
+
+    pub fn get_verifier(&self) -> Arc<dyn Verifier> {
+        self.verifier.clone()
+    }
+
+    pub fn get_session_keys(&self) -> Arc<SessionKeys> {
+        self.session_keys.clone()
+    }
+}
+
+// Keys of every node of the network
+pub struct KeyRing {
+    keys: HashMap<NodeID, Key>,
+}
+
+impl Debug for KeyRing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KeyRing {{ nodes: {:?} }}", self.keys.keys().collect::<Vec<&NodeID>>())
+    }
+}
+
+impl KeyRing {
+    // key_i = SHA-256(seed || i)
+    fn derive_key(id: NodeID, seed: u64) -> Key {
+        let mut material = seed.to_bytes();
+        id.encode(&mut material);
+        sha256(&material).to_vec()
+    }
+
+    pub fn generate(nodes: &HashSet<ID>, seed: u64) -> KeyRing {
+        let keys = nodes.iter().map(|id| (*id, Self::derive_key(*id, seed))).collect();
+        KeyRing{
+            keys,
+        }
+    }
+
+    // A replica joining later gets its own key, everybody else needs fresh
+    // credentials before they can check what it sends
+    pub fn add_key(&mut self, id: NodeID, seed: u64) {
+        self.keys.insert(id, Self::derive_key(id, seed));
+    }
+
+    pub fn remove_key(&mut self, id: NodeID) {
+        self.keys.remove(&id);
+    }
+
+    pub fn get_signer(&self, id: NodeID) -> Option<Arc<dyn Signer>> {
+        self.keys.get(&id).map(|key| {
+            let signer: Arc<dyn Signer> = Arc::new(HmacSigner{id, key: key.clone()});
+            signer
+        })
+    }
+
+    pub fn get_verifier(&self) -> Arc<dyn Verifier> {
+        Arc::new(HmacVerifier{keys: self.keys.clone()})
+    }
+
+    // k(i,j) = HMAC(key of min(i,j), max(i,j))
+    fn session_key(&self, a: NodeID, b: NodeID) -> Option<Key> {
+        let (low, high) = if a < b { (a, b) } else { (b, a) };
+        self.keys.get(&low).map(|key| hmac_sha256(key, &high.to_bytes()).to_vec())
+    }
+
+    pub fn get_session_keys(&self, id: NodeID) -> Option<SessionKeys> {
+        if !self.keys.contains_key(&id) {
+            return None;
+        }
+        let keys = self.keys.keys()
+            .filter(|peer| **peer != id)
+            .filter_map(|peer| self.session_key(id, *peer).map(|key| (*peer, key)))
+            .collect();
+        Some(SessionKeys{
+            id,
+            keys,
+        })
+    }
+
+    pub fn get_credentials(&self, id: NodeID) -> Option<Credentials> {
+        let signer = self.get_signer(id)?;
+        let session_keys = self.get_session_keys(id)?;
+        Some(Credentials{
+            signer,
+            verifier: self.get_verifier(),
+            session_keys: Arc::new(session_keys),
+        })
+    }
+}
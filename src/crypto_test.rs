@@ -0,0 +1,65 @@
+#[cfg(test)]
+mod signature_test {
+    use crate::crypto::{KeyRing,Signed};
+    use crate::dto::Prepare;
+    use crate::hash::{hmac_sha256,to_hex};
+    use crate::test_util::new_nodes;
+
+    #[test]
+    fn hmac_should_match_known_vectors() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            to_hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        // RFC 4231, test case 6: key longer than a block
+        assert_eq!(
+            to_hex(&hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First")),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+    }
+
+    #[test]
+    fn signed_message_should_verify() {
+        let key_ring = KeyRing::generate(&new_nodes(4), 1);
+        let mut prepare = Prepare::new(0, 1, "digest".to_owned(), 2);
+        assert!(!prepare.verify(&*key_ring.get_verifier()));
+        prepare.sign(&*key_ring.get_signer(2).unwrap());
+        assert!(prepare.verify(&*key_ring.get_verifier()));
+    }
+
+    #[test]
+    fn signature_should_not_survive_changes() {
+        let key_ring = KeyRing::generate(&new_nodes(4), 1);
+        let mut prepare = Prepare::new(0, 1, "digest".to_owned(), 2);
+        prepare.sign(&*key_ring.get_signer(2).unwrap());
+        let mut changed = Prepare::new(0, 2, "digest".to_owned(), 2);
+        changed.set_signature(prepare.get_signature());
+        assert!(!changed.verify(&*key_ring.get_verifier()));
+    }
+
+    #[test]
+    fn nodes_should_not_sign_for_each_other() {
+        let key_ring = KeyRing::generate(&new_nodes(4), 1);
+        let mut impersonated = Prepare::new(0, 1, "digest".to_owned(), 2);
+        impersonated.sign(&*key_ring.get_signer(3).unwrap());
+        assert!(!impersonated.verify(&*key_ring.get_verifier()));
+        // keys of another network don't work either
+        let mut foreign = Prepare::new(0, 1, "digest".to_owned(), 2);
+        foreign.sign(&*KeyRing::generate(&new_nodes(4), 2).get_signer(2).unwrap());
+        assert!(!foreign.verify(&*key_ring.get_verifier()));
+    }
+
+    #[test]
+    fn session_keys_should_be_pairwise() {
+        let key_ring = KeyRing::generate(&new_nodes(4), 1);
+        let nodes = new_nodes(4);
+        let authenticator = key_ring.get_session_keys(0).unwrap().authenticate(b"message", nodes.iter());
+        // no MAC for ourselves
+        assert_eq!(authenticator.len(), 3);
+        for id in 1..4 {
+            assert!(key_ring.get_session_keys(id).unwrap().check(0, b"message", &authenticator));
+            assert!(!key_ring.get_session_keys(id).unwrap().check(0, b"changed", &authenticator));
+            // the vector of 0 doesn't vouch for anyone else
+            assert!(!key_ring.get_session_keys(id).unwrap().check(3, b"message", &authenticator));
+        }
+    }
+}
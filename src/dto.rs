@@ -1,9 +1,16 @@
+use crate::crypto::Signed;
+use crate::encoding::{Encode,Persist};
+use crate::hash::{sha256,to_hex};
+use crate::util::digest;
 
 pub type ID = u64;
 pub type NodeID = ID;
-pub type Sig = ID; // Signature. ID of the node that signed it. invalid ID -> nobody signed it.
+pub type Sig = String; // Signature. Hex encoded, empty -> nobody signed it.
 pub type Digest = String; // Hash of something
-pub type Tip = String; // current progress of Nodes
+pub type Tip = String; // state of the demo `TipMachine`
+
+pub type Timestamp = ID; // t -- client side request counter
+pub type Batch<O> = Vec<Request<O>>; // requests the primary orders with a single sequence number
 
 /*
 Parameters:
@@ -12,17 +19,45 @@ p -- primary node of the view
 i -- current node of the view
 */
 
-#[derive(Debug)]
-pub struct PrePrepare {
+#[derive(Debug,Clone,PartialEq)]
+pub struct Request<O> {
+    op: O,        // o -- operation to execute
+    timestamp: Timestamp, // t
+    client_id: ID,  // c
+    read_only: bool, // executed by every replica right away, without ordering
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Reply<R> {
+    view_id: ID,    // v -- lets the client track the current primary
+    timestamp: Timestamp, // t -- of the request being answered
+    client_id: ID,  // c
+    replica_id: NodeID, // i
+    result: R,    // r -- result of executing o
+    read_only: bool, // answers a read-only request, needs 2f+1 matching replies
+    tentative: bool, // executed before it committed, needs 2f+1 matching replies
+    signature: Sig,  // sigma(i) -- Sig of replying node
+}
+
+// Membership change, ordered like a batch and applied once its seq executes
+#[derive(Debug,Clone,PartialEq)]
+pub enum Reconfiguration {
+    AddReplica(NodeID),
+    RemoveReplica(NodeID),
+}
+
+#[derive(Debug,Clone)]
+pub struct PrePrepare<O> {
     view_id: ID,    // v
     seq_id: ID,     // n
     digest: Digest,  // d -- digest for m
     signature: Sig,  // sigma(p) -- sig of primary node
-    message: Tip,    // m
+    batch: Batch<O>,    // m -- requests ordered at n, executed in this order
+    reconfiguration: Option<Reconfiguration>, // applied right after m executes
     sender_id: NodeID,    // i // Not present in the original protocol
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone)]
 pub struct Prepare {
     view_id: ID,    // v
     seq_id: ID,     // n
@@ -31,7 +66,7 @@ pub struct Prepare {
     signature: Sig,  // sigma(i) -- Sig of sending node
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone)]
 pub struct Commit {
     view_id: ID,    // v
     seq_id: ID,     // n
@@ -40,6 +75,65 @@ pub struct Commit {
     signature: Sig,  // sigma(i) -- Sig of sending node
 }
 
+#[derive(Debug,Clone)]
+pub struct Checkpoint {
+    seq_id: ID,     // n -- last executed seq
+    state_digest: Digest,  // d -- digest of the state after executing n
+    sender_id: NodeID,    // i
+    signature: Sig,  // sigma(i) -- Sig of sending node
+}
+
+// P_m -- proof that m was prepared: the pre-prepare and 2f matching prepares
+#[derive(Debug,Clone)]
+pub struct PreparedCert<O> {
+    preprepare: PrePrepare<O>,
+    prepares: Vec<Prepare>,
+}
+
+#[derive(Debug,Clone)]
+pub struct ViewChange<O> {
+    view_id: ID,    // v+1 -- the view the sender is moving to
+    seq_id: ID,     // n -- seq of the last stable checkpoint known to the sender
+    checkpoints: Vec<Checkpoint>, // C -- 2f+1 checkpoints proving n
+    prepared: Vec<PreparedCert<O>>, // P -- everything prepared above n
+    sender_id: NodeID,    // i
+    signature: Sig,  // sigma(i) -- Sig of sending node
+}
+
+#[derive(Debug,Clone)]
+pub struct NewView<O> {
+    view_id: ID,    // v+1
+    view_changes: Vec<ViewChange<O>>, // V -- 2f+1 view-changes for v+1
+    preprepares: Vec<PrePrepare<O>>,  // O -- pre-prepares re-issued in v+1
+    sender_id: NodeID,    // p -- primary of v+1
+    signature: Sig,  // sigma(p) -- sig of primary node
+}
+
+// Proof that m committed: the pre-prepare and 2f+1 matching commits
+#[derive(Debug,Clone)]
+pub struct CommittedCert<O> {
+    preprepare: PrePrepare<O>,
+    commits: Vec<Commit>,
+}
+
+// Asks peers for the state a replica is missing
+#[derive(Debug,Clone)]
+pub struct FetchState {
+    last_executed: ID, // n -- everything up to n is already there
+    sender_id: NodeID,    // i
+}
+
+// State transfer: the snapshot at the last stable checkpoint and what committed after it.
+// Needs no signature, the checkpoint proof and the certificates vouch for it
+#[derive(Debug,Clone)]
+pub struct StateTransfer<O, T> {
+    seq_id: ID,     // n -- seq of the stable checkpoint
+    checkpoints: Vec<Checkpoint>, // C -- 2f+1 checkpoints proving n
+    snapshot: T, // state after executing n, its digest is the one in C
+    committed: Vec<CommittedCert<O>>, // seqs above n executed by the sender
+    sender_id: NodeID,    // i
+}
+
 pub trait NodeRequest {
     fn get_view_id(&self) -> ID;   // v
     fn get_seq_id(&self) -> ID;    // n
@@ -47,29 +141,168 @@ pub trait NodeRequest {
     fn get_sender_id(&self) -> ID; // sigma(p) -- sig of primary node
 }
 
-impl PrePrepare {
+impl<O> Request<O> {
+    pub fn new(op: O, timestamp: Timestamp, client_id: ID) -> Request<O> {
+        Request{
+            op,
+            timestamp,
+            client_id,
+            read_only: false,
+        }
+    }
+    pub fn read_only(op: O, timestamp: Timestamp, client_id: ID) -> Request<O> {
+        Request{
+            read_only: true,
+            ..Request::new(op, timestamp, client_id)
+        }
+    }
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+    pub fn get_op(&self) -> &O {
+        &self.op
+    }
+    pub fn get_timestamp(&self) -> Timestamp {
+        self.timestamp
+    }
+    pub fn get_client_id(&self) -> ID {
+        self.client_id
+    }
+    pub fn make_reply<R>(&self, view_id: ID, replica_id: NodeID, result: R) -> Reply<R> {
+        Reply{
+            view_id,
+            timestamp: self.timestamp,
+            client_id: self.client_id,
+            replica_id,
+            result,
+            read_only: self.read_only,
+            tentative: false,
+            signature: Sig::new(),
+        }
+    }
+}
+
+impl<O: Encode> Encode for Request<O> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.op.encode(out);
+        self.timestamp.encode(out);
+        self.client_id.encode(out);
+        self.read_only.encode(out);
+    }
+}
+
+impl<R: Clone> Reply<R> {
+    pub fn get_view_id(&self) -> ID {
+        self.view_id
+    }
+    pub fn get_timestamp(&self) -> Timestamp {
+        self.timestamp
+    }
+    pub fn get_client_id(&self) -> ID {
+        self.client_id
+    }
+    pub fn get_replica_id(&self) -> NodeID {
+        self.replica_id
+    }
+    pub fn get_result(&self) -> R {
+        self.result.clone()
+    }
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+    pub fn is_tentative(&self) -> bool {
+        self.tentative
+    }
+    pub fn into_tentative(self) -> Reply<R> {
+        Reply{
+            tentative: true,
+            ..self
+        }
+    }
+}
+
+// d covers the membership change too, a plain batch hashes like before
+fn content_digest<O: Encode>(batch: &Batch<O>, reconfiguration: &Option<Reconfiguration>) -> Digest {
+    match reconfiguration {
+        None => digest(batch),
+        Some(reconfiguration) => {
+            let mut bytes = batch.to_bytes();
+            reconfiguration.encode(&mut bytes);
+            to_hex(&sha256(&bytes))
+        },
+    }
+}
+
+impl<O: Clone + Encode> PrePrepare<O> {
     pub fn new(
         view_id: ID,    // v
         seq_id: ID,     // n
-        message: Tip,    // m
+        batch: Batch<O>,    // m
+        sender_id: NodeID,
+    ) -> PrePrepare<O> {
+        PrePrepare::with_reconfiguration(view_id, seq_id, batch, None, sender_id)
+    }
+    fn with_reconfiguration(
+        view_id: ID,    // v
+        seq_id: ID,     // n
+        batch: Batch<O>,    // m
+        reconfiguration: Option<Reconfiguration>,
         sender_id: NodeID,
-    ) -> PrePrepare {
+    ) -> PrePrepare<O> {
         PrePrepare{
-            view_id: view_id,    // v
-            seq_id: seq_id,     // n
-            digest: "digest".to_owned(),  // d -- digest for m
-            signature: sender_id,  // sigma(i) -- Sig of sending node
-            message: message,    // m
-            sender_id: sender_id,
+            view_id,    // v
+            seq_id,     // n
+            digest: content_digest(&batch, &reconfiguration),  // d -- digest for the whole batch
+            signature: Sig::new(),  // sigma(i) -- Sig of sending node
+            batch,    // m
+            reconfiguration,
+            sender_id,
         }
     }
-    pub fn get_message(&self) -> Tip {
-        self.message.clone()
+    // Orders a membership change on its own, with an empty batch
+    pub fn reconfigure(
+        view_id: ID,    // v
+        seq_id: ID,     // n
+        reconfiguration: Reconfiguration,
+        sender_id: NodeID,
+    ) -> PrePrepare<O> {
+        PrePrepare::with_reconfiguration(view_id, seq_id, Batch::new(), Some(reconfiguration), sender_id)
+    }
+    // Null request: fills a gap in O during a view change, an empty batch executes as no-op
+    pub fn null(
+        view_id: ID,    // v
+        seq_id: ID,     // n
+        sender_id: NodeID,
+    ) -> PrePrepare<O> {
+        PrePrepare::new(view_id, seq_id, Batch::new(), sender_id)
+    }
+    pub fn is_null(&self) -> bool {
+        self.batch.is_empty() && self.reconfiguration.is_none()
+    }
+    pub fn get_batch(&self) -> &Batch<O> {
+        &self.batch
+    }
+    pub fn get_reconfiguration(&self) -> Option<&Reconfiguration> {
+        self.reconfiguration.as_ref()
+    }
+    // Re-issue the batch in a new view (used when building O)
+    pub fn reissue(&self, view_id: ID, sender_id: NodeID) -> PrePrepare<O> {
+        PrePrepare::with_reconfiguration(view_id, self.seq_id, self.batch.clone(), self.reconfiguration.clone(), sender_id)
+    }
+    // Unit test backdoor: a faulty primary sending m that doesn't match d
+    #[cfg(test)]
+    pub fn tamper_batch(&mut self, batch: Batch<O>) {
+        self.batch = batch;
+    }
+    // m could have been swapped on the way, d has to be recomputed
+    pub fn is_digest_valid(&self) -> bool {
+        self.digest == content_digest(&self.batch, &self.reconfiguration)
     }
     pub fn make_prepare(&self, sender_id: NodeID) -> Prepare {
         Prepare::new(
             self.view_id,
             self.seq_id,
+            self.digest.clone(),
             sender_id
         )
     }
@@ -79,20 +312,22 @@ impl Prepare {
     pub fn new(
         view_id: ID,    // v
         seq_id: ID,     // n
+        digest: Digest,  // d
         sender_id: NodeID,    // i
     ) -> Prepare {
         Prepare{
-            view_id: view_id,    // v
-            seq_id: seq_id,     // n
-            digest: "digest".to_owned(),  // d -- digest for m
-            sender_id: sender_id,
-            signature: sender_id,  // sigma(i) -- Sig of sending node
+            view_id,    // v
+            seq_id,     // n
+            digest,  // d -- digest for m
+            sender_id,
+            signature: Sig::new(),  // sigma(i) -- Sig of sending node
         }
     }
     pub fn make_commit(&self, sender_id: NodeID) -> Commit {
         Commit::new(
             self.view_id,
             self.seq_id,
+            self.digest.clone(),
             sender_id
         )
     }
@@ -102,16 +337,173 @@ impl Commit {
     pub fn new(
         view_id: ID,    // v
         seq_id: ID,     // n
+        digest: Digest,  // d
         sender_id: NodeID,    // i
     ) -> Commit {
         Commit{
-            view_id: view_id,    // v
-            seq_id: seq_id,     // n
-            digest: "digest".to_owned(),  // d -- digest for m
-            sender_id: sender_id,    // i
-            signature: sender_id,  // sigma(i) -- Sig of sending node
+            view_id,    // v
+            seq_id,     // n
+            digest,  // d -- digest for m
+            sender_id,    // i
+            signature: Sig::new(),  // sigma(i) -- Sig of sending node
+        }
+    }
+}
+
+impl Checkpoint {
+    pub fn new(
+        seq_id: ID,     // n
+        state_digest: Digest,  // d
+        sender_id: NodeID,    // i
+    ) -> Checkpoint {
+        Checkpoint{
+            seq_id,
+            state_digest,
+            sender_id,
+            signature: Sig::new(),  // sigma(i) -- Sig of sending node
+        }
+    }
+}
+
+impl<O> PreparedCert<O> {
+    pub fn new(preprepare: PrePrepare<O>, prepares: Vec<Prepare>) -> PreparedCert<O> {
+        PreparedCert{
+            preprepare,
+            prepares,
+        }
+    }
+    pub fn get_preprepare(&self) -> &PrePrepare<O> {
+        &self.preprepare
+    }
+    pub fn get_prepares(&self) -> &Vec<Prepare> {
+        &self.prepares
+    }
+}
+
+impl<O> ViewChange<O> {
+    pub fn new(
+        view_id: ID,    // v+1
+        seq_id: ID,     // n
+        checkpoints: Vec<Checkpoint>, // C
+        prepared: Vec<PreparedCert<O>>, // P
+        sender_id: NodeID,    // i
+    ) -> ViewChange<O> {
+        ViewChange{
+            view_id,
+            seq_id,
+            checkpoints,
+            prepared,
+            sender_id,
+            signature: Sig::new(),  // sigma(i) -- Sig of sending node
+        }
+    }
+    pub fn get_view_id(&self) -> ID {
+        self.view_id
+    }
+    pub fn get_seq_id(&self) -> ID {
+        self.seq_id
+    }
+    pub fn get_checkpoints(&self) -> &Vec<Checkpoint> {
+        &self.checkpoints
+    }
+    pub fn get_prepared(&self) -> &Vec<PreparedCert<O>> {
+        &self.prepared
+    }
+    pub fn get_sender_id(&self) -> NodeID {
+        self.sender_id
+    }
+}
+
+impl<O> NewView<O> {
+    pub fn new(
+        view_id: ID,    // v+1
+        view_changes: Vec<ViewChange<O>>, // V
+        preprepares: Vec<PrePrepare<O>>,  // O
+        sender_id: NodeID,    // p
+    ) -> NewView<O> {
+        NewView{
+            view_id,
+            view_changes,
+            preprepares,
+            sender_id,
+            signature: Sig::new(),  // sigma(p) -- sig of primary node
+        }
+    }
+    pub fn get_view_id(&self) -> ID {
+        self.view_id
+    }
+    pub fn get_view_changes(&self) -> &Vec<ViewChange<O>> {
+        &self.view_changes
+    }
+    pub fn get_preprepares(&self) -> &Vec<PrePrepare<O>> {
+        &self.preprepares
+    }
+    pub fn get_sender_id(&self) -> NodeID {
+        self.sender_id
+    }
+}
+
+impl<O> CommittedCert<O> {
+    pub fn new(preprepare: PrePrepare<O>, commits: Vec<Commit>) -> CommittedCert<O> {
+        CommittedCert{
+            preprepare,
+            commits,
+        }
+    }
+    pub fn get_preprepare(&self) -> &PrePrepare<O> {
+        &self.preprepare
+    }
+    pub fn get_commits(&self) -> &Vec<Commit> {
+        &self.commits
+    }
+}
+
+impl FetchState {
+    pub fn new(last_executed: ID, sender_id: NodeID) -> FetchState {
+        FetchState{
+            last_executed,
+            sender_id,
+        }
+    }
+    pub fn get_last_executed(&self) -> ID {
+        self.last_executed
+    }
+    pub fn get_sender_id(&self) -> NodeID {
+        self.sender_id
+    }
+}
+
+impl<O, T> StateTransfer<O, T> {
+    pub fn new(
+        seq_id: ID,    // n
+        checkpoints: Vec<Checkpoint>, // C
+        snapshot: T,
+        committed: Vec<CommittedCert<O>>,
+        sender_id: NodeID,    // i
+    ) -> StateTransfer<O, T> {
+        StateTransfer{
+            seq_id,
+            checkpoints,
+            snapshot,
+            committed,
+            sender_id,
         }
     }
+    pub fn get_seq_id(&self) -> ID {
+        self.seq_id
+    }
+    pub fn get_checkpoints(&self) -> &Vec<Checkpoint> {
+        &self.checkpoints
+    }
+    pub fn get_snapshot(&self) -> &T {
+        &self.snapshot
+    }
+    pub fn get_committed(&self) -> &Vec<CommittedCert<O>> {
+        &self.committed
+    }
+    pub fn get_sender_id(&self) -> NodeID {
+        self.sender_id
+    }
 }
 
 impl NodeRequest for Commit {
@@ -129,7 +521,7 @@ impl NodeRequest for Commit {
     }
 }
 
-impl NodeRequest for PrePrepare {
+impl<O> NodeRequest for PrePrepare<O> {
     fn get_view_id(&self) -> ID {
         self.view_id
     }
@@ -159,5 +551,405 @@ impl NodeRequest for Prepare {
     }
 }
 
+// Checkpoints don't belong to a view: they match on (n, d) only
+impl NodeRequest for Checkpoint {
+    fn get_view_id(&self) -> ID {
+        0
+    }
+    fn get_seq_id(&self) -> ID {
+        self.seq_id
+    }
+    fn get_digest(&self) -> Digest {
+        self.state_digest.clone()
+    }
+    fn get_sender_id(&self) -> NodeID {
+        self.sender_id
+    }
+}
+
 #[derive(Debug)]
 pub struct Shutdown {}
+
+// Control packet: tells the node that the primary of its view looks faulty
+#[derive(Debug)]
+pub struct SuspectPrimary {}
+
+impl Encode for Reconfiguration {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reconfiguration::AddReplica(id) => {
+                "ADD-REPLICA".encode(out);
+                id.encode(out);
+            },
+            Reconfiguration::RemoveReplica(id) => {
+                "REMOVE-REPLICA".encode(out);
+                id.encode(out);
+            },
+        }
+    }
+}
+
+// Canonical bytes that get signed: everything but the signature itself,
+// prefixed with the message type so a prepare can't pass for a commit
+
+impl<R: Encode> Encode for Reply<R> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        "REPLY".encode(out);
+        self.view_id.encode(out);
+        self.timestamp.encode(out);
+        self.client_id.encode(out);
+        self.replica_id.encode(out);
+        self.result.encode(out);
+        self.read_only.encode(out);
+        self.tentative.encode(out);
+    }
+}
+
+// m travels next to the signed part, it is bound to it by d
+impl<O> Encode for PrePrepare<O> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        "PRE-PREPARE".encode(out);
+        self.view_id.encode(out);
+        self.seq_id.encode(out);
+        self.digest.encode(out);
+        self.sender_id.encode(out);
+    }
+}
+
+impl Encode for Prepare {
+    fn encode(&self, out: &mut Vec<u8>) {
+        "PREPARE".encode(out);
+        self.view_id.encode(out);
+        self.seq_id.encode(out);
+        self.digest.encode(out);
+        self.sender_id.encode(out);
+    }
+}
+
+impl Encode for Commit {
+    fn encode(&self, out: &mut Vec<u8>) {
+        "COMMIT".encode(out);
+        self.view_id.encode(out);
+        self.seq_id.encode(out);
+        self.digest.encode(out);
+        self.sender_id.encode(out);
+    }
+}
+
+impl Encode for Checkpoint {
+    fn encode(&self, out: &mut Vec<u8>) {
+        "CHECKPOINT".encode(out);
+        self.seq_id.encode(out);
+        self.state_digest.encode(out);
+        self.sender_id.encode(out);
+    }
+}
+
+impl<O> Encode for PreparedCert<O> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.preprepare.encode(out);
+        self.prepares.encode(out);
+    }
+}
+
+impl<O> Encode for ViewChange<O> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        "VIEW-CHANGE".encode(out);
+        self.view_id.encode(out);
+        self.seq_id.encode(out);
+        self.checkpoints.encode(out);
+        self.prepared.encode(out);
+        self.sender_id.encode(out);
+    }
+}
+
+impl<O> Encode for NewView<O> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        "NEW-VIEW".encode(out);
+        self.view_id.encode(out);
+        self.view_changes.encode(out);
+        self.preprepares.encode(out);
+        self.sender_id.encode(out);
+    }
+}
+
+impl<R: Encode> Signed for Reply<R> {
+    fn get_signer_id(&self) -> NodeID {
+        self.replica_id
+    }
+    fn get_signature(&self) -> Sig {
+        self.signature.clone()
+    }
+    fn set_signature(&mut self, signature: Sig) {
+        self.signature = signature;
+    }
+}
+
+impl<O> Signed for PrePrepare<O> {
+    fn get_signer_id(&self) -> NodeID {
+        self.sender_id
+    }
+    fn get_signature(&self) -> Sig {
+        self.signature.clone()
+    }
+    fn set_signature(&mut self, signature: Sig) {
+        self.signature = signature;
+    }
+}
+
+impl Signed for Prepare {
+    fn get_signer_id(&self) -> NodeID {
+        self.sender_id
+    }
+    fn get_signature(&self) -> Sig {
+        self.signature.clone()
+    }
+    fn set_signature(&mut self, signature: Sig) {
+        self.signature = signature;
+    }
+}
+
+impl Signed for Commit {
+    fn get_signer_id(&self) -> NodeID {
+        self.sender_id
+    }
+    fn get_signature(&self) -> Sig {
+        self.signature.clone()
+    }
+    fn set_signature(&mut self, signature: Sig) {
+        self.signature = signature;
+    }
+}
+
+impl Signed for Checkpoint {
+    fn get_signer_id(&self) -> NodeID {
+        self.sender_id
+    }
+    fn get_signature(&self) -> Sig {
+        self.signature.clone()
+    }
+    fn set_signature(&mut self, signature: Sig) {
+        self.signature = signature;
+    }
+}
+
+impl<O> Signed for ViewChange<O> {
+    fn get_signer_id(&self) -> NodeID {
+        self.sender_id
+    }
+    fn get_signature(&self) -> Sig {
+        self.signature.clone()
+    }
+    fn set_signature(&mut self, signature: Sig) {
+        self.signature = signature;
+    }
+}
+
+impl<O> Signed for NewView<O> {
+    fn get_signer_id(&self) -> NodeID {
+        self.sender_id
+    }
+    fn get_signature(&self) -> Sig {
+        self.signature.clone()
+    }
+    fn set_signature(&mut self, signature: Sig) {
+        self.signature = signature;
+    }
+}
+
+// Write-ahead log records: every field, the signature included
+
+impl<O: Persist> Persist for Request<O> {
+    fn persist(&self, out: &mut Vec<u8>) {
+        self.op.persist(out);
+        self.timestamp.persist(out);
+        self.client_id.persist(out);
+        self.read_only.persist(out);
+    }
+    fn load(input: &mut &[u8]) -> Option<Request<O>> {
+        Some(Request{
+            op: O::load(input)?,
+            timestamp: Timestamp::load(input)?,
+            client_id: ID::load(input)?,
+            read_only: bool::load(input)?,
+        })
+    }
+}
+
+impl<R: Persist> Persist for Reply<R> {
+    fn persist(&self, out: &mut Vec<u8>) {
+        self.view_id.persist(out);
+        self.timestamp.persist(out);
+        self.client_id.persist(out);
+        self.replica_id.persist(out);
+        self.result.persist(out);
+        self.read_only.persist(out);
+        self.tentative.persist(out);
+        self.signature.persist(out);
+    }
+    fn load(input: &mut &[u8]) -> Option<Reply<R>> {
+        Some(Reply{
+            view_id: ID::load(input)?,
+            timestamp: Timestamp::load(input)?,
+            client_id: ID::load(input)?,
+            replica_id: NodeID::load(input)?,
+            result: R::load(input)?,
+            read_only: bool::load(input)?,
+            tentative: bool::load(input)?,
+            signature: Sig::load(input)?,
+        })
+    }
+}
+
+impl Persist for Reconfiguration {
+    fn persist(&self, out: &mut Vec<u8>) {
+        match self {
+            Reconfiguration::AddReplica(id) => {
+                0u8.persist(out);
+                id.persist(out);
+            },
+            Reconfiguration::RemoveReplica(id) => {
+                1u8.persist(out);
+                id.persist(out);
+            },
+        }
+    }
+    fn load(input: &mut &[u8]) -> Option<Reconfiguration> {
+        match u8::load(input)? {
+            0 => NodeID::load(input).map(Reconfiguration::AddReplica),
+            1 => NodeID::load(input).map(Reconfiguration::RemoveReplica),
+            _ => None,
+        }
+    }
+}
+
+impl<O: Persist> Persist for PrePrepare<O> {
+    fn persist(&self, out: &mut Vec<u8>) {
+        self.view_id.persist(out);
+        self.seq_id.persist(out);
+        self.digest.persist(out);
+        self.signature.persist(out);
+        self.batch.persist(out);
+        self.reconfiguration.persist(out);
+        self.sender_id.persist(out);
+    }
+    fn load(input: &mut &[u8]) -> Option<PrePrepare<O>> {
+        Some(PrePrepare{
+            view_id: ID::load(input)?,
+            seq_id: ID::load(input)?,
+            digest: Digest::load(input)?,
+            signature: Sig::load(input)?,
+            batch: Batch::load(input)?,
+            reconfiguration: Option::load(input)?,
+            sender_id: NodeID::load(input)?,
+        })
+    }
+}
+
+impl Persist for Prepare {
+    fn persist(&self, out: &mut Vec<u8>) {
+        self.view_id.persist(out);
+        self.seq_id.persist(out);
+        self.digest.persist(out);
+        self.sender_id.persist(out);
+        self.signature.persist(out);
+    }
+    fn load(input: &mut &[u8]) -> Option<Prepare> {
+        Some(Prepare{
+            view_id: ID::load(input)?,
+            seq_id: ID::load(input)?,
+            digest: Digest::load(input)?,
+            sender_id: NodeID::load(input)?,
+            signature: Sig::load(input)?,
+        })
+    }
+}
+
+impl Persist for Commit {
+    fn persist(&self, out: &mut Vec<u8>) {
+        self.view_id.persist(out);
+        self.seq_id.persist(out);
+        self.digest.persist(out);
+        self.sender_id.persist(out);
+        self.signature.persist(out);
+    }
+    fn load(input: &mut &[u8]) -> Option<Commit> {
+        Some(Commit{
+            view_id: ID::load(input)?,
+            seq_id: ID::load(input)?,
+            digest: Digest::load(input)?,
+            sender_id: NodeID::load(input)?,
+            signature: Sig::load(input)?,
+        })
+    }
+}
+
+impl Persist for Checkpoint {
+    fn persist(&self, out: &mut Vec<u8>) {
+        self.seq_id.persist(out);
+        self.state_digest.persist(out);
+        self.sender_id.persist(out);
+        self.signature.persist(out);
+    }
+    fn load(input: &mut &[u8]) -> Option<Checkpoint> {
+        Some(Checkpoint{
+            seq_id: ID::load(input)?,
+            state_digest: Digest::load(input)?,
+            sender_id: NodeID::load(input)?,
+            signature: Sig::load(input)?,
+        })
+    }
+}
+
+impl<O: Persist> Persist for PreparedCert<O> {
+    fn persist(&self, out: &mut Vec<u8>) {
+        self.preprepare.persist(out);
+        self.prepares.persist(out);
+    }
+    fn load(input: &mut &[u8]) -> Option<PreparedCert<O>> {
+        Some(PreparedCert{
+            preprepare: PrePrepare::load(input)?,
+            prepares: Vec::load(input)?,
+        })
+    }
+}
+
+impl<O: Persist> Persist for ViewChange<O> {
+    fn persist(&self, out: &mut Vec<u8>) {
+        self.view_id.persist(out);
+        self.seq_id.persist(out);
+        self.checkpoints.persist(out);
+        self.prepared.persist(out);
+        self.sender_id.persist(out);
+        self.signature.persist(out);
+    }
+    fn load(input: &mut &[u8]) -> Option<ViewChange<O>> {
+        Some(ViewChange{
+            view_id: ID::load(input)?,
+            seq_id: ID::load(input)?,
+            checkpoints: Vec::load(input)?,
+            prepared: Vec::load(input)?,
+            sender_id: NodeID::load(input)?,
+            signature: Sig::load(input)?,
+        })
+    }
+}
+
+impl<O: Persist> Persist for NewView<O> {
+    fn persist(&self, out: &mut Vec<u8>) {
+        self.view_id.persist(out);
+        self.view_changes.persist(out);
+        self.preprepares.persist(out);
+        self.sender_id.persist(out);
+        self.signature.persist(out);
+    }
+    fn load(input: &mut &[u8]) -> Option<NewView<O>> {
+        Some(NewView{
+            view_id: ID::load(input)?,
+            view_changes: Vec::load(input)?,
+            preprepares: Vec::load(input)?,
+            sender_id: NodeID::load(input)?,
+            signature: Sig::load(input)?,
+        })
+    }
+}
@@ -1,7 +1,7 @@
 #[cfg(test)]
 mod dto_transformation_test {
-    use crate::dto::{ID,NodeRequest};
-    use crate::test_util::new_random_preprepare;
+    use crate::dto::{ID,Tip,PrePrepare,Reconfiguration,NodeRequest};
+    use crate::test_util::{new_random_preprepare,new_request};
 
     #[test]
     fn preprepare_should_create_prepare() {
@@ -10,7 +10,46 @@ mod dto_transformation_test {
         let p = pp.make_prepare(new_sender_id);
         assert_eq!(p.get_view_id(), pp.get_view_id());
         assert_eq!(p.get_seq_id(), pp.get_seq_id());
-        assert_eq!(p.get_digest(), "digest".to_owned());
+        assert_eq!(p.get_digest(), pp.get_digest());
         assert_eq!(p.get_sender_id(), new_sender_id);
     }
+
+    #[test]
+    fn prepare_should_create_commit_with_same_digest() {
+        let p = new_random_preprepare().make_prepare(1);
+        let c = p.make_commit(2);
+        assert_eq!(c.get_digest(), p.get_digest());
+        assert_eq!(c.get_sender_id(), 2);
+    }
+
+    #[test]
+    fn different_requests_should_have_different_digests() {
+        let a = PrePrepare::new(0, 1, vec![new_request("a")], 0);
+        let b = PrePrepare::new(0, 1, vec![new_request("b")], 0);
+        assert_ne!(a.get_digest(), b.get_digest());
+        assert_eq!(a.get_digest(), PrePrepare::new(3, 7, vec![new_request("a")], 2).get_digest());
+        assert!(a.is_digest_valid());
+    }
+
+    #[test]
+    fn batch_digest_should_depend_on_order() {
+        let ab = PrePrepare::new(0, 1, vec![new_request("a"), new_request("b")], 0);
+        let ba = PrePrepare::new(0, 1, vec![new_request("b"), new_request("a")], 0);
+        assert_ne!(ab.get_digest(), ba.get_digest());
+        assert!(PrePrepare::<Tip>::null(0, 1, 0).is_null());
+        assert!(!ab.is_null());
+    }
+
+    #[test]
+    fn digest_should_cover_reconfiguration() {
+        let add = PrePrepare::<Tip>::reconfigure(0, 1, Reconfiguration::AddReplica(4), 0);
+        let remove = PrePrepare::<Tip>::reconfigure(0, 1, Reconfiguration::RemoveReplica(4), 0);
+        assert_ne!(add.get_digest(), remove.get_digest());
+        assert_ne!(add.get_digest(), PrePrepare::<Tip>::null(0, 1, 0).get_digest());
+        assert!(!add.is_null());
+        assert!(add.is_digest_valid());
+        let reissued = add.reissue(1, 1);
+        assert_eq!(reissued.get_reconfiguration(), Some(&Reconfiguration::AddReplica(4)));
+        assert_eq!(reissued.get_digest(), add.get_digest());
+    }
 }
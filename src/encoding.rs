@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+// Canonical byte encoding: every value has exactly one encoding,
+// so equal messages always hash (and later sign) the same way
+pub trait Encode {
+    fn encode(&self, out: &mut Vec<u8>);
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out
+    }
+}
+
+impl Encode for u64 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_be_bytes());
+    }
+}
+
+impl Encode for u8 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self);
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+}
+
+// Length prefixed so that ("ab", "c") and ("a", "bc") differ
+impl Encode for str {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (self.len() as u64).encode(out);
+        out.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.as_str().encode(out);
+    }
+}
+
+impl<T: Encode> Encode for [T] {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (self.len() as u64).encode(out);
+        for item in self {
+            item.encode(out);
+        }
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.as_slice().encode(out);
+    }
+}
+
+// Tagged so that None and Some("") differ
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            None => 0u8.encode(out),
+            Some(value) => {
+                1u8.encode(out);
+                value.encode(out);
+            },
+        }
+    }
+}
+
+// Full encoding that can be read back, for the write-ahead log. Unlike `Encode`
+// it keeps signatures and batches; `load` consumes what it reads from `input`
+// and gives None on bytes that don't decode
+pub trait Persist: Sized {
+    fn persist(&self, out: &mut Vec<u8>);
+    fn load(input: &mut &[u8]) -> Option<Self>;
+}
+
+pub fn take<'a>(input: &mut &'a [u8], count: usize) -> Option<&'a [u8]> {
+    if input.len() < count {
+        return None;
+    }
+    let (taken, rest) = input.split_at(count);
+    *input = rest;
+    Some(taken)
+}
+
+impl Persist for u64 {
+    fn persist(&self, out: &mut Vec<u8>) {
+        self.encode(out);
+    }
+    fn load(input: &mut &[u8]) -> Option<u64> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(take(input, 8)?);
+        Some(u64::from_be_bytes(bytes))
+    }
+}
+
+impl Persist for u8 {
+    fn persist(&self, out: &mut Vec<u8>) {
+        self.encode(out);
+    }
+    fn load(input: &mut &[u8]) -> Option<u8> {
+        take(input, 1).map(|bytes| bytes[0])
+    }
+}
+
+impl Persist for bool {
+    fn persist(&self, out: &mut Vec<u8>) {
+        self.encode(out);
+    }
+    fn load(input: &mut &[u8]) -> Option<bool> {
+        match u8::load(input)? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl Persist for String {
+    fn persist(&self, out: &mut Vec<u8>) {
+        self.encode(out);
+    }
+    fn load(input: &mut &[u8]) -> Option<String> {
+        let len = u64::load(input)?;
+        let bytes = take(input, usize::try_from(len).ok()?)?;
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+}
+
+impl<T: Persist> Persist for Vec<T> {
+    fn persist(&self, out: &mut Vec<u8>) {
+        (self.len() as u64).encode(out);
+        for item in self {
+            item.persist(out);
+        }
+    }
+    fn load(input: &mut &[u8]) -> Option<Vec<T>> {
+        let len = u64::load(input)?;
+        // the length comes from disk, don't trust it with the allocation
+        let mut items = Vec::new();
+        for _ in 0..len {
+            items.push(T::load(input)?);
+        }
+        Some(items)
+    }
+}
+
+impl<T: Persist> Persist for Option<T> {
+    fn persist(&self, out: &mut Vec<u8>) {
+        match self {
+            None => 0u8.encode(out),
+            Some(value) => {
+                1u8.encode(out);
+                value.persist(out);
+            },
+        }
+    }
+    fn load(input: &mut &[u8]) -> Option<Option<T>> {
+        match u8::load(input)? {
+            0 => Some(None),
+            1 => T::load(input).map(Some),
+            _ => None,
+        }
+    }
+}
+
+impl<K: Persist + Ord, V: Persist> Persist for BTreeMap<K, V> {
+    fn persist(&self, out: &mut Vec<u8>) {
+        (self.len() as u64).encode(out);
+        for (key, value) in self.iter() {
+            key.persist(out);
+            value.persist(out);
+        }
+    }
+    fn load(input: &mut &[u8]) -> Option<BTreeMap<K, V>> {
+        let len = u64::load(input)?;
+        let mut map = BTreeMap::new();
+        for _ in 0..len {
+            let key = K::load(input)?;
+            map.insert(key, V::load(input)?);
+        }
+        Some(map)
+    }
+}
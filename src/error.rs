@@ -0,0 +1,82 @@
+use crate::dto::{ID,NodeID,Timestamp};
+
+// Why a message was dropped or an operation failed
+#[derive(Debug,Clone,PartialEq)]
+pub enum PbftError {
+    LockPoisoned,
+    SendFailed{target_id: NodeID},
+    UnknownNode{node_id: NodeID},
+    NotAReplica{node_id: NodeID},
+    UnknownMessage,
+    NoNodes,
+    QueueEmpty,
+    TooFewReplicas{node_count: usize, f: usize},
+    InsufficientApprovers,
+    WrongView{view_id: ID, current_view_id: ID},
+    StaleView{view_id: ID, current_view_id: ID},
+    ViewNotActive{view_id: ID},
+    OutsideWaterMarks{seq_id: ID, low: ID, high: ID},
+    StaleSequence{seq_id: ID, last_seq_id: ID},
+    StaleTimestamp{client_id: ID, timestamp: Timestamp},
+    DuplicateRequest{client_id: ID, timestamp: Timestamp},
+    NotReadOnly{client_id: ID, timestamp: Timestamp},
+    NotPrimary{view_id: ID, sender_id: NodeID, primary_id: Option<NodeID>},
+    DigestMismatch{seq_id: ID},
+    InvalidSignature{sender_id: NodeID},
+    InvalidAuthenticator{sender_id: NodeID},
+    ConflictingPrePrepare{view_id: ID, seq_id: ID},
+    InvalidCheckpointProof{seq_id: ID},
+    SnapshotMismatch{seq_id: ID},
+    InvalidCommittedCert{seq_id: ID},
+    InvalidViewChange{view_id: ID, sender_id: NodeID},
+    NewViewMismatch{view_id: ID},
+    UnrequestedReconfiguration{seq_id: ID},
+    LogFailed{reason: String},
+    CorruptLog{offset: u64},
+}
+
+impl std::fmt::Display for PbftError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PbftError::LockPoisoned => write!(f, "a lock was poisoned by a panicking thread"),
+            PbftError::SendFailed{target_id} => write!(f, "can't send to {:?}, its channel is closed", target_id),
+            PbftError::UnknownNode{node_id} => write!(f, "{:?} is not a known replica", node_id),
+            PbftError::NotAReplica{node_id} => write!(f, "{:?} is not a replica anymore", node_id),
+            PbftError::UnknownMessage => write!(f, "replicas don't handle this kind of message"),
+            PbftError::NoNodes => write!(f, "no nodes were found"),
+            PbftError::QueueEmpty => write!(f, "no more requests"),
+            PbftError::TooFewReplicas{node_count, f: faulty} =>
+                write!(f, "{} replicas can't tolerate {} faulty ones, that takes {}", node_count, faulty, 3 * faulty + 1),
+            PbftError::InsufficientApprovers => write!(f, "message doesn't have enough approvers"),
+            PbftError::WrongView{view_id, current_view_id} =>
+                write!(f, "view {:?} is not the current view {:?}", view_id, current_view_id),
+            PbftError::StaleView{view_id, current_view_id} =>
+                write!(f, "view {:?} is stale, the node is in view {:?}", view_id, current_view_id),
+            PbftError::ViewNotActive{view_id} => write!(f, "view {:?} is not active yet", view_id),
+            PbftError::OutsideWaterMarks{seq_id, low, high} =>
+                write!(f, "seq {:?} is outside of the water marks ({:?}, {:?}]", seq_id, low, high),
+            PbftError::StaleSequence{seq_id, last_seq_id} => write!(f, "seq {:?} is behind seq {:?}", seq_id, last_seq_id),
+            PbftError::StaleTimestamp{client_id, timestamp} =>
+                write!(f, "timestamp {:?} of client {:?} is stale", timestamp, client_id),
+            PbftError::DuplicateRequest{client_id, timestamp} =>
+                write!(f, "request {:?} of client {:?} is already waiting", timestamp, client_id),
+            PbftError::NotReadOnly{client_id, timestamp} =>
+                write!(f, "request {:?} of client {:?} isn't read-only", timestamp, client_id),
+            PbftError::NotPrimary{view_id, sender_id, primary_id} =>
+                write!(f, "{:?} is not the primary of view {:?} (primary: {:?})", sender_id, view_id, primary_id),
+            PbftError::DigestMismatch{seq_id} => write!(f, "digest of seq {:?} doesn't match its request", seq_id),
+            PbftError::InvalidSignature{sender_id} => write!(f, "signature of {:?} doesn't verify", sender_id),
+            PbftError::InvalidAuthenticator{sender_id} => write!(f, "authenticator of {:?} doesn't verify", sender_id),
+            PbftError::ConflictingPrePrepare{view_id, seq_id} => write!(f, "another pre-prepare was accepted for view {:?} seq {:?}", view_id, seq_id),
+            PbftError::InvalidCheckpointProof{seq_id} => write!(f, "checkpoint {:?} isn't proven stable", seq_id),
+            PbftError::SnapshotMismatch{seq_id} => write!(f, "snapshot doesn't match the digest of checkpoint {:?}", seq_id),
+            PbftError::InvalidCommittedCert{seq_id} => write!(f, "seq {:?} isn't proven committed", seq_id),
+            PbftError::InvalidViewChange{view_id, sender_id} =>
+                write!(f, "view change of {:?} to view {:?} doesn't hold up", sender_id, view_id),
+            PbftError::NewViewMismatch{view_id} => write!(f, "O set of view {:?} doesn't match its view changes", view_id),
+            PbftError::UnrequestedReconfiguration{seq_id} => write!(f, "nobody asked for the membership change at seq {:?}", seq_id),
+            PbftError::LogFailed{reason} => write!(f, "write-ahead log failed: {}", reason),
+            PbftError::CorruptLog{offset} => write!(f, "write-ahead log doesn't decode at byte {:?}", offset),
+        }
+    }
+}
@@ -0,0 +1,29 @@
+#[cfg(test)]
+mod digest_test {
+    use crate::hash::{sha256,to_hex};
+    use crate::encoding::Encode;
+    use crate::util::digest;
+
+    #[test]
+    fn sha256_should_match_known_vectors() {
+        assert_eq!(to_hex(&sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(to_hex(&sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(
+            to_hex(&sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+    }
+
+    #[test]
+    fn sha256_should_handle_multiple_blocks() {
+        let data = vec![b'a'; 1000];
+        assert_eq!(to_hex(&sha256(&data)), "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3");
+    }
+
+    #[test]
+    fn encoding_should_be_unambiguous() {
+        let ab_c = vec!["ab".to_owned(), "c".to_owned()];
+        let a_bc = vec!["a".to_owned(), "bc".to_owned()];
+        assert_ne!(ab_c.to_bytes(), a_bc.to_bytes());
+        assert_ne!(digest(&ab_c), digest(&a_bc));
+    }
+}
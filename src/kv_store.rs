@@ -0,0 +1,206 @@
+use crate::dto::Digest;
+use crate::encoding::{Encode,Persist};
+use crate::state_machine::StateMachine;
+use crate::util::digest;
+use std::collections::BTreeMap;
+
+pub type Key = String;
+pub type Value = String;
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum KvOperation {
+    Get{key: Key},
+    Put{key: Key, value: Value},
+    Delete{key: Key},
+    // Stores `value` only when the current value is `expected` (None -- the key is absent)
+    CompareAndSwap{key: Key, expected: Option<Value>, value: Value},
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum KvResult {
+    Value(Option<Value>), // Get: the current value
+    Previous(Option<Value>), // Put, Delete: the value that was replaced
+    Swapped{success: bool, current: Option<Value>}, // CompareAndSwap: the value after the operation
+}
+
+impl KvOperation {
+    // Console syntax: `get k`, `put k v`, `del k`, `cas k expected v` (`-` expects no value)
+    pub fn parse(line: &str) -> Option<KvOperation> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["get", key] => Some(KvOperation::Get{key: key.to_string()}),
+            ["put", key, value] => Some(KvOperation::Put{key: key.to_string(), value: value.to_string()}),
+            ["del", key] => Some(KvOperation::Delete{key: key.to_string()}),
+            ["cas", key, expected, value] => Some(KvOperation::CompareAndSwap{
+                key: key.to_string(),
+                expected: if *expected == "-" { None } else { Some(expected.to_string()) },
+                value: value.to_string(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn get_key(&self) -> &Key {
+        match self {
+            KvOperation::Get{key} => key,
+            KvOperation::Put{key, ..} => key,
+            KvOperation::Delete{key} => key,
+            KvOperation::CompareAndSwap{key, ..} => key,
+        }
+    }
+}
+
+// Reference application: a replicated map from keys to values
+#[derive(Debug,Clone,Default)]
+pub struct KvStore {
+    entries: BTreeMap<Key, Value>, // ordered, so the encoding doesn't depend on insertion order
+}
+
+impl KvStore {
+    pub fn new() -> KvStore {
+        KvStore{
+            entries: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.entries.get(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+impl StateMachine for KvStore {
+    type Operation = KvOperation;
+    type Result = KvResult;
+    type Snapshot = BTreeMap<Key, Value>;
+
+    fn apply(&mut self, operation: &KvOperation) -> KvResult {
+        match operation {
+            KvOperation::Get{key} => KvResult::Value(self.entries.get(key).cloned()),
+            KvOperation::Put{key, value} => KvResult::Previous(self.entries.insert(key.clone(), value.clone())),
+            KvOperation::Delete{key} => KvResult::Previous(self.entries.remove(key)),
+            KvOperation::CompareAndSwap{key, expected, value} => {
+                let success = self.entries.get(key) == expected.as_ref();
+                if success {
+                    self.entries.insert(key.clone(), value.clone());
+                }
+                KvResult::Swapped{success, current: self.entries.get(key).cloned()}
+            },
+        }
+    }
+
+    fn read(&self, operation: &KvOperation) -> Option<KvResult> {
+        match operation {
+            KvOperation::Get{key} => Some(KvResult::Value(self.entries.get(key).cloned())),
+            _ => None,
+        }
+    }
+
+    fn snapshot(&self) -> BTreeMap<Key, Value> {
+        self.entries.clone()
+    }
+
+    fn restore(&mut self, snapshot: BTreeMap<Key, Value>) {
+        self.entries = snapshot;
+    }
+
+    fn digest(&self) -> Digest {
+        digest(self)
+    }
+}
+
+impl Encode for KvOperation {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            KvOperation::Get{key} => {
+                0u8.encode(out);
+                key.encode(out);
+            },
+            KvOperation::Put{key, value} => {
+                1u8.encode(out);
+                key.encode(out);
+                value.encode(out);
+            },
+            KvOperation::Delete{key} => {
+                2u8.encode(out);
+                key.encode(out);
+            },
+            KvOperation::CompareAndSwap{key, expected, value} => {
+                3u8.encode(out);
+                key.encode(out);
+                expected.encode(out);
+                value.encode(out);
+            },
+        }
+    }
+}
+
+// Written to the write-ahead log the way it is encoded
+impl Persist for KvOperation {
+    fn persist(&self, out: &mut Vec<u8>) {
+        self.encode(out);
+    }
+    fn load(input: &mut &[u8]) -> Option<KvOperation> {
+        match u8::load(input)? {
+            0 => Some(KvOperation::Get{key: Key::load(input)?}),
+            1 => Some(KvOperation::Put{key: Key::load(input)?, value: Value::load(input)?}),
+            2 => Some(KvOperation::Delete{key: Key::load(input)?}),
+            3 => Some(KvOperation::CompareAndSwap{
+                key: Key::load(input)?,
+                expected: Option::load(input)?,
+                value: Value::load(input)?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl Encode for KvResult {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            KvResult::Value(value) => {
+                0u8.encode(out);
+                value.encode(out);
+            },
+            KvResult::Previous(value) => {
+                1u8.encode(out);
+                value.encode(out);
+            },
+            KvResult::Swapped{success, current} => {
+                2u8.encode(out);
+                success.encode(out);
+                current.encode(out);
+            },
+        }
+    }
+}
+
+impl Persist for KvResult {
+    fn persist(&self, out: &mut Vec<u8>) {
+        self.encode(out);
+    }
+    fn load(input: &mut &[u8]) -> Option<KvResult> {
+        match u8::load(input)? {
+            0 => Some(KvResult::Value(Option::load(input)?)),
+            1 => Some(KvResult::Previous(Option::load(input)?)),
+            2 => Some(KvResult::Swapped{
+                success: bool::load(input)?,
+                current: Option::load(input)?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl Encode for KvStore {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (self.entries.len() as u64).encode(out);
+        for (key, value) in self.entries.iter() {
+            key.encode(out);
+            value.encode(out);
+        }
+    }
+}
@@ -0,0 +1,70 @@
+#[cfg(test)]
+mod key_value_test {
+    use crate::encoding::Encode;
+    use crate::kv_store::{KvStore,KvOperation,KvResult};
+    use crate::state_machine::StateMachine;
+
+    fn put(key: &str, value: &str) -> KvOperation {
+        KvOperation::Put{key: key.to_owned(), value: value.to_owned()}
+    }
+
+    fn get(key: &str) -> KvOperation {
+        KvOperation::Get{key: key.to_owned()}
+    }
+
+    fn cas(key: &str, expected: Option<&str>, value: &str) -> KvOperation {
+        KvOperation::CompareAndSwap{key: key.to_owned(), expected: expected.map(|e| e.to_owned()), value: value.to_owned()}
+    }
+
+    #[test]
+    fn put_get_delete() {
+        let mut store = KvStore::new();
+        assert_eq!(store.apply(&get("a")), KvResult::Value(None));
+        assert_eq!(store.apply(&put("a", "1")), KvResult::Previous(None));
+        assert_eq!(store.apply(&put("a", "2")), KvResult::Previous(Some("1".to_owned())));
+        assert_eq!(store.apply(&get("a")), KvResult::Value(Some("2".to_owned())));
+        assert_eq!(store.apply(&KvOperation::Delete{key: "a".to_owned()}), KvResult::Previous(Some("2".to_owned())));
+        assert_eq!(store.apply(&get("a")), KvResult::Value(None));
+        assert_eq!(store.len(), 0);
+    }
+
+    #[test]
+    fn compare_and_swap_should_need_expected_value() {
+        let mut store = KvStore::new();
+        assert_eq!(store.apply(&cas("a", None, "1")), KvResult::Swapped{success: true, current: Some("1".to_owned())});
+        assert_eq!(store.apply(&cas("a", None, "2")), KvResult::Swapped{success: false, current: Some("1".to_owned())});
+        assert_eq!(store.apply(&cas("a", Some("1"), "2")), KvResult::Swapped{success: true, current: Some("2".to_owned())});
+        assert_eq!(store.get("a"), Some(&"2".to_owned()));
+    }
+
+    #[test]
+    fn digest_should_not_depend_on_insertion_order() {
+        let mut a = KvStore::new();
+        a.apply(&put("x", "1"));
+        a.apply(&put("y", "2"));
+        let mut b = KvStore::new();
+        b.apply(&put("y", "2"));
+        b.apply(&put("x", "1"));
+        assert_eq!(a.digest(), b.digest());
+        assert_eq!(a.snapshot(), b.snapshot());
+        b.apply(&put("x", "3"));
+        assert_ne!(a.digest(), b.digest());
+    }
+
+    #[test]
+    fn encoding_should_tell_variants_apart() {
+        assert_ne!(KvResult::Value(None).to_bytes(), KvResult::Previous(None).to_bytes());
+        assert_ne!(KvResult::Value(None).to_bytes(), KvResult::Value(Some("".to_owned())).to_bytes());
+        assert_ne!(get("a").to_bytes(), KvOperation::Delete{key: "a".to_owned()}.to_bytes());
+    }
+
+    #[test]
+    fn operations_should_parse_from_console() {
+        assert_eq!(KvOperation::parse("put a 1"), Some(put("a", "1")));
+        assert_eq!(KvOperation::parse(" get  a "), Some(get("a")));
+        assert_eq!(KvOperation::parse("cas a - 1"), Some(cas("a", None, "1")));
+        assert_eq!(KvOperation::parse("cas a 1 2"), Some(cas("a", Some("1"), "2")));
+        assert_eq!(KvOperation::parse("put a"), None);
+        assert_eq!(KvOperation::parse("Advanced tip message"), None);
+    }
+}
@@ -1,50 +1,77 @@
 // An attribute to hide warnings for unused code.
 #![allow(dead_code)]
 
+mod client;
+mod client_test;
+mod config;
+mod crypto;
+mod crypto_test;
 mod dto;
 mod dto_test;
+mod encoding;
+mod error;
+mod hash;
+mod hash_test;
+mod kv_store;
+mod kv_store_test;
 mod network;
 mod network_test;
 mod node;
 mod node_test;
 mod reqtable;
 mod reqtable_test;
+mod slot;
+mod slot_test;
+mod state_machine;
+mod state_machine_test;
 mod sufficiency;
 mod sufficiency_test;
 mod test_util;
 mod ui;
 mod util;
+mod view;
+mod view_test;
+mod wal;
+mod wal_test;
 use network::Network;
-use crate::dto::{PrePrepare};
-use crate::node::{Message};
-use ui::{interactive_mode,print_queue,print_statuses};
-use std::sync::{Arc,RwLock};
+use kv_store::{KvStore,KvOperation};
+use config::{Config,Authentication};
+use ui::{interactive_mode,print_queue,print_statuses,print_clients};
 use std::env;
 use std::thread;
 use std::time::Duration;
 
-fn queue_requests(net: &mut Network) {
-    let sender_id = 0;
-    let target_id = 1;
-    net.queue_add(
-            Message::preprepare(
-                sender_id,
-                target_id,
-                Arc::new(RwLock::new(PrePrepare::new(
-                    0,
-                    1,
-                    "Advanced tip message".to_owned(),
-                    sender_id)))));
+const CLIENT_ID: u64 = 100;
+
+fn queue_requests(net: &mut Network<KvStore>) {
+    net.add_client(CLIENT_ID);
+    net.submit(CLIENT_ID, KvOperation::Put{key: "greeting".to_owned(), value: "hello".to_owned()});
+    net.submit(CLIENT_ID, KvOperation::CompareAndSwap{
+        key: "greeting".to_owned(),
+        expected: Some("hello".to_owned()),
+        value: "hello again".to_owned(),
+    });
+    net.submit(CLIENT_ID, KvOperation::Get{key: "greeting".to_owned()});
 }
 
 fn is_interactive_ui(args: &mut env::Args) -> bool {
     args.any(|arg| arg == "--ui")
 }
 
+fn is_mac_mode(args: &mut env::Args) -> bool {
+    args.any(|arg| arg == "--mac")
+}
+
 fn main() {
-    let mut net = Network::new(5);
+    let authentication = if is_mac_mode(&mut env::args()) {
+        Authentication::Authenticators
+    } else {
+        Authentication::Signatures
+    };
+    let mut net: Network<KvStore> = Network::with_config(5, Config{authentication, ..Config::default()});
     if is_interactive_ui(&mut env::args()) {
-        interactive_mode(&mut net);
+        net.add_client(CLIENT_ID);
+        interactive_mode(&mut net, CLIENT_ID);
         return
     }
     println!("To run with interactive UI add option '--ui'");
@@ -54,7 +81,9 @@ fn main() {
         print_statuses(&net);
         println!("Ticking");
         net.queue_update();
-        let _res = net.tick();
+        net.tick_queue_all();
+        net.tick_until_empty_skip_queue();
         thread::sleep(Duration::from_millis(100));
     }
+    print_clients(&net);
 }
@@ -1,61 +1,135 @@
 use crate::node::{Node,Message,NodeCtrl,State};
-use crate::dto::{ID,Shutdown};
+use crate::dto::{ID,Timestamp,Reconfiguration,Shutdown,SuspectPrimary};
+use crate::state_machine::StateMachine;
+use crate::client::Client;
+use crate::config::Config;
+use crate::crypto::{KeyRing,Signer};
+use crate::sufficiency::{fault_threshold,tolerates};
+use crate::error::PbftError;
+use crate::wal::WriteAheadLog;
 use std::collections::{HashMap,HashSet,VecDeque};
 use std::thread::JoinHandle;
 use std::sync::{Arc,RwLock,Mutex};
 use std::sync::mpsc;
-use std::sync::mpsc::{Receiver,TryRecvError};
+use std::sync::mpsc::{Sender,Receiver,TryRecvError};
 use std::iter::{Iterator};
+use std::time::{Duration,Instant,SystemTime,UNIX_EPOCH};
 
 #[derive(Debug)]
-pub struct Network {
-    nodes: HashMap<ID, NodeCtrl>,
-    inter_receiver: Receiver<Message>,
-    queue: VecDeque<Message>,
+pub struct Network<S: StateMachine> {
+    nodes: HashMap<ID, NodeCtrl<S>>,
+    all_nodes: HashSet<ID>, // current configuration, including replicas that crashed
+    config: Config,
+    inter_sender: Sender<Message<S>>,
+    inter_receiver: Receiver<Message<S>>,
+    queue: VecDeque<Message<S>>,
+    clients: HashMap<ID, Client<S>>,
+    key_ring: KeyRing,
+    recovery_queue: VecDeque<ID>, // replicas waiting for their proactive recovery
+    recovering: HashSet<ID>, // at most f at a time
+    last_recovery_round: Instant,
 }
 
-fn create_nodes(size: usize) -> (HashMap<ID, NodeCtrl>, Receiver<Message>) {
-    let (inter_sender, inter_receiver) = mpsc::channel();
-    let node_ids: &HashSet<ID> = &(0..size as ID).into_iter().collect();
-    let mut nodes: HashMap<ID, NodeCtrl> = HashMap::new();
+// Every run gets fresh keys
+fn new_seed() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0)
+}
+
+// Every node starts from its own copy of the default machine
+fn create_nodes<S>(node_ids: &HashSet<ID>, config: &Config, key_ring: &KeyRing, inter_sender: &Sender<Message<S>>) -> Result<HashMap<ID, NodeCtrl<S>>, PbftError>
+where S: StateMachine + Default {
+    let mut nodes: HashMap<ID, NodeCtrl<S>> = HashMap::new();
     for i in node_ids {
-        nodes.insert(*i, Node::spawn(*i, node_ids, inter_sender.clone()));
+        let credentials = key_ring.get_credentials(*i).unwrap();
+        nodes.insert(*i, Node::try_spawn(*i, node_ids, inter_sender.clone(), config, credentials, S::default())?);
     }
-    return (nodes, inter_receiver);
+    Ok(nodes)
 }
 
-impl Network {
-    pub fn new(size: usize) -> Network {
-        let (nodes, inter_receiver) = create_nodes(size);
-        Network{
-            nodes: nodes,
-            inter_receiver: inter_receiver,
-            queue: VecDeque::new(),
+impl<S: StateMachine + Default> Network<S> {
+    pub fn new(size: usize) -> Network<S> {
+        Network::with_config(size, Config::default())
+    }
+
+    pub fn with_config(size: usize, config: Config) -> Network<S> {
+        match Network::try_with_config(size, config) {
+            Ok(net) => net,
+            Err(e) => panic!("{}", e),
         }
     }
 
-    pub fn tick(&mut self) -> Result<bool, String> {
-        if self.nodes.len() == 0 {
-            return Err("No nodes were found".to_owned());
+    // An explicit f needs at least 3f+1 replicas, a write-ahead log has to be readable
+    pub fn try_with_config(size: usize, config: Config) -> Result<Network<S>, PbftError> {
+        if let Some(f) = config.fault_threshold {
+            if !tolerates(size, f) {
+                return Err(PbftError::TooFewReplicas{node_count: size, f});
+            }
         }
-        match self.queue.pop_front() {
-            Some(req) => {
-                //println!("[Network] Processing request");
-                return self.send(req);
+        let node_ids: HashSet<ID> = (0..size as ID).collect();
+        let key_ring = KeyRing::generate(&node_ids, new_seed());
+        let (inter_sender, inter_receiver) = mpsc::channel();
+        let nodes = create_nodes(&node_ids, &config, &key_ring, &inter_sender)?;
+        Ok(Network{
+            nodes,
+            all_nodes: node_ids,
+            config,
+            inter_sender,
+            inter_receiver,
+            queue: VecDeque::new(),
+            clients: HashMap::new(),
+            key_ring,
+            recovery_queue: VecDeque::new(),
+            recovering: HashSet::new(),
+            last_recovery_round: Instant::now(),
+        })
+    }
+
+    // A removed node comes back from genesis or from its write-ahead log, state transfer brings it up to date.
+    // It stays down if the log can't be read
+    pub fn restart_node(&mut self, id: ID) -> bool {
+        if self.nodes.contains_key(&id) || !self.all_nodes.contains(&id) {
+            return false;
+        }
+        let credentials = match self.key_ring.get_credentials(id) {
+            Some(credentials) => credentials,
+            None => return false,
+        };
+        match Node::try_spawn(id, &self.all_nodes, self.inter_sender.clone(), &self.config, credentials, S::default()) {
+            Ok(node) => {
+                self.nodes.insert(id, node);
+                true
+            },
+            Err(e) => {
+                println!("[Network] Can't restart node {:?}: {}", id, e);
+                false
             },
-            None => {
-                return Err("No more requests".to_owned());
-            }
         }
     }
 
-    pub fn tick_queue_all(&mut self) {
-        while !self.queue.is_empty() {
-            let _ = self.tick();
+    // The new replica starts from genesis in the new configuration and catches up
+    // through state transfer after the next checkpoint. The others count it in
+    // once the change is ordered and executed
+    pub fn add_replica(&mut self, id: ID) -> bool {
+        if self.all_nodes.contains(&id) || self.nodes.contains_key(&id) {
+            return false;
+        }
+        self.key_ring.add_key(id, new_seed());
+        self.all_nodes.insert(id);
+        // the others hear of it only once it runs, a failed attempt leaves nothing behind
+        if !self.restart_node(id) {
+            self.all_nodes.remove(&id);
+            self.key_ring.remove_key(id);
+            return false;
         }
+        self.distribute_credentials();
+        self.reconfigure(Reconfiguration::AddReplica(id));
+        true
     }
 
     pub fn queue_update(&mut self) {
+        self.retire_replicas();
+        self.schedule_recovery_round();
+        self.advance_recoveries();
         for _ in 0..10 {
             match self.inter_receiver.try_recv() {
                 Ok(message) => self.queue.push_back(message),
@@ -70,6 +144,148 @@ impl Network {
         }
     }
 
+    // Blocks until a replica sends something, false if none does within `timeout`
+    pub fn queue_next(&mut self, timeout: Duration) -> bool {
+        match self.inter_receiver.recv_timeout(timeout) {
+            Ok(message) => {
+                self.queue.push_back(message);
+                true
+            },
+            Err(_) => false,
+        }
+    }
+
+    // Recovery is staggered: a replica waits in the queue until fewer than f are recovering
+    pub fn schedule_recovery(&mut self, id: ID) -> bool {
+        if !self.all_nodes.contains(&id) || self.recovery_queue.contains(&id) || self.recovering.contains(&id) {
+            return false;
+        }
+        self.recovery_queue.push_back(id);
+        true
+    }
+
+    // Every `recovery_period` each replica of the configuration gets in line
+    fn schedule_recovery_round(&mut self) {
+        let period = match self.config.recovery_period {
+            Some(period) if self.last_recovery_round.elapsed() >= period => period,
+            _ => return,
+        };
+        println!("[Network] Recovery round after {:?}", period);
+        self.last_recovery_round = Instant::now();
+        let mut ids: Vec<ID> = self.all_nodes.iter().copied().collect();
+        ids.sort();
+        for id in ids {
+            self.schedule_recovery(id);
+        }
+    }
+
+    fn advance_recoveries(&mut self) {
+        let finished: Vec<ID> = self.recovering.iter()
+            .filter(|id| self.nodes.get(id).map(|node| !node.get_state().lock().map(|state| state.is_recovering()).unwrap_or(true)).unwrap_or(true)).copied()
+            .collect();
+        for id in finished {
+            println!("[Network] Node {:?} recovered", id);
+            self.recovering.remove(&id);
+        }
+        while self.recovering.len() < fault_threshold(self.config.fault_threshold, &self.all_nodes) {
+            let id = match self.recovery_queue.pop_front() {
+                Some(id) => id,
+                None => break,
+            };
+            if self.recover_node(id) {
+                self.recovering.insert(id);
+            }
+        }
+    }
+
+    // The replica loses its in-memory state, comes back from the checkpoint it kept
+    // and gets a new key: whatever an attacker learned about the old one is useless
+    fn recover_node(&mut self, id: ID) -> bool {
+        let checkpoint = match self.nodes.get(&id) {
+            Some(node) => match node.get_state().lock() {
+                Ok(state) => state.get_stable_checkpoint(),
+                Err(_) => None,
+            },
+            None => return false,
+        };
+        // it is back once a checkpoint above what the others had executed is stable
+        let target = self.nodes.iter()
+            .filter(|(other, _)| **other != id)
+            .filter_map(|(_, node)| node.get_state().lock().ok().map(|state| state.get_last_executed()))
+            .max()
+            .unwrap_or(0);
+        println!("[Network] Recovering node {:?}", id);
+        if let Some(join_handle) = self.remove_node(id) {
+            let _ = join_handle.join();
+        }
+        // the checkpoint is all it keeps, the log is signed with the old key. Only a
+        // stopped replica can't write to it anymore. If it can't go the replica comes
+        // back as it was
+        if let Some(dir) = &self.config.wal_dir {
+            if let Err(e) = WriteAheadLog::remove(dir, id) {
+                println!("[Network] Can't discard the log of node {:?}: {}", id, e);
+                self.restart_node(id);
+                return false;
+            }
+        }
+        self.key_ring.add_key(id, new_seed());
+        self.distribute_credentials();
+        if !self.restart_node(id) {
+            return false;
+        }
+        if let Some(node) = self.nodes.get(&id) {
+            if let Ok(mut state) = node.get_state().lock() {
+                state.recover(id, checkpoint, target, self.inter_sender.clone());
+            }
+        }
+        true
+    }
+
+    pub fn get_recovering(&self) -> &HashSet<ID> {
+        &self.recovering
+    }
+
+    pub fn get_recovery_queue(&self) -> impl Iterator<Item = &ID> + '_ {
+        self.recovery_queue.iter()
+    }
+
+    // The replica leaves once the change executes, see `retire_replicas`
+    pub fn remove_replica(&mut self, id: ID) -> bool {
+        if !self.all_nodes.contains(&id) || self.all_nodes.len() <= 1 {
+            return false;
+        }
+        if let Some(f) = self.config.fault_threshold {
+            if !tolerates(self.all_nodes.len() - 1, f) {
+                return false;
+            }
+        }
+        self.all_nodes.remove(&id);
+        self.reconfigure(Reconfiguration::RemoveReplica(id));
+        true
+    }
+}
+
+impl<S: StateMachine> Network<S> {
+    pub fn tick(&mut self) -> Result<bool, PbftError> {
+        if self.nodes.is_empty() {
+            return Err(PbftError::NoNodes);
+        }
+        match self.queue.pop_front() {
+            Some(req) => {
+                self.send(req)
+            },
+            None => {
+                Err(PbftError::QueueEmpty)
+            }
+        }
+    }
+
+    pub fn tick_queue_all(&mut self) {
+        while !self.queue.is_empty() {
+            let _ = self.tick();
+        }
+    }
+
     pub fn tick_until_empty_skip_queue(&mut self) {
         loop {
             match self.inter_receiver.try_recv() {
@@ -87,49 +303,184 @@ impl Network {
         }
     }
 
-    pub fn queue_add(&mut self, req: Message) {
+    pub fn queue_add(&mut self, req: Message<S>) {
         self.queue.push_back(req)
     }
 
-    fn send(&mut self, req: Message) -> Result<bool, String> {
+    fn send(&mut self, req: Message<S>) -> Result<bool, PbftError> {
+        if req.get_reply().is_some() {
+            return self.send_to_client(req);
+        }
         self.send_to_node(req.get_target_id(), req)
     }
 
-    fn send_to_node(&mut self, id: ID, req: Message) -> Result<bool, String> {
-        let maybe_node_data: Option<&NodeCtrl> = self.nodes.get(&id);
+    fn send_to_client(&mut self, req: Message<S>) -> Result<bool, PbftError> {
+        let reply = match req.get_reply() {
+            Some(reply) => reply,
+            None => return Ok(false),
+        };
+        let client_id = req.get_target_id();
+        let timestamp = match self.clients.get_mut(&client_id) {
+            Some(client) => match reply.read() {
+                Ok(reply) => {
+                    client.handle_reply(&*reply);
+                    reply.get_timestamp()
+                },
+                Err(_) => return Err(PbftError::LockPoisoned),
+            },
+            None => return Ok(false),
+        };
+        self.fall_back_if_stuck(client_id, timestamp);
+        Ok(true)
+    }
+
+    // Replicas disagree on a read-only request, order it like any other
+    fn fall_back_if_stuck(&mut self, client_id: ID, timestamp: Timestamp) {
+        let client = match self.clients.get_mut(&client_id) {
+            Some(client) if client.is_read_stuck(timestamp) => client,
+            _ => return,
+        };
+        let request = client.fall_back(timestamp);
+        if let (Some(request), Some(primary_id)) = (request, client.get_primary()) {
+            self.queue_add(Message::request(client_id, primary_id, Arc::new(RwLock::new(request))));
+        }
+    }
+
+    pub fn add_client(&mut self, id: ID) {
+        let client = Client::from_config(id, self.get_nodes(), &self.config, self.key_ring.get_verifier());
+        self.clients.insert(id, client);
+    }
+
+    // Queues a new request from the client to the primary it knows about
+    pub fn submit(&mut self, client_id: ID, op: S::Operation) -> Option<Timestamp> {
+        let client = self.clients.get_mut(&client_id)?;
+        let request = client.new_request(op);
+        let timestamp = request.get_timestamp();
+        let primary_id = client.get_primary()?;
+        self.queue_add(Message::request(client_id, primary_id, Arc::new(RwLock::new(request))));
+        Some(timestamp)
+    }
+
+    // Queues a read-only request to every replica
+    pub fn submit_read(&mut self, client_id: ID, op: S::Operation) -> Option<Timestamp> {
+        let client = self.clients.get_mut(&client_id)?;
+        let request = client.new_read_request(op);
+        let timestamp = request.get_timestamp();
+        let request = Arc::new(RwLock::new(request));
+        for id in self.get_nodes() {
+            self.queue_add(Message::request(client_id, id, request.clone()));
+        }
+        Some(timestamp)
+    }
+
+    // Sends a request that got no result yet once more, this time to every replica
+    pub fn retry(&mut self, client_id: ID, timestamp: Timestamp) -> bool {
+        let request = match self.clients.get(&client_id).and_then(|client| client.retry(timestamp)) {
+            Some(request) => Arc::new(RwLock::new(request)),
+            None => return false,
+        };
+        for id in self.get_nodes() {
+            self.queue_add(Message::request(client_id, id, request.clone()));
+        }
+        true
+    }
+
+    pub fn get_client(&self, id: &ID) -> Option<&Client<S>> {
+        self.clients.get(id)
+    }
+
+    pub fn get_clients(&self) -> impl Iterator<Item = (&ID, &Client<S>)> + '_ {
+        self.clients.iter()
+    }
+
+    fn send_to_node(&mut self, id: ID, req: Message<S>) -> Result<bool, PbftError> {
+        let maybe_node_data: Option<&NodeCtrl<S>> = self.nodes.get(&id);
         match maybe_node_data {
             Some(node_data) => match node_data.get_data_sender().send(req) {
                 Ok(()) => Ok(true),
-                Err(e) => Err(format!("Can't send: {:?}", e)),
+                Err(_) => Err(PbftError::SendFailed{target_id: id}),
             },
             None => Ok(false),
         }
     }
 
-    pub fn remove_node(&mut self, id: ID) -> Option<JoinHandle<Result<(), String>>> {
+    pub fn remove_node(&mut self, id: ID) -> Option<JoinHandle<Result<(), PbftError>>> {
         let node_res = self.send_to_node(id, Message::shutdown(0 as ID, 0 as ID, Arc::new(RwLock::new(Shutdown{}))));
-        let tuple: Option<NodeCtrl> = match node_res {
+        let tuple: Option<NodeCtrl<S>> = match node_res {
             Ok(_b) => self.nodes.remove(&id),
             Err(_e) => None,
         };
         tuple.map(|t| t.get_join_handle())
     }
 
-    pub fn get_statuses<'a>(&'a self) -> impl Iterator<Item = (&ID, Arc<Mutex<State>>)> + 'a {
+    // Every replica gets the change, only the primary orders it
+    fn reconfigure(&mut self, reconfiguration: Reconfiguration) {
+        let reconfiguration = Arc::new(RwLock::new(reconfiguration));
+        for id in self.get_nodes() {
+            self.queue_add(Message::reconfiguration(id, id, reconfiguration.clone()));
+        }
+        for client in self.clients.values_mut() {
+            client.set_nodes(self.all_nodes.clone());
+        }
+    }
+
+    // Running replicas and the clients learn the keys of the ones that joined or recovered
+    fn distribute_credentials(&mut self) {
+        for id in self.get_nodes() {
+            if let Some(credentials) = self.key_ring.get_credentials(id) {
+                let _ = self.send_to_node(id, Message::credentials(id, id, Arc::new(RwLock::new(credentials))));
+            }
+        }
+        for client in self.clients.values_mut() {
+            client.set_verifier(self.key_ring.get_verifier());
+        }
+    }
+
+    // Threads of replicas that executed their own removal are shut down
+    fn retire_replicas(&mut self) {
+        let retired: Vec<ID> = self.nodes.iter()
+            .filter(|(id, _)| !self.all_nodes.contains(id))
+            .filter(|(id, node)| node.get_state().lock().map(|state| !state.is_member(**id)).unwrap_or(false))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in retired {
+            println!("[Network] Retiring node {:?}", id);
+            if let Some(join_handle) = self.remove_node(id) {
+                let _ = join_handle.join();
+            }
+        }
+    }
+
+    // Make the node give up on the primary of its current view
+    pub fn suspect_primary(&mut self, id: ID) -> Result<bool, PbftError> {
+        self.send_to_node(id, Message::suspect(id, id, Arc::new(RwLock::new(SuspectPrimary{}))))
+    }
+
+    pub fn get_statuses(&self) -> impl Iterator<Item = (&ID, Arc<Mutex<State<S>>>)> + '_ {
         self.nodes.iter().map(|(id, node_ctrl)| {
             (id, node_ctrl.get_state())
         })
     }
 
-    pub fn get_node(&self, id: &ID) -> Option<&NodeCtrl> {
+    // Signing key of a node, for injecting messages on its behalf
+    pub fn get_signer(&self, id: ID) -> Option<Arc<dyn Signer>> {
+        self.key_ring.get_signer(id)
+    }
+
+    pub fn get_node(&self, id: &ID) -> Option<&NodeCtrl<S>> {
         self.nodes.get(id)
     }
 
-    pub fn get_queue<'a>(&'a self) -> impl Iterator<Item = &Message> + 'a {
+    pub fn get_queue(&self) -> impl Iterator<Item = &Message<S>> + '_ {
         self.queue.iter()
     }
 
     pub fn get_nodes(&self) -> HashSet<ID> {
-        self.nodes.keys().map(|k| *k).collect()
+        self.nodes.keys().copied().collect()
+    }
+
+    // The configuration, replicas that are down included
+    pub fn get_all_nodes(&self) -> &HashSet<ID> {
+        &self.all_nodes
     }
 }
@@ -1,49 +1,218 @@
-use crate::dto::{PrePrepare,Prepare,Commit,NodeID,ID,Tip,Shutdown,NodeRequest};
+use crate::dto::{Batch,Request,Reply,PrePrepare,Prepare,Commit,Checkpoint,ViewChange,NewView,PreparedCert,CommittedCert,FetchState,StateTransfer,Reconfiguration,NodeID,ID,Timestamp,Digest,Shutdown,SuspectPrimary,NodeRequest};
 use std::sync::mpsc;
-use std::sync::mpsc::{Sender,Receiver};
+use std::sync::mpsc::{Sender,Receiver,RecvTimeoutError};
 use std::option::Option;
 use std::thread;
 use std::thread::JoinHandle;
 use std::sync::{Arc,Mutex,RwLock,RwLockReadGuard};
-use std::collections::HashSet;
+use std::collections::{BTreeMap,HashMap,HashSet};
 use std::result::{Result};
+use std::time::{Duration,Instant};
+use std::path::Path;
 use crate::util::find_others;
 use crate::reqtable::RequestTable;
-use crate::sufficiency::{one,two_thirds};
-use crate::util::{convert_err};
+use crate::slot::Slots;
+use crate::sufficiency::{SufficiencyChecker,single,tolerates};
+use crate::util::convert_err;
+use crate::error::PbftError;
+use crate::config::{Config,Authentication};
+use crate::crypto::{Authenticator,Credentials,SessionKeys,Signed,Signer,Verifier};
+use crate::encoding::{Encode,Persist};
+use crate::hash::{sha256,to_hex};
+use crate::state_machine::StateMachine;
+use crate::wal::{WriteAheadLog,Entry,Direction};
+use crate::view::{primary,is_valid_view_change,is_valid_checkpoint_proof,is_valid_committed_cert,new_view_preprepares,is_same_preprepares};
 
+// A batch executed before it committed, with what it takes to undo it
 #[derive(Debug)]
-pub struct State {
-    tip: Tip, // current consensus viewpoint of the node
+struct Tentative<S: StateMachine> {
     seq_id: ID,
+    batch: Batch<S::Operation>,
+    results: Vec<S::Result>, // one per request that wasn't a duplicate
+    before: S::Snapshot, // committed state the batch was applied to
+}
+
+// The last reply sent to every client: a retry gets it again, anything older is dropped
+pub type ReplyCache<R> = BTreeMap<ID, Reply<R>>;
+type ViewChangeVotes<O> = HashMap<NodeID, Arc<RwLock<ViewChange<O>>>>; // per sender, for one view
+
+// What a checkpoint covers: the machine and the reply cache, so that a replica that
+// catches up through state transfer discards the same duplicates as everybody else
+#[derive(Debug)]
+pub struct StateSnapshot<S: StateMachine> {
+    machine: S::Snapshot,
+    replies: ReplyCache<S::Result>,
+}
+
+impl<S: StateMachine> Clone for StateSnapshot<S> {
+    fn clone(&self) -> StateSnapshot<S> {
+        StateSnapshot{
+            machine: self.machine.clone(),
+            replies: self.replies.clone(),
+        }
+    }
+}
+
+// n, its proof C and the state it vouches for: what a restarted or recovering replica starts from
+pub type StableCheckpoint<S> = (ID, Vec<Checkpoint>, StateSnapshot<S>);
+
+impl<S: StateMachine> Persist for StateSnapshot<S> {
+    fn persist(&self, out: &mut Vec<u8>) {
+        self.machine.persist(out);
+        self.replies.persist(out);
+    }
+    fn load(input: &mut &[u8]) -> Option<StateSnapshot<S>> {
+        Some(StateSnapshot{
+            machine: S::Snapshot::load(input)?,
+            replies: ReplyCache::load(input)?,
+        })
+    }
+}
+
+impl<S: StateMachine> StateSnapshot<S> {
+    pub fn new(machine: S::Snapshot, replies: ReplyCache<S::Result>) -> StateSnapshot<S> {
+        StateSnapshot{
+            machine,
+            replies,
+        }
+    }
+    pub fn get_machine(&self) -> &S::Snapshot {
+        &self.machine
+    }
+    pub fn get_replies(&self) -> &ReplyCache<S::Result> {
+        &self.replies
+    }
+}
+
+#[derive(Debug)]
+pub struct State<S: StateMachine> {
+    machine: S, // the replicated application, only committed requests reach it
+    view_id: ID, // v -- view the node is in (or is moving to)
+    view_active: bool, // false while waiting for the new-view of view_id
+    stable_seq: ID, // h -- seq of the last stable checkpoint
+    log_size: ID, // L -- H = h + L
+    stable_proof: Vec<Checkpoint>, // C -- 2f+1 checkpoints proving stable_seq
+    checkpoint_interval: ID, // K
+    assigned_seq: ID, // last seq handed out to a request while being the primary
     remaining_nodes: HashSet<ID>,
     all_nodes: HashSet<ID>,
-    preprepares: RequestTable<PrePrepare>,
+    preprepares: RequestTable<PrePrepare<S::Operation>>,
     prepares: RequestTable<Prepare>,
     commits: RequestTable<Commit>,
-    sent_preprepare: Option<Arc<RwLock<PrePrepare>>>,
-    sent_prepare: Option<Arc<RwLock<Prepare>>>,
-    sent_commit: Option<Arc<RwLock<Commit>>>,
+    checkpoints: RequestTable<Checkpoint>,
+    checkpoints_ahead: HashMap<NodeID, Checkpoint>, // per replica, its latest checkpoint beyond H
+    snapshots: BTreeMap<ID, StateSnapshot<S>>, // state at every checkpoint that isn't garbage yet
+    fetching: ID, // highest stable checkpoint we asked peers to transfer state for
+    recovering_until: Option<ID>, // proactive recovery is over once a checkpoint above this seq is stable
+    slots: Slots, // per (v, n): what was accepted and sent
+    last_executed: ID, // every seq up to this one has been applied to the machine
+    last_replies: ReplyCache<S::Result>, // per client, only for requests that executed
+    committed: BTreeMap<ID, PrePrepare<S::Operation>>, // committed but waiting for a lower seq to execute
+    tentative_execution: bool,
+    tentative: Option<Tentative<S>>, // last_executed + 1 when it is prepared but not committed yet
+    waiting_reads: Vec<Request<S::Operation>>, // read-only requests must not see tentative state
+    view_changes: HashMap<ID, ViewChangeVotes<S::Operation>>,
+    sent_new_view: Option<ID>,
+    pending: Batch<S::Operation>, // requests the primary hasn't ordered yet
+    pending_reconfigurations: Vec<Reconfiguration>, // membership changes asked for that haven't executed yet
+    batch_started: Option<Instant>, // when the oldest pending request arrived
+    max_batch_size: usize,
+    max_batch_delay: Duration,
+    waiting: HashMap<ID, Timestamp>, // per client, the latest request received that hasn't executed yet
+    timer_started: Option<Instant>, // the liveness timer runs while requests or a new view are awaited
+    view_change_timeout: Option<Duration>, // the initial timeout, none disables the timer
+    timeout: Duration, // the current timeout, doubled for every view change in a row
+    quorum: Arc<dyn SufficiencyChecker>, // 2f+1, by heads or by voting weight
+    weak_certificate: Arc<dyn SufficiencyChecker>, // f+1, by heads or by voting weight
+    fault_threshold: Option<usize>, // f as configured, none follows |R|
+    authentication: Authentication,
+    signer: Arc<dyn Signer>,
+    verifier: Arc<dyn Verifier>,
+    session_keys: Arc<SessionKeys>,
+    wal: Option<WriteAheadLog>, // none until the log is replayed, or when there is no log
+    replaying: bool, // what the log holds is being handled again
 }
 
-impl State {
-    pub fn genesis(me: ID, all_nodes: HashSet<ID>) -> Arc<Mutex<State>> {
+impl<S: StateMachine> State<S> {
+    pub fn genesis(me: ID, all_nodes: HashSet<ID>, config: &Config, credentials: Credentials, machine: S) -> Arc<Mutex<State<S>>> {
         let remaining_nodes = find_others(me, all_nodes.iter()).collect();
+        let mut snapshots = BTreeMap::new();
+        snapshots.insert(0, StateSnapshot::new(machine.snapshot(), ReplyCache::new()));
+        let quorum = config.quorum();
         Arc::new(Mutex::new(State{
-            tip: "genesis".to_owned(),
-            seq_id: 0,
-            preprepares: RequestTable::new(one),
-            prepares: RequestTable::new(two_thirds),
-            commits: RequestTable::new(two_thirds),
-            remaining_nodes: remaining_nodes,
-            all_nodes: all_nodes,
-            sent_preprepare: None,
-            sent_prepare: None,
-            sent_commit: None
+            machine,
+            view_id: 0,
+            view_active: true,
+            stable_seq: 0,
+            stable_proof: Vec::new(),
+            checkpoint_interval: config.checkpoint_interval,
+            log_size: config.log_size,
+            assigned_seq: 0,
+            preprepares: RequestTable::with_checker(Arc::new(single())),
+            prepares: RequestTable::with_checker(quorum.clone()),
+            commits: RequestTable::with_checker(quorum.clone()),
+            checkpoints: RequestTable::with_checker(quorum.clone()),
+            checkpoints_ahead: HashMap::new(),
+            snapshots,
+            fetching: 0,
+            recovering_until: None,
+            remaining_nodes,
+            all_nodes,
+            slots: Slots::new(),
+            last_executed: 0,
+            last_replies: ReplyCache::new(),
+            committed: BTreeMap::new(),
+            tentative_execution: config.tentative_execution,
+            tentative: None,
+            waiting_reads: Vec::new(),
+            view_changes: HashMap::new(),
+            sent_new_view: None,
+            pending: Batch::new(),
+            pending_reconfigurations: Vec::new(),
+            batch_started: None,
+            max_batch_size: config.max_batch_size,
+            max_batch_delay: config.max_batch_delay,
+            waiting: HashMap::new(),
+            timer_started: None,
+            view_change_timeout: config.view_change_timeout,
+            timeout: config.view_change_timeout.unwrap_or_default(),
+            quorum,
+            weak_certificate: config.weak_certificate(),
+            fault_threshold: config.fault_threshold,
+            authentication: config.authentication,
+            signer: credentials.get_signer(),
+            verifier: credentials.get_verifier(),
+            session_keys: credentials.get_session_keys(),
+            wal: None,
+            replaying: false,
         }))
     }
 
-    pub fn get_preprepares(&self) -> &RequestTable<PrePrepare> {
+    pub fn get_machine(&self) -> &S {
+        &self.machine
+    }
+
+    pub fn get_snapshots(&self) -> &BTreeMap<ID, StateSnapshot<S>> {
+        &self.snapshots
+    }
+
+    pub fn get_view_id(&self) -> ID {
+        self.view_id
+    }
+
+    pub fn is_view_active(&self) -> bool {
+        self.view_active
+    }
+
+    pub fn get_timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn is_timer_running(&self) -> bool {
+        self.timer_started.is_some()
+    }
+
+    pub fn get_preprepares(&self) -> &RequestTable<PrePrepare<S::Operation>> {
         &self.preprepares
     }
 
@@ -55,10 +224,86 @@ impl State {
         &self.commits
     }
 
-    fn send<M>(&self, me: ID, data_sender: Sender<Message>, conversion_fn: fn(ID, ID, Arc<RwLock<M>>) -> Message, request: Arc<RwLock<M>>) {
+    pub fn get_checkpoints(&self) -> &RequestTable<Checkpoint> {
+        &self.checkpoints
+    }
+
+    pub fn get_slots(&self) -> &Slots {
+        &self.slots
+    }
+
+    pub fn get_last_executed(&self) -> ID {
+        self.last_executed
+    }
+
+    pub fn get_last_replies(&self) -> &ReplyCache<S::Result> {
+        &self.last_replies
+    }
+
+    // Committed seqs that can't execute yet because of a gap below them
+    pub fn get_committed(&self) -> &BTreeMap<ID, PrePrepare<S::Operation>> {
+        &self.committed
+    }
+
+    // Replicas of the configuration this node executed up to
+    pub fn get_all_nodes(&self) -> &HashSet<ID> {
+        &self.all_nodes
+    }
+
+    // A removed replica stays silent from the seq that removed it
+    pub fn is_member(&self, me: ID) -> bool {
+        self.all_nodes.contains(&me)
+    }
+
+    pub fn get_stable_seq(&self) -> ID {
+        self.stable_seq
+    }
+
+    pub fn get_stable_proof(&self) -> &Vec<Checkpoint> {
+        &self.stable_proof
+    }
+
+    // What survives a proactive recovery: the last stable checkpoint with its proof
+    pub fn get_stable_checkpoint(&self) -> Option<StableCheckpoint<S>> {
+        self.snapshots.get(&self.stable_seq)
+            .map(|snapshot| (self.stable_seq, self.stable_proof.clone(), snapshot.clone()))
+    }
+
+    pub fn is_recovering(&self) -> bool {
+        self.recovering_until.is_some()
+    }
+
+    // (h, H] -- sequence numbers the node accepts right now
+    pub fn get_water_marks(&self) -> (ID, ID) {
+        (self.stable_seq, self.stable_seq.saturating_add(self.log_size))
+    }
+
+    fn send<M>(&self, me: ID, data_sender: Sender<Message<S>>, conversion_fn: fn(ID, ID, Arc<RwLock<M>>) -> Message<S>, request: Arc<RwLock<M>>)
+    where M: Encode {
+        // once sent it can be held against us, a restart has to know about it
+        if let Some(wal) = &self.wal {
+            if let Err(e) = wal.append(Direction::Sent, me, &conversion_fn(me, me, request.clone()).payload) {
+                println!("[{:?}] Not sending: {}", me, e);
+                return;
+            }
+        }
+        // the same vector goes to everyone, each recipient checks its own MAC
+        let authenticator = match self.authentication {
+            Authentication::Signatures => None,
+            Authentication::Authenticators => match request.read() {
+                Ok(message) => Some(Arc::new(self.session_keys.authenticate(&message.to_bytes(), self.remaining_nodes.iter()))),
+                Err(e) => {
+                    println!("[{:?}] Can't authenticate: {:?}", me, e);
+                    return;
+                },
+            },
+        };
         // Error handling: fire and forget - UDP mode
         for m in Message::multiply(conversion_fn, request, me, &self.remaining_nodes) {
-            //println!("[{:?}] Sending to network", me);
+            let m = match &authenticator {
+                Some(authenticator) => m.with_authenticator(authenticator.clone()),
+                None => m,
+            };
             let res = data_sender.send(m);
             if res.is_err() {
                 println!("[{:?}] Send error: {:?}", me, res.err())
@@ -66,263 +311,1445 @@ impl State {
         }
     }
 
-    fn is_valid_next_seq(&self, req: &NodeRequest) -> bool {
-        req.get_seq_id() > self.seq_id
+    fn is_in_window(water_marks: (ID, ID), seq_id: ID) -> bool {
+        let (low, high) = water_marks;
+        seq_id > low && seq_id <= high
     }
 
-    fn append<M>(reqs: &mut RequestTable<M>, sent_request: &Option<Arc<RwLock<M>>>, message: &Arc<RwLock<M>>) -> Result<(), String>  where M: NodeRequest {
-        // Did we sent any preprepares for this round? We should send only one next preprepare
-        if sent_request.is_some() {
-            return Ok(())
+    fn append<M>(reqs: &mut RequestTable<M>, message: &Arc<RwLock<M>>, water_marks: (ID, ID)) -> Result<(), PbftError>  where M: NodeRequest {
+        // don't let anyone fill the log with sequence numbers we won't ever reach
+        let seq_id = convert_err(message.read())?.get_seq_id();
+        if !Self::is_in_window(water_marks, seq_id) {
+            let (low, high) = water_marks;
+            return Err(PbftError::OutsideWaterMarks{seq_id, low, high});
         }
         reqs.append(message.clone())
     }
 
-    fn validate_message<M, N>(&self,me: ID, reqs: &RequestTable<M>,  message: &N) -> bool
-    where M: NodeRequest + std::fmt::Debug,
-          N: NodeRequest + std::fmt::Debug
+    fn sign<M>(&self, mut message: M) -> M
+    where M: Signed {
+        message.sign(&*self.signer);
+        message
+    }
+
+    // Commits and new-views never leave the replica they were sent to when there are
+    // authenticators, the MAC is all they need. Anything that ends up in a proof for
+    // a third replica is signed in every mode
+    fn sign_unforwarded<M>(&self, message: M) -> M
+    where M: Signed {
+        match self.authentication {
+            Authentication::Signatures => self.sign(message),
+            Authentication::Authenticators => message,
+        }
+    }
+
+    // sigma(i) has to verify against the key of i. A MAC only convinces the replica
+    // it was computed for, so whatever may be forwarded as proof is checked either way
+    fn check_signature<N>(&self, message: &N) -> Result<(), PbftError>
+    where N: Signed {
+        if !message.verify(&*self.verifier) {
+            return Err(PbftError::InvalidSignature{sender_id: message.get_signer_id()});
+        }
+        Ok(())
+    }
+
+    // Counterpart of `sign_unforwarded`: with authenticators the MAC was checked
+    // when the message arrived, see `check_authenticator`
+    fn check_unforwarded<N>(&self, message: &N) -> Result<(), PbftError>
+    where N: Signed {
+        match self.authentication {
+            Authentication::Signatures => self.check_signature(message),
+            Authentication::Authenticators => Ok(()),
+        }
+    }
+
+    // The handlers checked sigma(i) before the message was stored
+    fn validate_message<M, N>(&self, reqs: &RequestTable<M>,  message: &N) -> Result<(), PbftError>
+    where M: NodeRequest,
+          N: NodeRequest
     {
-        println!("[{:?}: {} -> {}; [{:?}]] Msg: {:?}", me, me, message.get_sender_id(), self.all_nodes, message);
         // Was the inserted message valid?
-        //println!("[{:?}] Preprepare sufficiency {:?}", me, self.preprepares.is_sufficient(&message_lock, &self.all_nodes));
         if !reqs.is_sufficient(message, &self.all_nodes) {
-            println!("[{:?}] Drop: message doesn't have enough approvers", me);
-            return false
+            return Err(PbftError::InsufficientApprovers);
         }
-        // seq_id must point to the future one
-        if !self.is_valid_next_seq(message) {
-            println!("[{:?}] Drop: next seq is invalid", me);
-            return false;
+        // normal case messages are only accepted in the active view, the water marks
+        // were checked before the message was stored, see `append`
+        if !self.view_active || message.get_view_id() != self.view_id {
+            return Err(PbftError::WrongView{view_id: message.get_view_id(), current_view_id: self.view_id});
         }
-        true
+        Ok(())
     }
 
-    fn handle_preprepare(&mut self, me: ID, message: Arc<RwLock<PrePrepare>>, data_sender: Sender<Message>) -> Result<(), String> {
-        let result = State::append(&mut self.preprepares, &self.sent_preprepare, &message);
-        if result.is_err() {
-            return result;
+    // Only the primary of v may assign sequence numbers in v, d has to be D(m)
+    // Checks the sender i, that sigma(i) is really from i is up to `check_signature`
+    pub fn check_primary(&self, preprepare: &PrePrepare<S::Operation>) -> Result<(), PbftError> {
+        let primary_id = primary(preprepare.get_view_id(), &self.all_nodes);
+        if primary_id != Some(preprepare.get_sender_id()) {
+            return Err(PbftError::NotPrimary{
+                view_id: preprepare.get_view_id(),
+                sender_id: preprepare.get_sender_id(),
+                primary_id,
+            });
         }
-        convert_err(message.read()).map(|_message_lock| {
-            let message_lock: RwLockReadGuard<PrePrepare> = _message_lock;
-            if !self.validate_message(me, &self.preprepares, &*message_lock) {
-                return;
+        if !preprepare.is_digest_valid() {
+            return Err(PbftError::DigestMismatch{seq_id: preprepare.get_seq_id()});
+        }
+        Ok(())
+    }
+
+    // A backup accepts only one pre-prepare for (v, n)
+    pub fn check_slot(&self, preprepare: &PrePrepare<S::Operation>) -> Result<(), PbftError> {
+        let conflicts = self.slots.get(preprepare.get_view_id(), preprepare.get_seq_id())
+            .map(|slot| slot.conflicts_with(&preprepare.get_digest()))
+            .unwrap_or(false);
+        if conflicts {
+            return Err(PbftError::ConflictingPrePrepare{view_id: preprepare.get_view_id(), seq_id: preprepare.get_seq_id()});
+        }
+        Ok(())
+    }
+
+    // A faulty primary must not add replicas of its own or remove correct ones:
+    // a membership change is prepared only if the administrator asked us for it too
+    pub fn check_reconfiguration(&self, preprepare: &PrePrepare<S::Operation>) -> Result<(), PbftError> {
+        match preprepare.get_reconfiguration() {
+            Some(reconfiguration) if !self.pending_reconfigurations.contains(reconfiguration) =>
+                Err(PbftError::UnrequestedReconfiguration{seq_id: preprepare.get_seq_id()}),
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_preprepare(&mut self, me: ID, message: Arc<RwLock<PrePrepare<S::Operation>>>, data_sender: Sender<Message<S>>) -> Result<(), PbftError> {
+        // forged pre-prepares or ones from anyone but the primary are not even stored
+        {
+            let preprepare = convert_err(message.read())?;
+            self.check_signature(&*preprepare)?;
+            self.check_primary(&*preprepare)?;
+            self.check_slot(&*preprepare)?;
+            self.check_reconfiguration(&*preprepare)?;
+        }
+        let water_marks = self.get_water_marks();
+        Self::append(&mut self.preprepares, &message, water_marks)?;
+        let message_lock: RwLockReadGuard<PrePrepare<S::Operation>> = convert_err(message.read())?;
+        self.validate_message(&self.preprepares, &*message_lock)?;
+        let slot = self.slots.get_mut(message_lock.get_view_id(), message_lock.get_seq_id());
+        slot.accept(message_lock.get_digest());
+        // the primary's pre-prepare already stands for its prepare
+        if slot.has_sent_prepare() || message_lock.get_sender_id() == me {
+            return Ok(());
+        }
+        slot.mark_sent_prepare();
+        // new prepare
+        let prepare = Arc::new(RwLock::new(self.sign(message_lock.make_prepare(me))));
+        // handle our new prepare internally
+        self.handle_prepare(me, prepare.clone(), data_sender.clone())?;
+        self.send(me, data_sender, Message::prepare, prepare);
+        Ok(())
+    }
+
+    fn handle_prepare(&mut self, me: ID, message: Arc<RwLock<Prepare>>, data_sender: Sender<Message<S>>) -> Result<(), PbftError> {
+        // forged votes must not count towards any quorum
+        self.check_signature(&*convert_err(message.read())?)?;
+        let water_marks = self.get_water_marks();
+        Self::append(&mut self.prepares, &message, water_marks)?;
+        let message_lock: RwLockReadGuard<Prepare> = convert_err(message.read())?;
+        self.validate_message(&self.preprepares, &*message_lock)?;
+        // commit only once the request is prepared, and only once per (v, n)
+        if !self.is_prepared(&*message_lock) {
+            return Ok(());
+        }
+        let slot = self.slots.get_mut(message_lock.get_view_id(), message_lock.get_seq_id());
+        if slot.has_sent_commit() {
+            return Ok(());
+        }
+        slot.mark_sent_commit();
+        // new commit
+        let commit = Arc::new(RwLock::new(self.sign_unforwarded(message_lock.make_commit(me))));
+        // handle our new commit internally
+        self.handle_commit(me, commit.clone(), data_sender.clone())?;
+        self.send(me, data_sender.clone(), Message::commit, commit);
+        self.execute_tentatively(me, data_sender);
+        Ok(())
+    }
+
+    fn update_tip(&mut self, me: ID, commit: &Commit, data_sender: Sender<Message<S>>) {
+        // committed-local: prepared and 2f+1 matching commits
+        if !self.is_prepared(commit)
+            || !self.commits.is_sufficient(commit, &self.all_nodes) {
+                return
             }
-            // if we've sent anything we shouldn't send anything twice
-            if self.sent_prepare.is_some() {
-                println!("[{:?}] Preprepare drop: next seq is invalid", me);
-                return;
+        let seq_id = commit.get_seq_id();
+        if seq_id <= self.last_executed || self.committed.contains_key(&seq_id) {
+            return
+        }
+        let found_p = self.preprepares.find(commit);
+        // null requests carry an empty batch
+        let preprepare = match found_p.and_then(|preprepare_lock| preprepare_lock.read().ok().map(|preprepare| preprepare.clone())) {
+            Some(preprepare) => preprepare,
+            None => return,
+        };
+        self.committed.insert(seq_id, preprepare);
+        self.execute_committed(me, data_sender);
+    }
+
+    // Requests execute in seq order: n waits in `committed` until n-1 has executed
+    fn execute_committed(&mut self, me: ID, data_sender: Sender<Message<S>>) {
+        while let Some(preprepare) = self.committed.remove(&(self.last_executed + 1)) {
+            self.last_executed += 1;
+            let batch = preprepare.get_batch();
+            let fresh = self.find_fresh(batch);
+            let results = match self.confirm_tentative(me, self.last_executed, batch) {
+                // already applied, the replies just aren't tentative anymore
+                Some(results) => results,
+                None => batch.iter().zip(fresh.iter())
+                    .filter(|(_, is_fresh)| **is_fresh)
+                    .map(|(request, _)| self.machine.apply(request.get_op()))
+                    .collect(),
+            };
+            let mut results = results.into_iter();
+            for (request, is_fresh) in batch.iter().zip(fresh) {
+                if !is_fresh {
+                    if let Err(e) = self.resend_reply(me, request, data_sender.clone()) {
+                        println!("[{:?}] Request drop: {}", me, e);
+                    }
+                    continue;
+                }
+                if let Some(result) = results.next() {
+                    self.reply_executed(me, request, result, data_sender.clone());
+                }
             }
-            // new prepare
-            let prepare = Arc::new(RwLock::new(message_lock.make_prepare(me)));
-            // handle our new prepare internally
-            let res = self.handle_prepare(me, prepare.clone(), data_sender.clone());
-            if res.is_err() {
-                println!("[{:?}] Prepare insertion err {:?}", me, res.err());
-                return;
+            if let Some(reconfiguration) = preprepare.get_reconfiguration() {
+                self.reconfigure(me, reconfiguration);
             }
-            //println!("[{:?}] Preprepare is sufficient! Sending to {:?}", me, self.all_nodes);
-            self.send(me, data_sender, Message::prepare, prepare);
-        })
+            if self.checkpoint_interval > 0 && self.last_executed.is_multiple_of(self.checkpoint_interval) {
+                self.take_checkpoint(me, self.last_executed, data_sender.clone());
+            }
+        }
+        if !self.committed.is_empty() {
+            println!("[{:?}] Execution waits for seq {:?}", me, self.last_executed + 1);
+        }
+        self.stop_waiting();
+        self.answer_waiting_reads(me, data_sender.clone());
+        self.execute_tentatively(me, data_sender);
     }
 
-    fn handle_prepare(&mut self, me: ID, message: Arc<RwLock<Prepare>>, data_sender: Sender<Message>) -> Result<(), String> {
-        let result = State::append(&mut self.prepares, &self.sent_prepare, &message);
-        if result.is_err() {
-            return result;
+    // Tentative execution: once the request prepares and everything below it has
+    // committed it executes right away, 2f+1 tentative replies convince the client
+    fn execute_tentatively(&mut self, me: ID, data_sender: Sender<Message<S>>) {
+        if !self.tentative_execution || !self.view_active || self.tentative.is_some() {
+            return;
         }
-        convert_err(message.read()).map(|_message_lock| {
-            let message_lock: RwLockReadGuard<Prepare> = _message_lock;
-            if !self.validate_message(me, &self.preprepares, &*message_lock) {
-                return;
+        let seq_id = self.last_executed + 1;
+        let batch = match self.find_prepared_batch(seq_id) {
+            Some(batch) => batch,
+            None => return,
+        };
+        let before = self.machine.snapshot();
+        let mut results = Vec::new();
+        let fresh = self.find_fresh(&batch);
+        for (request, _) in batch.iter().zip(fresh).filter(|(_, is_fresh)| *is_fresh) {
+            let result = self.machine.apply(request.get_op());
+            self.reply_tentatively(me, request, result.clone(), data_sender.clone());
+            results.push(result);
+        }
+        println!("[{:?}] Executed seq {:?} tentatively", me, seq_id);
+        self.tentative = Some(Tentative{
+            seq_id,
+            batch,
+            results,
+            before,
+        });
+    }
+
+    // Batch of a request prepared at seq_id in the current view. Membership
+    // changes can't be undone, they wait until they commit
+    fn find_prepared_batch(&self, seq_id: ID) -> Option<Batch<S::Operation>> {
+        self.preprepares.iter()
+            .filter_map(|pp| pp.read().ok().map(|pp| pp.clone()))
+            .find(|pp| {
+                pp.get_seq_id() == seq_id
+                    && pp.get_view_id() == self.view_id
+                    && pp.get_reconfiguration().is_none()
+                    && self.find_prepared_cert(pp).is_some()
+            })
+            .map(|pp| pp.get_batch().clone())
+    }
+
+    // The new configuration holds from the next seq on: quorums are counted
+    // over it and the primary of the current view is picked from it
+    fn reconfigure(&mut self, me: ID, reconfiguration: &Reconfiguration) {
+        self.pending_reconfigurations.retain(|pending| pending != reconfiguration);
+        if let Err(e) = self.check_tolerated(reconfiguration) {
+            println!("[{:?}] Reconfiguration ignore: {:?} at seq {:?}: {}", me, reconfiguration, self.last_executed, e);
+            return;
+        }
+        let changed = match reconfiguration {
+            Reconfiguration::AddReplica(id) => self.all_nodes.insert(*id),
+            // there is nobody left to run the protocol with an empty configuration
+            Reconfiguration::RemoveReplica(id) => self.all_nodes.len() > 1 && self.all_nodes.remove(id),
+        };
+        if !changed {
+            println!("[{:?}] Reconfiguration ignore: {:?} at seq {:?} changes nothing", me, reconfiguration, self.last_executed);
+            return;
+        }
+        println!("[{:?}] Reconfiguration {:?} at seq {:?}, replicas {:?}", me, reconfiguration, self.last_executed, self.all_nodes);
+        self.remaining_nodes = find_others(me, self.all_nodes.iter()).collect();
+        // a replica that just became primary continues right after the change
+        if self.assigned_seq < self.last_executed {
+            self.assigned_seq = self.last_executed;
+        }
+        if primary(self.view_id, &self.all_nodes) != Some(me) && !self.pending.is_empty() {
+            println!("[{:?}] Request drop: {:?} pending requests, not the primary anymore", me, self.pending.len());
+            self.pending.clear();
+            self.batch_started = None;
+        }
+    }
+
+    // An explicit f needs at least 3f+1 replicas after the change too
+    fn check_tolerated(&self, reconfiguration: &Reconfiguration) -> Result<(), PbftError> {
+        let (f, id) = match (self.fault_threshold, reconfiguration) {
+            (Some(f), Reconfiguration::RemoveReplica(id)) => (f, id),
+            _ => return Ok(()),
+        };
+        let node_count = self.all_nodes.iter().filter(|node_id| *node_id != id).count();
+        if !tolerates(node_count, f) {
+            return Err(PbftError::TooFewReplicas{node_count, f});
+        }
+        Ok(())
+    }
+
+    // Nothing is ordered after a membership change until it executed,
+    // so every seq above it runs in the new configuration
+    fn is_reconfiguring(&self) -> bool {
+        self.preprepares.iter()
+            .filter_map(|pp| pp.read().ok().map(|pp| (pp.get_view_id(), pp.get_seq_id(), pp.get_reconfiguration().is_some())))
+            .any(|(view_id, seq_id, is_reconfiguration)| {
+                is_reconfiguration && view_id == self.view_id && seq_id > self.last_executed
+            })
+    }
+
+    // A request executes only if its timestamp is above the one of the last reply to its
+    // client: retries and stale requests, even within the same batch, are skipped
+    fn find_fresh(&self, batch: &Batch<S::Operation>) -> Vec<bool> {
+        let mut latest: HashMap<ID, Timestamp> = self.last_replies.iter()
+            .map(|(client_id, reply)| (*client_id, reply.get_timestamp()))
+            .collect();
+        batch.iter().map(|request| {
+            let is_fresh = latest.get(&request.get_client_id())
+                .map(|timestamp| request.get_timestamp() > *timestamp)
+                .unwrap_or(true);
+            if is_fresh {
+                latest.insert(request.get_client_id(), request.get_timestamp());
             }
-            // if we've sent anything we shouldn't send anything twice
-            if self.sent_commit.is_some() {
-                println!("[{:?}] Prepare drop: next seq is invalid", me);
-                return;
+            is_fresh
+        }).collect()
+    }
+
+    // Results of the tentative execution when the same batch committed at seq_id
+    fn confirm_tentative(&mut self, me: ID, seq_id: ID, batch: &Batch<S::Operation>) -> Option<Vec<S::Result>> {
+        match self.tentative.take() {
+            Some(tentative) if tentative.seq_id == seq_id && tentative.batch == *batch => Some(tentative.results),
+            Some(tentative) => {
+                println!("[{:?}] Tentative seq {:?} didn't commit, rolling back", me, tentative.seq_id);
+                self.machine.restore(tentative.before);
+                None
+            },
+            None => None,
+        }
+    }
+
+    // The new view may order something else at the tentative seq
+    fn roll_back_tentative(&mut self, me: ID, data_sender: Sender<Message<S>>) {
+        if let Some(tentative) = self.tentative.take() {
+            println!("[{:?}] Rolling back tentative seq {:?}", me, tentative.seq_id);
+            self.machine.restore(tentative.before);
+            self.answer_waiting_reads(me, data_sender);
+        }
+    }
+
+    fn answer_waiting_reads(&mut self, me: ID, data_sender: Sender<Message<S>>) {
+        if self.tentative.is_some() {
+            return;
+        }
+        for request in std::mem::take(&mut self.waiting_reads) {
+            if let Err(e) = self.handle_read_only(me, &request, data_sender.clone()) {
+                println!("[{:?}] Request drop: {}", me, e);
             }
-            // new prepare
-            let commit = Arc::new(RwLock::new(message_lock.make_commit(me)));
-            // handle our new prepare internally
-            let res = self.handle_commit(me, commit.clone(), data_sender.clone());
+        }
+    }
+
+    // d of the machine followed by (c, t, r) of every cached reply
+    fn state_digest(&self) -> Digest {
+        let mut bytes = self.machine.digest().to_bytes();
+        for (client_id, reply) in self.last_replies.iter() {
+            client_id.encode(&mut bytes);
+            reply.get_timestamp().encode(&mut bytes);
+            reply.get_result().encode(&mut bytes);
+        }
+        to_hex(&sha256(&bytes))
+    }
+
+    fn take_checkpoint(&mut self, me: ID, seq_id: ID, data_sender: Sender<Message<S>>) {
+        let checkpoint = self.sign(Checkpoint::new(seq_id, self.state_digest(), me));
+        // executing the same seq again must not announce it twice
+        if self.checkpoints.find_approvers(&checkpoint).contains(&me) {
+            return;
+        }
+        self.snapshots.insert(seq_id, StateSnapshot::new(self.machine.snapshot(), self.last_replies.clone()));
+        let checkpoint = Arc::new(RwLock::new(checkpoint));
+        let res = self.handle_checkpoint(me, checkpoint.clone(), data_sender.clone());
+        if let Err(e) = res {
+            println!("[{:?}] Checkpoint insertion err: {}", me, e);
+            return;
+        }
+        self.send(me, data_sender, Message::checkpoint, checkpoint);
+    }
+
+    fn handle_checkpoint(&mut self, me: ID, message: Arc<RwLock<Checkpoint>>, data_sender: Sender<Message<S>>) -> Result<(), PbftError> {
+        let checkpoint: Checkpoint = convert_err(message.read())?.clone();
+        if !self.all_nodes.contains(&checkpoint.get_sender_id()) {
+            return Err(PbftError::UnknownNode{node_id: checkpoint.get_sender_id()});
+        }
+        if checkpoint.get_seq_id() < self.stable_seq {
+            return Err(PbftError::StaleSequence{seq_id: checkpoint.get_seq_id(), last_seq_id: self.stable_seq});
+        }
+        // nobody correct checkpoints between two intervals
+        let (low, high) = self.get_water_marks();
+        if self.checkpoint_interval > 0 && !checkpoint.get_seq_id().is_multiple_of(self.checkpoint_interval) {
+            return Err(PbftError::OutsideWaterMarks{seq_id: checkpoint.get_seq_id(), low, high});
+        }
+        self.check_signature(&checkpoint)?;
+        if checkpoint.get_seq_id() == self.stable_seq {
+            self.extend_stable_proof(checkpoint);
+            return Ok(());
+        }
+        if checkpoint.get_seq_id() > high {
+            return self.handle_checkpoint_ahead(me, checkpoint, data_sender);
+        }
+        self.checkpoints.append(message)?;
+        if !self.checkpoints.is_sufficient(&checkpoint, &self.all_nodes) {
+            return Ok(());
+        }
+        let proof: Vec<Checkpoint> = self.checkpoints.find_all(&checkpoint).iter()
+            .filter_map(|c| c.read().ok().map(|c| c.clone()))
+            .collect();
+        self.stabilize(me, checkpoint.get_seq_id(), proof, data_sender);
+        Ok(())
+    }
+
+    // Beyond H the table would grow with whatever a faulty replica sends, only the latest
+    // checkpoint of every replica is kept: enough to tell the others moved on without us
+    fn handle_checkpoint_ahead(&mut self, me: ID, checkpoint: Checkpoint, data_sender: Sender<Message<S>>) -> Result<(), PbftError> {
+        let seq_id = checkpoint.get_seq_id();
+        let is_latest = self.checkpoints_ahead.get(&checkpoint.get_sender_id())
+            .map(|known| known.get_seq_id() < seq_id)
+            .unwrap_or(true);
+        if is_latest {
+            self.checkpoints_ahead.insert(checkpoint.get_sender_id(), checkpoint.clone());
+        }
+        let proof: Vec<Checkpoint> = self.checkpoints_ahead.values()
+            .filter(|known| known.get_seq_id() == seq_id && known.get_digest() == checkpoint.get_digest())
+            .cloned()
+            .collect();
+        let signers: HashSet<NodeID> = proof.iter().map(|known| known.get_sender_id()).collect();
+        if !self.quorum.is_sufficient(&self.all_nodes, &signers) {
+            let (low, high) = self.get_water_marks();
+            return Err(PbftError::OutsideWaterMarks{seq_id, low, high});
+        }
+        self.stabilize(me, seq_id, proof, data_sender);
+        Ok(())
+    }
+
+    fn stabilize(&mut self, me: ID, seq_id: ID, proof: Vec<Checkpoint>, data_sender: Sender<Message<S>>) {
+        self.collect_garbage(me, seq_id, proof);
+        // the others moved on without us, the messages we'd need are garbage now
+        if self.last_executed < seq_id {
+            self.fetch_state(me, seq_id, data_sender);
+        }
+    }
+
+    // Late checkpoints still go into C: the proof outlives the rekeying of up to f of its signers
+    fn extend_stable_proof(&mut self, checkpoint: Checkpoint) {
+        let matches_proof = self.stable_proof.first()
+            .map(|proven| proven.get_digest() == checkpoint.get_digest())
+            .unwrap_or(false);
+        let is_known = self.stable_proof.iter().any(|proven| proven.get_sender_id() == checkpoint.get_sender_id());
+        if matches_proof && !is_known {
+            self.stable_proof.push(checkpoint);
+        }
+    }
+
+    fn fetch_state(&mut self, me: ID, seq_id: ID, data_sender: Sender<Message<S>>) {
+        // a fetch during replay would go nowhere, `open_log` sends one once it is done
+        if self.replaying || seq_id <= self.fetching {
+            return;
+        }
+        println!("[{:?}] Behind stable checkpoint {:?}, fetching state", me, seq_id);
+        self.fetching = seq_id;
+        let fetch = Arc::new(RwLock::new(FetchState::new(self.last_executed, me)));
+        for id in self.remaining_nodes.iter() {
+            let res = data_sender.send(Message::fetch_state(me, *id, fetch.clone()));
             if res.is_err() {
-                println!("[{:?}] Prepare insertion err {:?}", me, res.err());
-                return;
+                println!("[{:?}] Fetch state send error: {:?}", me, res.err())
             }
-            //println!("[{:?}] Prepare is sufficient! Sending to {:?}", me, self.all_nodes);
-            self.send(me, data_sender, Message::commit, commit);
-        })
+        }
     }
 
-    fn update_tip(&mut self, me: ID, commit: &Commit) {
-        // check that all preprepares and prepares exist
-        if !self.preprepares.is_sufficient(commit, &self.all_nodes)
-            && !self.prepares.is_sufficient(commit, &self.all_nodes) {
-                println!("[{:?}] Commit ignore: previous requests are not sufficient", me);
-                return
+    // Sends what the peer is missing: our stable snapshot and the certificates of
+    // everything we executed after it
+    fn handle_fetch_state(&self, me: ID, message: Arc<RwLock<FetchState>>, data_sender: Sender<Message<S>>) -> Result<(), PbftError> {
+        let fetch: FetchState = convert_err(message.read())?.clone();
+        if !self.remaining_nodes.contains(&fetch.get_sender_id()) {
+            return Err(PbftError::UnknownNode{node_id: fetch.get_sender_id()});
+        }
+        // the peer isn't behind us, there is nothing to send
+        if fetch.get_last_executed() >= self.last_executed {
+            return Ok(());
+        }
+        let snapshot = match self.snapshots.get(&self.stable_seq) {
+            Some(snapshot) => snapshot.clone(),
+            None => return Ok(()),
+        };
+        let committed = ((self.stable_seq + 1)..=self.last_executed)
+            .map_while(|seq_id| self.find_committed_cert(seq_id))
+            .collect();
+        let transfer = StateTransfer::new(self.stable_seq, self.stable_proof.clone(), snapshot, committed, me);
+        data_sender.send(Message::state_transfer(me, fetch.get_sender_id(), Arc::new(RwLock::new(transfer))))
+            .map_err(|_| PbftError::SendFailed{target_id: fetch.get_sender_id()})
+    }
+
+    // committed(m, v, n): the pre-prepare and 2f+1 matching commits. Commits aren't
+    // signed with authenticators, the peer waits for the next checkpoint instead
+    fn find_committed_cert(&self, seq_id: ID) -> Option<CommittedCert<S::Operation>> {
+        if self.authentication == Authentication::Authenticators {
+            return None;
+        }
+        self.preprepares.iter()
+            .filter_map(|pp| pp.read().ok().map(|pp| pp.clone()))
+            .filter(|pp| pp.get_seq_id() == seq_id)
+            .find_map(|pp| {
+                let commits: Vec<Commit> = self.commits.find_all(&pp).iter()
+                    .filter_map(|c| c.read().ok().map(|c| c.clone()))
+                    .collect();
+                let senders: HashSet<ID> = commits.iter().map(|c| c.get_sender_id()).collect();
+                if !self.quorum.is_sufficient(&self.all_nodes, &senders) {
+                    return None;
+                }
+                Some(CommittedCert::new(pp, commits))
+            })
+    }
+
+    fn handle_state_transfer(&mut self, me: ID, message: Arc<RwLock<StateTransfer<S::Operation, StateSnapshot<S>>>>, data_sender: Sender<Message<S>>) -> Result<(), PbftError> {
+        let transfer = convert_err(message.read())?.clone();
+        if !self.remaining_nodes.contains(&transfer.get_sender_id()) {
+            return Err(PbftError::UnknownNode{node_id: transfer.get_sender_id()});
+        }
+        if transfer.get_seq_id() > self.last_executed {
+            self.install_snapshot(me, transfer.get_seq_id(), transfer.get_checkpoints(), transfer.get_snapshot())?;
+        }
+        // what is proven up to the first bad certificate still executes
+        let mut result = Ok(());
+        for cert in transfer.get_committed() {
+            let preprepare = cert.get_preprepare();
+            let seq_id = preprepare.get_seq_id();
+            if seq_id <= self.last_executed || self.committed.contains_key(&seq_id) {
+                continue;
             }
-        let found_p = self.preprepares.find(commit);
-        let new_state: Option<String> = found_p
-            .map(|preprepare_lock|
-                 preprepare_lock
-                 .read()
-                 .map(|preprepare| preprepare.get_message())
-                 .ok())
-            .unwrap();
-        // save the new state
-        self.tip = new_state.unwrap_or(self.tip.clone());
-    }
-
-    fn handle_commit(&mut self, me: ID, message: Arc<RwLock<Commit>>, _data_sender: Sender<Message>) -> Result<(), String> {
-        let result = State::append(&mut self.commits, &self.sent_commit, &message);
-        if result.is_err() {
-            return result;
-        }
-        convert_err(message.read()).map(|_message_lock| {
-            let message_lock: RwLockReadGuard<Commit> = _message_lock;
-            if !self.validate_message(me, &self.prepares, &*message_lock) {
-                return;
+            if !is_valid_committed_cert(cert, &self.all_nodes, &*self.verifier, &*self.quorum) {
+                result = Err(PbftError::InvalidCommittedCert{seq_id});
+                break;
             }
-            // if we've sent anything we shouldn't send anything twice
-            if self.sent_commit.is_some() {
-                println!("[{:?}] Prepare drop: next seq is invalid", me);
-                return;
+            self.committed.insert(seq_id, preprepare.clone());
+        }
+        self.execute_committed(me, data_sender);
+        result
+    }
+
+    // The snapshot replaces our state only if its digest is the one 2f+1 replicas checkpointed
+    fn install_snapshot(&mut self, me: ID, seq_id: ID, proof: &[Checkpoint], snapshot: &StateSnapshot<S>) -> Result<(), PbftError> {
+        if !is_valid_checkpoint_proof(seq_id, proof, &self.all_nodes, &*self.verifier, &*self.quorum) {
+            return Err(PbftError::InvalidCheckpointProof{seq_id});
+        }
+        let expected = match proof.first() {
+            Some(checkpoint) => checkpoint.get_digest(),
+            None => return Err(PbftError::InvalidCheckpointProof{seq_id}),
+        };
+        let current = self.machine.snapshot();
+        let current_replies = std::mem::replace(&mut self.last_replies, snapshot.get_replies().clone());
+        self.machine.restore(snapshot.get_machine().clone());
+        if self.state_digest() != expected {
+            self.machine.restore(current);
+            self.last_replies = current_replies;
+            return Err(PbftError::SnapshotMismatch{seq_id});
+        }
+        println!("[{:?}] Installed snapshot of seq {:?}", me, seq_id);
+        self.stop_waiting();
+        // whatever was tentative is part of the snapshot or will come as committed
+        self.tentative = None;
+        self.last_executed = seq_id;
+        self.committed.retain(|seq, _| *seq > seq_id);
+        self.snapshots.insert(seq_id, snapshot.clone());
+        if self.assigned_seq < seq_id {
+            self.assigned_seq = seq_id;
+        }
+        if seq_id > self.stable_seq {
+            self.collect_garbage(me, seq_id, proof.to_vec());
+        }
+        Ok(())
+    }
+
+    // Proactive recovery: a fresh state reloads the checkpoint that was kept
+    // aside, if its proof still holds, and fetches everything the others executed
+    // since. The replica counts as recovering until a checkpoint above `target`
+    // is stable: from then on no proof needs a signature made with its old key
+    pub fn recover(&mut self, me: ID, checkpoint: Option<StableCheckpoint<S>>, target: ID, data_sender: Sender<Message<S>>) {
+        println!("[{:?}] Recovering past seq {:?}", me, target);
+        self.recovering_until = Some(target);
+        if let Some((seq_id, proof, snapshot)) = checkpoint.filter(|(seq_id, _, _)| *seq_id > 0) {
+            if let Err(rejection) = self.install_snapshot(me, seq_id, &proof, &snapshot) {
+                println!("[{:?}] Checkpoint reload drop: {}", me, rejection);
             }
-            println!("[{:?}] Client response: {:?}", me, message_lock);
-            self.update_tip(me, &*message_lock)
-        })
+        }
+        if self.last_executed < target {
+            self.fetch_state(me, target, data_sender);
+        }
     }
 
-    pub fn handle_protocol_message(&mut self, me: ID, message: Message, data_sender: Sender<Message>) -> Result<(), String> {
-        //print!("new message! {:?}", &message);
-        // TODO: Not sure how to make a for loop here; don't want to create new structs
-        if message.preprepare.is_some() {
-            return self.handle_preprepare(me, message.preprepare.unwrap(), data_sender)
+    fn finish_recovery(&mut self, me: ID) {
+        if let Some(target) = self.recovering_until.filter(|target| *target < self.stable_seq) {
+            println!("[{:?}] Recovered past seq {:?}", me, target);
+            self.recovering_until = None;
         }
-        if message.prepare.is_some() {
-            return self.handle_prepare(me, message.prepare.unwrap(), data_sender)
+    }
+
+    // The checkpoint is stable: nothing at or below it will be needed again
+    fn collect_garbage(&mut self, me: ID, seq_id: ID, proof: Vec<Checkpoint>) {
+        println!("[{:?}] Stable checkpoint {:?}", me, seq_id);
+        self.stable_seq = seq_id;
+        self.stable_proof = proof;
+        self.preprepares.discard_through(seq_id);
+        self.prepares.discard_through(seq_id);
+        self.commits.discard_through(seq_id);
+        self.slots.discard_through(seq_id);
+        self.checkpoints.discard_through(seq_id - 1);
+        self.checkpoints_ahead.retain(|_, checkpoint| checkpoint.get_seq_id() > seq_id);
+        // the stable snapshot stays, it is what the checkpoint proof vouches for
+        self.snapshots.retain(|seq, _| *seq >= seq_id);
+        self.compact_log(me, seq_id);
+        self.finish_recovery(me);
+    }
+
+    // The stable checkpoint stands for everything the log holds up to it
+    fn compact_log(&mut self, me: ID, seq_id: ID) {
+        let checkpoint = match (&self.wal, self.get_stable_checkpoint()) {
+            (Some(_), Some(checkpoint)) => checkpoint,
+            _ => return,
+        };
+        if let Some(mut wal) = self.wal.take() {
+            if let Err(e) = wal.compact(&checkpoint, |entry| self.is_logged_after(seq_id, entry)) {
+                println!("[{:?}] Log compaction err: {}", me, e);
+            }
+            self.wal = Some(wal);
         }
-        if message.commit.is_some() {
-            return self.handle_commit(me, message.commit.unwrap(), data_sender)
+    }
+
+    // What a log compacted to the checkpoint at seq_id still needs: the slots above it,
+    // view changes that haven't been superseded and membership changes yet to execute
+    fn is_logged_after(&self, seq_id: ID, entry: &Entry<S>) -> bool {
+        match entry.get_payload() {
+            Payload::PrePrepare(m) => m.read().map(|m| m.get_seq_id() > seq_id).unwrap_or(true),
+            Payload::Prepare(m) => m.read().map(|m| m.get_seq_id() > seq_id).unwrap_or(true),
+            Payload::Commit(m) => m.read().map(|m| m.get_seq_id() > seq_id).unwrap_or(true),
+            Payload::Checkpoint(m) => m.read().map(|m| m.get_seq_id() > seq_id).unwrap_or(true),
+            Payload::ViewChange(m) => m.read().map(|m| m.get_view_id() >= self.view_id).unwrap_or(true),
+            Payload::NewView(m) => m.read().map(|m| m.get_view_id() >= self.view_id).unwrap_or(true),
+            Payload::Reconfiguration(m) => m.read().map(|m| self.pending_reconfigurations.contains(&m)).unwrap_or(true),
+            _ => false,
+        }
+    }
+
+    fn reply(&self, me: ID, request: &Request<S::Operation>, result: S::Result, data_sender: Sender<Message<S>>) {
+        self.send_reply(me, request.make_reply(self.view_id, me, result), data_sender)
+    }
+
+    // The reply to an executed request is kept for retries of it
+    fn reply_executed(&mut self, me: ID, request: &Request<S::Operation>, result: S::Result, data_sender: Sender<Message<S>>) {
+        let reply = request.make_reply(self.view_id, me, result);
+        self.last_replies.insert(request.get_client_id(), reply.clone());
+        self.send_reply(me, reply, data_sender)
+    }
+
+    // A retry gets the cached reply, an older request gets nothing. Returns whether
+    // the request executed already
+    fn resend_reply(&self, me: ID, request: &Request<S::Operation>, data_sender: Sender<Message<S>>) -> Result<bool, PbftError> {
+        let reply = match self.last_replies.get(&request.get_client_id()) {
+            Some(reply) if request.get_timestamp() <= reply.get_timestamp() => reply,
+            _ => return Ok(false),
+        };
+        if reply.get_timestamp() != request.get_timestamp() {
+            return Err(PbftError::StaleTimestamp{client_id: request.get_client_id(), timestamp: request.get_timestamp()});
+        }
+        // the cache may come from another replica through state transfer
+        self.send_reply(me, request.make_reply(self.view_id, me, reply.get_result()), data_sender);
+        Ok(true)
+    }
+
+    fn reply_tentatively(&self, me: ID, request: &Request<S::Operation>, result: S::Result, data_sender: Sender<Message<S>>) {
+        self.send_reply(me, request.make_reply(self.view_id, me, result).into_tentative(), data_sender)
+    }
+
+    fn send_reply(&self, me: ID, reply: Reply<S::Result>, data_sender: Sender<Message<S>>) {
+        let reply = self.sign(reply);
+        println!("[{:?}] Client response: {:?}", me, reply);
+        let res = data_sender.send(Message::reply(me, reply.get_client_id(), Arc::new(RwLock::new(reply))));
+        if res.is_err() {
+            println!("[{:?}] Reply send error: {:?}", me, res.err())
+        }
+    }
+
+    // The primary orders client requests by wrapping them into pre-prepares,
+    // backups relay what clients sent them to the primary
+    fn handle_request(&mut self, me: ID, sender_id: ID, message: Arc<RwLock<Request<S::Operation>>>, data_sender: Sender<Message<S>>) -> Result<(), PbftError> {
+        let request: Request<S::Operation> = convert_err(message.read())?.clone();
+        if request.is_read_only() {
+            return self.handle_read_only(me, &request, data_sender);
+        }
+        if self.resend_reply(me, &request, data_sender.clone())? {
+            return Ok(());
+        }
+        self.wait_for(&request);
+        let primary_id = primary(self.view_id, &self.all_nodes);
+        if primary_id != Some(me) {
+            // relay only once, straight from the client
+            if let (Some(primary_id), false) = (primary_id, self.all_nodes.contains(&sender_id)) {
+                let res = data_sender.send(Message::request(me, primary_id, message.clone()));
+                if res.is_err() {
+                    println!("[{:?}] Request relay error: {:?}", me, res.err())
+                }
+            }
+            return Ok(());
+        }
+        if !self.view_active {
+            return Err(PbftError::ViewNotActive{view_id: self.view_id});
+        }
+        // the same request may come from the client and from a backup
+        if self.pending.contains(&request) {
+            return Err(PbftError::DuplicateRequest{client_id: request.get_client_id(), timestamp: request.get_timestamp()});
+        }
+        if self.pending.is_empty() {
+            self.batch_started = Some(Instant::now());
+        }
+        self.pending.push(request);
+        if self.pending.len() >= self.max_batch_size {
+            self.flush_batch(me, data_sender);
+        }
+        Ok(())
+    }
+
+    // Read-only requests go to every replica, each answers from its committed state
+    // and the client decides whether 2f+1 of them agree
+    fn handle_read_only(&mut self, me: ID, request: &Request<S::Operation>, data_sender: Sender<Message<S>>) -> Result<(), PbftError> {
+        if self.tentative.is_some() {
+            self.waiting_reads.push(request.clone());
+            return Ok(());
+        }
+        match self.machine.read(request.get_op()) {
+            Some(result) => self.reply(me, request, result, data_sender),
+            // a faulty client could try to sneak a write past ordering
+            None => return Err(PbftError::NotReadOnly{client_id: request.get_client_id(), timestamp: request.get_timestamp()}),
+        }
+        Ok(())
+    }
+
+    // Membership changes come from the administrator to every replica: the primary
+    // orders them, the backups keep them to know which ones to prepare
+    fn handle_reconfiguration(&mut self, me: ID, message: Arc<RwLock<Reconfiguration>>, data_sender: Sender<Message<S>>) -> Result<(), PbftError> {
+        let reconfiguration: Reconfiguration = convert_err(message.read())?.clone();
+        if self.pending_reconfigurations.contains(&reconfiguration) {
+            return Ok(());
+        }
+        self.pending_reconfigurations.push(reconfiguration);
+        self.flush_batch(me, data_sender);
+        Ok(())
+    }
+
+    // Liveness: once a request is received the timer runs until it executes
+    fn wait_for(&mut self, request: &Request<S::Operation>) {
+        if self.view_change_timeout.is_none() {
+            return;
+        }
+        let waiting = self.waiting.entry(request.get_client_id()).or_insert(request.get_timestamp());
+        if *waiting < request.get_timestamp() {
+            *waiting = request.get_timestamp();
+        }
+        if self.timer_started.is_none() {
+            self.timer_started = Some(Instant::now());
+        }
+    }
+
+    // Every request that executes restarts the timer for the ones still waiting
+    // and undoes the backoff, with none left the timer stops
+    fn stop_waiting(&mut self) {
+        let before = self.waiting.len();
+        let replies = &self.last_replies;
+        self.waiting.retain(|client_id, t| replies.get(client_id).is_none_or(|reply| reply.get_timestamp() < *t));
+        if self.waiting.len() == before {
+            return;
+        }
+        self.timeout = self.view_change_timeout.unwrap_or_default();
+        self.timer_started = if self.waiting.is_empty() && self.view_active { None } else { Some(Instant::now()) };
+    }
+
+    // Called periodically: a backup whose requests didn't execute in time, or whose
+    // view change didn't end in a new view, moves to the next view and waits twice
+    // as long before giving up on that one too. Returns whether it did
+    pub fn check_timers(&mut self, me: ID, now: Instant, data_sender: Sender<Message<S>>) -> bool {
+        let started = match (self.view_change_timeout, self.timer_started) {
+            (Some(_), Some(started)) => started,
+            _ => return false,
+        };
+        if now.saturating_duration_since(started) < self.timeout
+            || (self.view_active && primary(self.view_id, &self.all_nodes) == Some(me)) {
+            return false;
+        }
+        println!("[{:?}] Timer expired after {:?} in view {:?}", me, self.timeout, self.view_id);
+        self.suspect_primary(me, data_sender);
+        self.timeout = self.timeout.saturating_mul(2);
+        true
+    }
+
+    // Called periodically: orders the pending requests once the oldest one waited long enough
+    pub fn flush_due_batch(&mut self, me: ID, data_sender: Sender<Message<S>>) -> bool {
+        if !self.pending_reconfigurations.is_empty() {
+            return self.flush_batch(me, data_sender);
+        }
+        match self.batch_started {
+            Some(started) if started.elapsed() >= self.max_batch_delay => self.flush_batch(me, data_sender),
+            _ => false,
+        }
+    }
+
+    // Orders up to `max_batch_size` pending requests with a single pre-prepare,
+    // membership changes go first and on their own. Returns whether one was sent
+    pub fn flush_batch(&mut self, me: ID, data_sender: Sender<Message<S>>) -> bool {
+        // what was ordered before a restart is in the log, nothing new is
+        if (self.pending.is_empty() && self.pending_reconfigurations.is_empty()) || self.replaying
+            || !self.view_active || primary(self.view_id, &self.all_nodes) != Some(me) {
+            return false;
+        }
+        // a faulty primary could exhaust the sequence space, a correct one waits for the next checkpoint
+        let (_, high) = self.get_water_marks();
+        if self.assigned_seq >= high {
+            println!("[{:?}] Batch wait: log is full up to {:?}", me, high);
+            return false;
+        }
+        if self.is_reconfiguring() {
+            println!("[{:?}] Batch wait: membership changes at a seq up to {:?}", me, self.assigned_seq);
+            return false;
+        }
+        self.assigned_seq += 1;
+        let preprepare = if self.pending_reconfigurations.is_empty() {
+            let size = self.pending.len().min(self.max_batch_size.max(1));
+            let batch: Batch<S::Operation> = self.pending.drain(..size).collect();
+            self.batch_started = if self.pending.is_empty() { None } else { Some(Instant::now()) };
+            PrePrepare::new(self.view_id, self.assigned_seq, batch, me)
+        } else {
+            // it stays pending until it executes, another primary orders it again otherwise
+            PrePrepare::reconfigure(self.view_id, self.assigned_seq, self.pending_reconfigurations[0].clone(), me)
+        };
+        let preprepare = Arc::new(RwLock::new(self.sign(preprepare)));
+        if let Err(e) = self.handle_preprepare(me, preprepare.clone(), data_sender.clone()) {
+            println!("[{:?}] Preprepare insertion err {:?}", me, e);
+            return false;
+        }
+        self.send(me, data_sender, Message::preprepare, preprepare);
+        true
+    }
+
+    fn handle_commit(&mut self, me: ID, message: Arc<RwLock<Commit>>, data_sender: Sender<Message<S>>) -> Result<(), PbftError> {
+        self.check_unforwarded(&*convert_err(message.read())?)?;
+        let water_marks = self.get_water_marks();
+        Self::append(&mut self.commits, &message, water_marks)?;
+        let message_lock: RwLockReadGuard<Commit> = convert_err(message.read())?;
+        self.validate_message(&self.preprepares, &*message_lock)?;
+        self.update_tip(me, &message_lock, data_sender);
+        Ok(())
+    }
+
+    // prepared(m, v, n, i): the pre-prepare from the primary and matching prepares
+    // from backups form a 2f+1 quorum
+    fn find_prepared_cert(&self, preprepare: &PrePrepare<S::Operation>) -> Option<PreparedCert<S::Operation>> {
+        if primary(preprepare.get_view_id(), &self.all_nodes) != Some(preprepare.get_sender_id()) {
+            return None;
+        }
+        let prepares: Vec<Prepare> = self.prepares.find_all(preprepare).iter()
+            .filter_map(|p| p.read().ok().map(|p| p.clone()))
+            .filter(|p| p.get_sender_id() != preprepare.get_sender_id())
+            .collect();
+        let mut approvers: HashSet<ID> = prepares.iter().map(|p| p.get_sender_id()).collect();
+        approvers.insert(preprepare.get_sender_id());
+        if !self.quorum.is_sufficient(&self.all_nodes, &approvers) {
+            return None;
+        }
+        Some(PreparedCert::new(preprepare.clone(), prepares))
+    }
+
+    fn is_prepared<N>(&self, message: &N) -> bool
+    where N: NodeRequest {
+        self.preprepares.find_all(message).iter()
+            .filter_map(|pp| pp.read().ok().map(|pp| pp.clone()))
+            .any(|pp| self.find_prepared_cert(&pp).is_some())
+    }
+
+    // P -- for every seq above the stable checkpoint the cert prepared in the highest view
+    fn prepared_certs(&self) -> Vec<PreparedCert<S::Operation>> {
+        let mut certs: BTreeMap<ID, PreparedCert<S::Operation>> = BTreeMap::new();
+        for preprepare_lock in self.preprepares.iter() {
+            let cert = match preprepare_lock.read() {
+                Ok(preprepare) => {
+                    if preprepare.get_seq_id() <= self.stable_seq {
+                        continue;
+                    }
+                    self.find_prepared_cert(&*preprepare)
+                },
+                Err(_) => continue,
+            };
+            if let Some(cert) = cert {
+                let seq_id = cert.get_preprepare().get_seq_id();
+                let is_higher = certs.get(&seq_id)
+                    .map(|found| found.get_preprepare().get_view_id() < cert.get_preprepare().get_view_id())
+                    .unwrap_or(true);
+                if is_higher {
+                    certs.insert(seq_id, cert);
+                }
+            }
+        }
+        certs.into_values().collect()
+    }
+
+    pub fn suspect_primary(&mut self, me: ID, data_sender: Sender<Message<S>>) {
+        let next_view = self.view_id + 1;
+        self.start_view_change(me, next_view, data_sender)
+    }
+
+    fn start_view_change(&mut self, me: ID, new_view: ID, data_sender: Sender<Message<S>>) {
+        if new_view <= self.view_id {
+            return;
+        }
+        println!("[{:?}] Moving to view {:?}", me, new_view);
+        self.view_id = new_view;
+        self.view_active = false;
+        self.timer_started = Some(Instant::now());
+        self.roll_back_tentative(me, data_sender.clone());
+        let view_change = Arc::new(RwLock::new(self.sign(ViewChange::new(
+            new_view,
+            self.stable_seq,
+            self.stable_proof.clone(),
+            self.prepared_certs(),
+            me))));
+        self.view_changes.entry(new_view)
+            .or_default()
+            .insert(me, view_change.clone());
+        self.send(me, data_sender.clone(), Message::view_change, view_change);
+        self.try_new_view(me, new_view, data_sender)
+    }
+
+    fn is_pending_view(&self, view_id: ID) -> bool {
+        view_id > self.view_id || (view_id == self.view_id && !self.view_active)
+    }
+
+    // Liveness: f+1 replicas asking for higher views means at least one correct replica
+    // suspects the primary; join the smallest of those views
+    fn join_view_change(&mut self, me: ID, data_sender: Sender<Message<S>>) {
+        let mut smallest_views: HashMap<NodeID, ID> = HashMap::new();
+        for (view_id, senders) in self.view_changes.iter() {
+            if *view_id <= self.view_id {
+                continue;
+            }
+            for sender_id in senders.keys() {
+                let smallest = smallest_views.entry(*sender_id).or_insert(*view_id);
+                if *view_id < *smallest {
+                    *smallest = *view_id;
+                }
+            }
+        }
+        let senders: HashSet<ID> = smallest_views.keys().copied().collect();
+        if !self.weak_certificate.is_sufficient(&self.all_nodes, &senders) {
+            return;
+        }
+        if let Some(view_id) = smallest_views.values().min().copied() {
+            self.start_view_change(me, view_id, data_sender)
+        }
+    }
+
+    // The primary of the new view announces it once 2f+1 view-changes are in
+    fn try_new_view(&mut self, me: ID, view_id: ID, data_sender: Sender<Message<S>>) {
+        if primary(view_id, &self.all_nodes) != Some(me) || self.sent_new_view >= Some(view_id) {
+            return;
+        }
+        let view_changes: Vec<ViewChange<S::Operation>> = match self.view_changes.get(&view_id) {
+            Some(found) => found.values()
+                .filter_map(|vc| vc.read().ok().map(|vc| vc.clone()))
+                .collect(),
+            None => return,
+        };
+        let senders: HashSet<ID> = view_changes.iter().map(|vc| vc.get_sender_id()).collect();
+        if !self.quorum.is_sufficient(&self.all_nodes, &senders) {
+            return;
+        }
+        let preprepares = new_view_preprepares(view_id, &view_changes, me).into_iter()
+            .map(|preprepare| self.sign(preprepare))
+            .collect();
+        let new_view = self.sign_unforwarded(NewView::new(view_id, view_changes, preprepares, me));
+        println!("[{:?}] Announcing view {:?}", me, view_id);
+        self.sent_new_view = Some(view_id);
+        self.install_new_view(me, &new_view, data_sender.clone());
+        self.send(me, data_sender, Message::new_view, Arc::new(RwLock::new(new_view)));
+    }
+
+    fn install_new_view(&mut self, me: ID, new_view: &NewView<S::Operation>, data_sender: Sender<Message<S>>) {
+        self.view_id = new_view.get_view_id();
+        self.view_active = true;
+        // the new primary gets a whole timeout to execute what is waiting
+        self.timer_started = if self.waiting.is_empty() { None } else { Some(Instant::now()) };
+        let view_id = self.view_id;
+        self.view_changes.retain(|v, _| *v > view_id);
+        // the next request goes after everything re-issued in O
+        let max_s = new_view.get_preprepares().iter()
+            .map(|pp| pp.get_seq_id())
+            .chain(new_view.get_view_changes().iter().map(|vc| vc.get_seq_id()))
+            .max()
+            .unwrap_or(0);
+        if max_s > self.assigned_seq {
+            self.assigned_seq = max_s;
+        }
+        // O starts after min-s, a checkpoint in V we may not have seen become stable
+        if let Some(latest) = new_view.get_view_changes().iter().max_by_key(|vc| vc.get_seq_id()) {
+            if latest.get_seq_id() > self.stable_seq {
+                self.stabilize(me, latest.get_seq_id(), latest.get_checkpoints().to_vec(), data_sender.clone());
+            }
+        }
+        // a membership change in O prepared in an earlier view, 2f+1 replicas vouched for it
+        for preprepare in new_view.get_preprepares() {
+            match preprepare.get_reconfiguration() {
+                Some(reconfiguration) if preprepare.get_seq_id() > self.last_executed
+                    && !self.pending_reconfigurations.contains(reconfiguration) =>
+                    self.pending_reconfigurations.push(reconfiguration.clone()),
+                _ => {},
+            }
+        }
+        for preprepare in new_view.get_preprepares() {
+            let res = self.handle_preprepare(me, Arc::new(RwLock::new(preprepare.clone())), data_sender.clone());
+            if let Err(e) = res {
+                println!("[{:?}] New view preprepare err: {}", me, e);
+            }
         }
-        Err("Unknown message".to_owned())
     }
+
+    fn handle_view_change(&mut self, me: ID, message: Arc<RwLock<ViewChange<S::Operation>>>, data_sender: Sender<Message<S>>) -> Result<(), PbftError> {
+        let view_change: ViewChange<S::Operation> = convert_err(message.read())?.clone();
+        if !self.is_pending_view(view_change.get_view_id()) {
+            return Err(PbftError::StaleView{view_id: view_change.get_view_id(), current_view_id: self.view_id});
+        }
+        if !is_valid_view_change(&view_change, &self.all_nodes, &*self.verifier, &*self.quorum) {
+            return Err(PbftError::InvalidViewChange{view_id: view_change.get_view_id(), sender_id: view_change.get_sender_id()});
+        }
+        self.view_changes.entry(view_change.get_view_id())
+            .or_default()
+            .insert(view_change.get_sender_id(), message.clone());
+        self.join_view_change(me, data_sender.clone());
+        self.try_new_view(me, view_change.get_view_id(), data_sender);
+        Ok(())
+    }
+
+    fn handle_new_view(&mut self, me: ID, message: Arc<RwLock<NewView<S::Operation>>>, data_sender: Sender<Message<S>>) -> Result<(), PbftError> {
+        let new_view: NewView<S::Operation> = convert_err(message.read())?.clone();
+        let view_id = new_view.get_view_id();
+        if !self.is_pending_view(view_id) {
+            return Err(PbftError::StaleView{view_id, current_view_id: self.view_id});
+        }
+        let primary_id = primary(view_id, &self.all_nodes);
+        if primary_id != Some(new_view.get_sender_id()) {
+            return Err(PbftError::NotPrimary{view_id, sender_id: new_view.get_sender_id(), primary_id});
+        }
+        self.check_unforwarded(&new_view)?;
+        let view_changes = new_view.get_view_changes();
+        let invalid = view_changes.iter().find(|vc| {
+            vc.get_view_id() != view_id || !is_valid_view_change(*vc, &self.all_nodes, &*self.verifier, &*self.quorum)
+        });
+        if let Some(vc) = invalid {
+            return Err(PbftError::InvalidViewChange{view_id: vc.get_view_id(), sender_id: vc.get_sender_id()});
+        }
+        let senders: HashSet<ID> = view_changes.iter().map(|vc| vc.get_sender_id()).collect();
+        if !self.quorum.is_sufficient(&self.all_nodes, &senders) {
+            return Err(PbftError::InsufficientApprovers);
+        }
+        let expected = new_view_preprepares(view_id, view_changes, new_view.get_sender_id());
+        if !is_same_preprepares(&expected, new_view.get_preprepares()) {
+            return Err(PbftError::NewViewMismatch{view_id});
+        }
+        self.install_new_view(me, &new_view, data_sender);
+        Ok(())
+    }
+
+    // Messages between nodes have to carry a valid MAC for us and may only carry
+    // what their sender signed itself
+    fn check_authenticator(&self, message: &Message<S>) -> Result<(), PbftError> {
+        if self.authentication == Authentication::Signatures {
+            return Ok(());
+        }
+        let (signer_id, bytes) = match message.get_signed_bytes() {
+            Some(signed) => signed,
+            None => return Ok(()),
+        };
+        let is_valid = signer_id == message.get_sender_id() && message.authenticator.as_ref()
+            .map(|authenticator| self.session_keys.check(message.get_sender_id(), &bytes, authenticator))
+            .unwrap_or(false);
+        if !is_valid {
+            return Err(PbftError::InvalidAuthenticator{sender_id: message.get_sender_id()});
+        }
+        Ok(())
+    }
+
+    // Control packet: the key ring changed, e.g. a replica joined
+    pub fn set_credentials(&mut self, credentials: &Credentials) {
+        self.signer = credentials.get_signer();
+        self.verifier = credentials.get_verifier();
+        self.session_keys = credentials.get_session_keys();
+    }
+
+    pub fn handle_protocol_message(&mut self, me: ID, message: Message<S>, data_sender: Sender<Message<S>>) -> Result<(), PbftError> {
+        if let Payload::Credentials(credentials) = &message.payload {
+            self.set_credentials(&*convert_err(credentials.read())?);
+            return Ok(())
+        }
+        if !self.is_member(me) {
+            return Err(PbftError::NotAReplica{node_id: me});
+        }
+        self.check_authenticator(&message)?;
+        if let Some(wal) = &self.wal {
+            wal.append(Direction::Received, message.sender_id, &message.payload)?;
+        }
+        self.dispatch(me, message.sender_id, message.payload, data_sender)
+    }
+
+    fn dispatch(&mut self, me: ID, sender_id: NodeID, payload: Payload<S>, data_sender: Sender<Message<S>>) -> Result<(), PbftError> {
+        match payload {
+            Payload::Request(request) => self.handle_request(me, sender_id, request, data_sender),
+            Payload::PrePrepare(preprepare) => self.handle_preprepare(me, preprepare, data_sender),
+            Payload::Prepare(prepare) => self.handle_prepare(me, prepare, data_sender),
+            Payload::Commit(commit) => self.handle_commit(me, commit, data_sender),
+            Payload::Checkpoint(checkpoint) => self.handle_checkpoint(me, checkpoint, data_sender),
+            Payload::ViewChange(view_change) => self.handle_view_change(me, view_change, data_sender),
+            Payload::NewView(new_view) => self.handle_new_view(me, new_view, data_sender),
+            Payload::FetchState(fetch) => self.handle_fetch_state(me, fetch, data_sender),
+            Payload::StateTransfer(transfer) => self.handle_state_transfer(me, transfer, data_sender),
+            Payload::Reconfiguration(reconfiguration) => self.handle_reconfiguration(me, reconfiguration, data_sender),
+            Payload::SuspectPrimary(_) => {
+                self.suspect_primary(me, data_sender);
+                Ok(())
+            },
+            // handled before the membership check, see `handle_protocol_message`
+            Payload::Credentials(_) => Ok(()),
+            // replies go to clients and the node loop stops on a shutdown
+            Payload::Reply(_) | Payload::Shutdown(_) => Err(PbftError::UnknownMessage),
+        }
+    }
+
+    // Replays the log of `me` in `dir`, from then on everything received and sent is logged first.
+    // A replica the log leaves behind a stable checkpoint asks its peers for state right away
+    pub fn open_log(&mut self, me: ID, dir: &Path, data_sender: Sender<Message<S>>) -> Result<(), PbftError> {
+        let (wal, checkpoint, entries) = WriteAheadLog::open(dir, me)?;
+        self.replay(me, checkpoint, entries);
+        self.wal = Some(wal);
+        if self.last_executed < self.stable_seq {
+            self.fetch_state(me, self.stable_seq, data_sender);
+        }
+        Ok(())
+    }
+
+    // Rebuilds what the log of a restarted replica led to, starting from the checkpoint
+    // it was compacted to. Whatever this sends went out before the restart already, so
+    // it goes nowhere now
+    fn replay(&mut self, me: ID, checkpoint: Option<StableCheckpoint<S>>, entries: Vec<Entry<S>>) {
+        let (discard, _discarded) = mpsc::channel();
+        self.replaying = true;
+        if let Some((seq_id, proof, snapshot)) = checkpoint {
+            if let Err(e) = self.install_snapshot(me, seq_id, &proof, &snapshot) {
+                println!("[{:?}] Replay drop: {}", me, e);
+            }
+        }
+        for entry in entries {
+            let direction = entry.get_direction();
+            let sender_id = entry.get_sender_id();
+            let res = match direction {
+                Direction::Received => self.dispatch(me, sender_id, entry.into_payload(), discard.clone()),
+                Direction::Sent => self.replay_sent(me, entry.into_payload(), discard.clone()),
+            };
+            if let Err(e) = res {
+                println!("[{:?}] Replay drop: {}", me, e);
+            }
+        }
+        self.replaying = false;
+    }
+
+    // Our own messages mostly follow from what we received, what they commit us to
+    // is restored either way
+    fn replay_sent(&mut self, me: ID, payload: Payload<S>, data_sender: Sender<Message<S>>) -> Result<(), PbftError> {
+        match &payload {
+            // a seq handed out before the restart is never handed out again
+            Payload::PrePrepare(preprepare) => {
+                let seq_id = convert_err(preprepare.read())?.get_seq_id();
+                self.assigned_seq = self.assigned_seq.max(seq_id);
+            },
+            Payload::Prepare(prepare) => {
+                let prepare = convert_err(prepare.read())?;
+                let slot = self.slots.get_mut(prepare.get_view_id(), prepare.get_seq_id());
+                slot.accept(prepare.get_digest());
+                slot.mark_sent_prepare();
+            },
+            Payload::Commit(commit) => {
+                let commit = convert_err(commit.read())?;
+                self.slots.get_mut(commit.get_view_id(), commit.get_seq_id()).mark_sent_commit();
+            },
+            // an expired timer led to it, nothing else in the log does
+            Payload::ViewChange(view_change) => {
+                let view_id = convert_err(view_change.read())?.get_view_id();
+                self.start_view_change(me, view_id, data_sender);
+                return Ok(());
+            },
+            Payload::Checkpoint(_) | Payload::NewView(_) => {},
+            Payload::Request(_) | Payload::Reply(_) | Payload::FetchState(_) | Payload::StateTransfer(_)
+                | Payload::Shutdown(_) | Payload::SuspectPrimary(_) | Payload::Reconfiguration(_) | Payload::Credentials(_) => {
+                return Err(PbftError::UnknownMessage);
+            },
+        }
+        self.dispatch(me, me, payload, data_sender)
+    }
+}
+
+// What a message carries. Matches on it list every variant, so a new kind of
+// message doesn't go unhandled anywhere
+#[derive(Debug)]
+pub enum Payload<S: StateMachine> {
+    Request(Arc<RwLock<Request<S::Operation>>>),
+    Reply(Arc<RwLock<Reply<S::Result>>>),
+    PrePrepare(Arc<RwLock<PrePrepare<S::Operation>>>),
+    Prepare(Arc<RwLock<Prepare>>),
+    Commit(Arc<RwLock<Commit>>),
+    Checkpoint(Arc<RwLock<Checkpoint>>),
+    ViewChange(Arc<RwLock<ViewChange<S::Operation>>>),
+    NewView(Arc<RwLock<NewView<S::Operation>>>),
+    FetchState(Arc<RwLock<FetchState>>),
+    StateTransfer(Arc<RwLock<StateTransfer<S::Operation, StateSnapshot<S>>>>),
+    Shutdown(Arc<RwLock<Shutdown>>),  // control packet
+    SuspectPrimary(Arc<RwLock<SuspectPrimary>>),  // control packet
+    Reconfiguration(Arc<RwLock<Reconfiguration>>),  // control packet
+    Credentials(Arc<RwLock<Credentials>>),  // control packet
 }
 
 #[derive(Debug)]
-pub struct Message {
+pub struct Message<S: StateMachine> {
     sender_id: NodeID,
     target_id: NodeID,
-    preprepare: Option<Arc<RwLock<PrePrepare>>>,
-    prepare: Option<Arc<RwLock<Prepare>>>,
-    commit: Option<Arc<RwLock<Commit>>>,
-    shutdown: Option<Arc<RwLock<Shutdown>>>,  // control packet
+    payload: Payload<S>,
+    authenticator: Option<Arc<Authenticator>>,  // only in `Authentication::Authenticators` mode
 }
 
-impl Message {
-    pub fn multiply<'a, M>(conversion_fn: fn(NodeID, NodeID, Arc<RwLock<M>>) -> Message, req: Arc<RwLock<M>>, sender: NodeID, nodes: &'a HashSet<ID>) -> impl Iterator<Item = Message> + 'a where M: 'a {
+impl<S: StateMachine> Message<S> {
+    pub fn multiply<'a, M>(conversion_fn: fn(NodeID, NodeID, Arc<RwLock<M>>) -> Message<S>, req: Arc<RwLock<M>>, sender: NodeID, nodes: &'a HashSet<ID>) -> impl Iterator<Item = Message<S>> + 'a where M: 'a {
         nodes.iter().map(move |target_node_id| {
             conversion_fn(sender, *target_node_id, req.clone())
         })
     }
-    pub fn preprepare(sender_id: NodeID, target_id: ID, pp: Arc<RwLock<PrePrepare>>) -> Message {
+    pub fn new(sender_id: NodeID, target_id: ID, payload: Payload<S>) -> Message<S> {
         Message{
-            sender_id: sender_id,
-            target_id: target_id,
-            preprepare: Option::from(pp),
-            prepare: Option::None,
-            commit: Option::None,
-            shutdown: Option::None,
+            sender_id,
+            target_id,
+            payload,
+            authenticator: Option::None,
         }
     }
-    pub fn prepare(sender_id: NodeID, target_id: ID, p: Arc<RwLock<Prepare>>) -> Message {
-        Message{
-            sender_id: sender_id,
-            target_id: target_id,
-            preprepare: Option::None,
-            prepare: Option::from(p),
-            commit: Option::None,
-            shutdown: Option::None,
-        }
+    pub fn request(sender_id: NodeID, target_id: ID, r: Arc<RwLock<Request<S::Operation>>>) -> Message<S> {
+        Message::new(sender_id, target_id, Payload::Request(r))
     }
-    pub fn commit(sender_id: NodeID, target_id: ID, c: Arc<RwLock<Commit>>) -> Message {
-        Message{
-            sender_id: sender_id,
-            target_id: target_id,
-            preprepare: Option::None,
-            prepare: Option::None,
-            commit: Option::from(c),
-            shutdown: Option::None,
+    pub fn reply(sender_id: NodeID, target_id: ID, r: Arc<RwLock<Reply<S::Result>>>) -> Message<S> {
+        Message::new(sender_id, target_id, Payload::Reply(r))
+    }
+    pub fn preprepare(sender_id: NodeID, target_id: ID, pp: Arc<RwLock<PrePrepare<S::Operation>>>) -> Message<S> {
+        Message::new(sender_id, target_id, Payload::PrePrepare(pp))
+    }
+    pub fn prepare(sender_id: NodeID, target_id: ID, p: Arc<RwLock<Prepare>>) -> Message<S> {
+        Message::new(sender_id, target_id, Payload::Prepare(p))
+    }
+    pub fn commit(sender_id: NodeID, target_id: ID, c: Arc<RwLock<Commit>>) -> Message<S> {
+        Message::new(sender_id, target_id, Payload::Commit(c))
+    }
+    pub fn checkpoint(sender_id: NodeID, target_id: ID, c: Arc<RwLock<Checkpoint>>) -> Message<S> {
+        Message::new(sender_id, target_id, Payload::Checkpoint(c))
+    }
+    pub fn view_change(sender_id: NodeID, target_id: ID, vc: Arc<RwLock<ViewChange<S::Operation>>>) -> Message<S> {
+        Message::new(sender_id, target_id, Payload::ViewChange(vc))
+    }
+    pub fn new_view(sender_id: NodeID, target_id: ID, nv: Arc<RwLock<NewView<S::Operation>>>) -> Message<S> {
+        Message::new(sender_id, target_id, Payload::NewView(nv))
+    }
+    pub fn fetch_state(sender_id: NodeID, target_id: ID, f: Arc<RwLock<FetchState>>) -> Message<S> {
+        Message::new(sender_id, target_id, Payload::FetchState(f))
+    }
+    pub fn state_transfer(sender_id: NodeID, target_id: ID, st: Arc<RwLock<StateTransfer<S::Operation, StateSnapshot<S>>>>) -> Message<S> {
+        Message::new(sender_id, target_id, Payload::StateTransfer(st))
+    }
+    pub fn shutdown(sender_id: NodeID, target_id: ID, s: Arc<RwLock<Shutdown>>) -> Message<S> {
+        Message::new(sender_id, target_id, Payload::Shutdown(s))
+    }
+    pub fn suspect(sender_id: NodeID, target_id: ID, s: Arc<RwLock<SuspectPrimary>>) -> Message<S> {
+        Message::new(sender_id, target_id, Payload::SuspectPrimary(s))
+    }
+    pub fn reconfiguration(sender_id: NodeID, target_id: ID, r: Arc<RwLock<Reconfiguration>>) -> Message<S> {
+        Message::new(sender_id, target_id, Payload::Reconfiguration(r))
+    }
+    pub fn credentials(sender_id: NodeID, target_id: ID, c: Arc<RwLock<Credentials>>) -> Message<S> {
+        Message::new(sender_id, target_id, Payload::Credentials(c))
+    }
+
+    pub fn get_target_id(&self) -> NodeID {
+        self.target_id
+    }
+
+    pub fn get_sender_id(&self) -> NodeID {
+        self.sender_id
+    }
+
+    pub fn get_payload(&self) -> &Payload<S> {
+        &self.payload
+    }
+
+    // Replies are addressed to clients, not to nodes
+    pub fn get_reply(&self) -> Option<Arc<RwLock<Reply<S::Result>>>> {
+        match &self.payload {
+            Payload::Reply(reply) => Some(reply.clone()),
+            _ => None,
         }
     }
-    pub fn shutdown(sender_id: NodeID, target_id: ID, s: Arc<RwLock<Shutdown>>) -> Message {
+
+    pub fn is_shutdown(&self) -> bool {
+        matches!(self.payload, Payload::Shutdown(_))
+    }
+
+    pub fn with_authenticator(self, authenticator: Arc<Authenticator>) -> Message<S> {
         Message{
-            sender_id: sender_id,
-            target_id: target_id,
-            preprepare: Option::None,
-            prepare: Option::None,
-            commit: Option::None,
-            shutdown: Option::from(s),
+            authenticator: Some(authenticator),
+            ..self
         }
     }
 
-    pub fn get_target_id(&self) -> NodeID {
-        self.target_id
+    fn signed_bytes<M>(payload: &Arc<RwLock<M>>) -> Option<(NodeID, Vec<u8>)>
+    where M: Signed {
+        payload.read().ok().map(|payload| (payload.get_signer_id(), payload.to_bytes()))
+    }
+
+    // Who signed the node-to-node payload and what exactly is covered by it,
+    // requests from clients and control packets have none
+    pub fn get_signed_bytes(&self) -> Option<(NodeID, Vec<u8>)> {
+        match &self.payload {
+            Payload::PrePrepare(preprepare) => Self::signed_bytes(preprepare),
+            Payload::Prepare(prepare) => Self::signed_bytes(prepare),
+            Payload::Commit(commit) => Self::signed_bytes(commit),
+            Payload::Checkpoint(checkpoint) => Self::signed_bytes(checkpoint),
+            Payload::ViewChange(view_change) => Self::signed_bytes(view_change),
+            Payload::NewView(new_view) => Self::signed_bytes(new_view),
+            Payload::Request(_) | Payload::Reply(_) | Payload::FetchState(_) | Payload::StateTransfer(_)
+                | Payload::Shutdown(_) | Payload::SuspectPrimary(_) | Payload::Reconfiguration(_) | Payload::Credentials(_) => None,
+        }
     }
 }
 
 #[derive(Debug)]
-pub struct Node {
+pub struct Node<S: StateMachine> {
     id: ID,
-    state: Arc<Mutex<State>>,
+    state: Arc<Mutex<State<S>>>,
+    batch_delay: Duration, // how often pending requests are checked
 }
 
-impl Node {
-    pub fn spawn(id: ID, all_nodes: &HashSet<ID>, inter_sender: Sender<Message>) -> NodeCtrl {
+impl<S: StateMachine> Node<S> {
+    // With a write-ahead log the node replays it before it takes any message,
+    // so it never contradicts what it sent before a restart
+    pub fn try_spawn(id: ID, all_nodes: &HashSet<ID>, inter_sender: Sender<Message<S>>, config: &Config, credentials: Credentials, machine: S) -> Result<NodeCtrl<S>, PbftError> {
         let (data_sender, data_receiver) = mpsc::channel();
-        let state = State::genesis(id, all_nodes.iter().map(|i| *i).collect());
+        let state = State::genesis(id, all_nodes.iter().copied().collect(), config, credentials, machine);
+        if let Some(dir) = &config.wal_dir {
+            convert_err(state.lock())?.open_log(id, dir, inter_sender.clone())?;
+        }
         let state_clone = state.clone();
+        let batch_delay = config.max_batch_delay;
         let join_handle = thread::spawn(
             move || {
                 let node = Node {
-                    id: id,
+                    id,
                     state: state.clone(),
+                    batch_delay,
                 };
                 node.handle_all_requests(data_receiver, inter_sender)
                 });
-        NodeCtrl {
-            join_handle: join_handle,
-            data_sender: data_sender,
+        Ok(NodeCtrl {
+            join_handle,
+            data_sender,
             state: state_clone
-        }
+        })
     }
 
-    fn handle_all_requests(&self, data_receiver: Receiver<Message>, data_sender: Sender<Message>) -> Result<(), String> {
-        for msg in data_receiver {
-            //println!("[{}] Received {:?}", node.id, msg);
-            let should_shutdown = self.handle_control_message(&msg);
-            if should_shutdown {
-                print!("[{}] Shutdown", self.id);
-                break;
+    fn handle_all_requests(&self, data_receiver: Receiver<Message<S>>, data_sender: Sender<Message<S>>) -> Result<(), PbftError> {
+        loop {
+            match data_receiver.recv_timeout(self.batch_delay) {
+                Ok(msg) => {
+                    let should_shutdown = self.handle_control_message(&msg);
+                    if should_shutdown {
+                        print!("[{}] Shutdown", self.id);
+                        break;
+                    }
+                    self.handle_protocol_message(msg, data_sender.clone());
+                },
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => break,
             }
-            self.handle_protocol_message(msg, data_sender.clone());
+            self.flush_due_batch(data_sender.clone());
+            self.check_timers(data_sender.clone());
         }
         Ok(())
     }
 
-    fn handle_control_message(&self, message: &Message) -> bool {
-        //print!("[{}] Received shutdown request", self.id);
-        if message.shutdown.is_some() {
-            return true
+    fn flush_due_batch(&self, data_sender: Sender<Message<S>>) {
+        match self.state.lock() {
+            Ok(mut guard) => {
+                (*guard).flush_due_batch(self.id, data_sender);
+            },
+            Err(e) => {
+                println!("[{}] Error while trying to acquire node's own state: {:?}", self.id, e);
+            },
+        }
+    }
+
+    fn check_timers(&self, data_sender: Sender<Message<S>>) {
+        match self.state.lock() {
+            Ok(mut guard) => {
+                (*guard).check_timers(self.id, Instant::now(), data_sender);
+            },
+            Err(e) => {
+                println!("[{}] Error while trying to acquire node's own state: {:?}", self.id, e);
+            },
         }
-        false
     }
 
-    fn handle_protocol_message(&self, message: Message, data_sender: Sender<Message>) {
+    fn handle_control_message(&self, message: &Message<S>) -> bool {
+        message.is_shutdown()
+    }
+
+    fn handle_protocol_message(&self, message: Message<S>, data_sender: Sender<Message<S>>) {
         match self.state.lock() {
             Ok(mut guard) => {
                 match (*guard).handle_protocol_message(self.id, message, data_sender) {
                     Ok(_ok) => (),
-                    Err(e) => println!("[{}] Error in message loop: {:?}", self.id, e),
+                    Err(e) => println!("[{}] Drop: {}", self.id, e),
                 }
             },
             Err(e) => {
@@ -333,20 +1760,20 @@ impl Node {
 }
 
 #[derive(Debug)]
-pub struct NodeCtrl {
-    join_handle: JoinHandle<Result<(), String>>,
-    data_sender: Sender<Message>,
-    state: Arc<Mutex<State>>,
+pub struct NodeCtrl<S: StateMachine> {
+    join_handle: JoinHandle<Result<(), PbftError>>,
+    data_sender: Sender<Message<S>>,
+    state: Arc<Mutex<State<S>>>,
 }
 
-impl NodeCtrl {
-    pub fn get_join_handle(self) -> JoinHandle<Result<(), String>> {
+impl<S: StateMachine> NodeCtrl<S> {
+    pub fn get_join_handle(self) -> JoinHandle<Result<(), PbftError>> {
         self.join_handle
     }
-    pub fn get_data_sender(&self) -> Sender<Message>{
+    pub fn get_data_sender(&self) -> Sender<Message<S>>{
         self.data_sender.clone()
     }
-    pub fn get_state(&self) -> Arc<Mutex<State>>{
+    pub fn get_state(&self) -> Arc<Mutex<State<S>>>{
         self.state.clone()
     }
 }
@@ -4,13 +4,1796 @@ mod network_interaction_test {
     use crate::node::Message;
     use crate::dto::ID;
     use std::sync::{Arc,RwLock};
+    use crate::state_machine::TipMachine;
 
     #[test]
     fn multi_should_produce_copies_for_multiple_nodes() {
         let pp = Arc::new(RwLock::new(new_random_preprepare()));
         let sender_id = 15 as ID;
         let node_ids = new_nodes(20);
-        let multi: Vec<Message> = Message::multiply(Message::preprepare, pp, sender_id, &node_ids).collect();
+        let multi: Vec<Message<TipMachine>> = Message::multiply(Message::preprepare, pp, sender_id, &node_ids).collect();
         assert_eq!(multi.len(), 20)
     }
 }
+
+#[cfg(test)]
+mod view_change_test {
+    use crate::dto::{PrePrepare};
+    use crate::test_util::{Cluster,config,new_request};
+
+    #[test]
+    fn backups_should_move_to_next_view() {
+        let mut cluster = Cluster::new(4);
+        cluster.silence(0);
+        for id in 1..4 {
+            cluster.suspect_primary(id);
+        }
+        cluster.deliver_all();
+        for id in 1..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_view_id(), 1);
+            assert!(state.is_view_active());
+        }
+    }
+
+    #[test]
+    fn f_plus_one_suspicions_should_pull_others_along() {
+        let mut cluster = Cluster::new(4);
+        cluster.silence(0);
+        cluster.suspect_primary(2);
+        cluster.deliver_all();
+        assert_eq!(cluster.get_state(3).lock().unwrap().get_view_id(), 0);
+        cluster.suspect_primary(3);
+        cluster.deliver_all();
+        for id in 1..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_view_id(), 1);
+            assert!(state.is_view_active());
+        }
+    }
+
+    #[test]
+    fn prepared_request_should_survive_view_change() {
+        let mut cluster = Cluster::new(4);
+        // only 1 and 2 hear the primary: prepared there, but too few commits
+        cluster.silence(3);
+        cluster.broadcast_preprepare(PrePrepare::new(0, 1, vec![new_request("prepared")], 0));
+        cluster.deliver_all();
+        assert_eq!(cluster.get_state(1).lock().unwrap().get_machine().get_tip(), "genesis".to_owned());
+        cluster.restore(3);
+        cluster.silence(0);
+        for id in 1..4 {
+            cluster.suspect_primary(id);
+        }
+        cluster.deliver_all();
+        for id in 1..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_view_id(), 1);
+            assert_eq!(state.get_machine().get_tip(), "prepared".to_owned());
+        }
+    }
+
+    #[test]
+    fn new_view_should_move_replica_to_its_stable_checkpoint() {
+        let mut cluster = Cluster::with_config(4, config());
+        let mut client = cluster.new_client();
+        // 3 misses everything up to the checkpoint at 6, its window still ends at 4
+        cluster.silence(3);
+        for i in 1..=6 {
+            cluster.run_request(&mut client, &format!("op{}", i));
+        }
+        cluster.restore(3);
+        cluster.silence(0);
+        for id in 1..4 {
+            cluster.suspect_primary(id);
+        }
+        cluster.deliver_all();
+        {
+            let state = cluster.get_state(3);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_view_id(), 1);
+            assert_eq!(state.get_stable_seq(), 6);
+            assert_eq!(state.get_last_executed(), 6);
+        }
+        cluster.submit(client.new_request("after".to_owned()), 1);
+        cluster.deliver_all();
+        assert_eq!(cluster.get_state(3).lock().unwrap().get_machine().get_tip(), "after".to_owned());
+    }
+}
+
+#[cfg(test)]
+mod primary_test {
+    use crate::dto::{PrePrepare};
+    use crate::error::PbftError;
+    use crate::test_util::{Cluster,new_request};
+
+    #[test]
+    fn preprepare_from_backup_should_be_rejected() {
+        let cluster = Cluster::new(4);
+        let forged = PrePrepare::new(0, 1, vec![new_request("forged")], 1);
+        assert_eq!(
+            cluster.get_state(2).lock().unwrap().check_primary(&forged),
+            Err(PbftError::NotPrimary{view_id: 0, sender_id: 1, primary_id: Some(0)}));
+        cluster.broadcast_preprepare(forged);
+        cluster.deliver_all();
+        for id in 0..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_preprepares().get_reqs().len(), 0);
+            assert_eq!(state.get_prepares().get_reqs().len(), 0);
+        }
+    }
+
+    #[test]
+    fn preprepare_with_wrong_digest_should_be_rejected() {
+        let cluster = Cluster::new(4);
+        let mut tampered = PrePrepare::new(0, 1, vec![new_request("honest")], 0);
+        tampered.tamper_batch(vec![new_request("swapped")]);
+        assert_eq!(
+            cluster.get_state(1).lock().unwrap().check_primary(&tampered),
+            Err(PbftError::DigestMismatch{seq_id: 1}));
+        cluster.broadcast_preprepare(tampered);
+        cluster.deliver_all();
+        assert_eq!(cluster.get_state(1).lock().unwrap().get_preprepares().get_reqs().len(), 0);
+    }
+
+    #[test]
+    fn preprepare_signed_by_someone_else_should_be_rejected() {
+        let cluster = Cluster::new(4);
+        // claims to come from the primary, but node 1 signed it
+        cluster.broadcast_preprepare_signed_by(1, PrePrepare::new(0, 1, vec![new_request("impersonated")], 0));
+        cluster.deliver_all();
+        for id in 1..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_preprepares().get_reqs().len(), 0);
+            assert_eq!(state.get_machine().get_tip(), "genesis".to_owned());
+        }
+    }
+
+    #[test]
+    fn preprepare_signed_by_primary_should_pass() {
+        let cluster = Cluster::new(4);
+        let signed = cluster.sign(0, PrePrepare::new(0, 1, vec![new_request("signed")], 0));
+        // the sender decides who the primary is, the signature only has to verify
+        assert_eq!(cluster.get_state(1).lock().unwrap().check_primary(&signed), Ok(()));
+        cluster.broadcast_preprepare(signed);
+        cluster.deliver_all();
+        for id in 1..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_preprepares().get_reqs().len(), 1);
+            assert_eq!(state.get_machine().get_tip(), "signed".to_owned());
+        }
+    }
+
+    #[test]
+    fn primary_should_follow_view() {
+        let cluster = Cluster::new(4);
+        let state = cluster.get_state(3);
+        let state = state.lock().unwrap();
+        assert_eq!(state.check_primary(&PrePrepare::new(0, 1, vec![new_request("m")], 0)), Ok(()));
+        assert_eq!(state.check_primary(&PrePrepare::new(1, 1, vec![new_request("m")], 1)), Ok(()));
+        assert_eq!(
+            state.check_primary(&PrePrepare::new(1, 1, vec![new_request("m")], 0)),
+            Err(PbftError::NotPrimary{view_id: 1, sender_id: 0, primary_id: Some(1)}));
+    }
+}
+
+#[cfg(test)]
+mod client_request_test {
+    use crate::client::Client;
+    use crate::test_util::Cluster;
+    use crate::state_machine::TipMachine;
+
+    fn run_request(cluster: &Cluster<TipMachine>, target: u64) -> Option<String> {
+        let mut client: Client<TipMachine> = cluster.new_client();
+        let request = client.new_request("op".to_owned());
+        cluster.submit(request, target);
+        cluster.deliver_all();
+        let mut result = None;
+        for reply in cluster.take_replies() {
+            result = client.handle_reply(&reply).or(result);
+        }
+        result
+    }
+
+    #[test]
+    fn primary_should_order_client_request() {
+        let cluster = Cluster::new(4);
+        assert_eq!(run_request(&cluster, 0), Some("op".to_owned()));
+        for id in 0..4 {
+            assert_eq!(cluster.get_state(id).lock().unwrap().get_machine().get_tip(), "op".to_owned());
+        }
+    }
+
+    #[test]
+    fn backup_should_relay_client_request() {
+        let mut cluster = Cluster::new(4);
+        cluster.silence(3);
+        assert_eq!(run_request(&cluster, 2), Some("op".to_owned()));
+    }
+}
+
+#[cfg(test)]
+mod checkpoint_test {
+    use crate::config::Config;
+    use crate::dto::Checkpoint;
+    use crate::error::PbftError;
+    use crate::node::Message;
+    use crate::test_util::Cluster;
+    use crate::state_machine::TipMachine;
+    use std::sync::{Arc,RwLock};
+
+    fn cluster() -> Cluster<TipMachine> {
+        Cluster::with_config(4, Config{checkpoint_interval: 2, log_size: 4, ..Config::default()})
+    }
+
+    #[test]
+    fn stable_checkpoint_should_discard_old_requests() {
+        let cluster = cluster();
+        let mut client = cluster.new_client();
+        for i in 0..5 {
+            assert_eq!(cluster.run_request(&mut client, &format!("op{}", i)), Some(format!("op{}", i)));
+        }
+        for id in 0..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_stable_seq(), 4);
+            assert_eq!(state.get_preprepares().get_reqs().keys().collect::<Vec<_>>(), vec![&5]);
+            assert_eq!(state.get_prepares().get_reqs().keys().collect::<Vec<_>>(), vec![&5]);
+            assert_eq!(state.get_commits().get_reqs().keys().collect::<Vec<_>>(), vec![&5]);
+            assert_eq!(state.get_checkpoints().get_reqs().keys().collect::<Vec<_>>(), vec![&4]);
+            let snapshots: Vec<(u64, String)> = state.get_snapshots().iter()
+                .map(|(seq_id, snapshot)| (*seq_id, snapshot.get_machine().clone()))
+                .collect();
+            assert_eq!(snapshots, vec![(4, "op3".to_owned())]);
+        }
+    }
+
+    #[test]
+    fn checkpoint_outside_water_marks_should_be_rejected() {
+        let cluster = cluster();
+        let send = |seq_id| {
+            let checkpoint = cluster.sign(1, Checkpoint::new(seq_id, "digest".to_owned(), 1));
+            cluster.try_deliver(Message::checkpoint(1, 0, Arc::new(RwLock::new(checkpoint))))
+        };
+        assert_eq!(send(u64::MAX - 1), Err(PbftError::OutsideWaterMarks{seq_id: u64::MAX - 1, low: 0, high: 4}));
+        assert_eq!(send(6), Err(PbftError::OutsideWaterMarks{seq_id: 6, low: 0, high: 4}));
+        // within the window, but no checkpoint is taken at 3
+        assert_eq!(send(3), Err(PbftError::OutsideWaterMarks{seq_id: 3, low: 0, high: 4}));
+        assert!(cluster.get_state(0).lock().unwrap().get_checkpoints().get_reqs().is_empty());
+        assert_eq!(send(4), Ok(()));
+    }
+
+    #[test]
+    fn checkpoint_needs_quorum() {
+        let mut cluster = cluster();
+        cluster.silence(2);
+        cluster.silence(3);
+        let mut client = cluster.new_client();
+        cluster.run_request(&mut client, "op1");
+        cluster.run_request(&mut client, "op2");
+        assert_eq!(cluster.get_state(0).lock().unwrap().get_stable_seq(), 0);
+    }
+
+    #[test]
+    fn view_change_should_start_from_stable_checkpoint() {
+        let mut cluster = cluster();
+        let mut client = cluster.new_client();
+        for i in 0..3 {
+            cluster.run_request(&mut client, &format!("op{}", i));
+        }
+        cluster.silence(0);
+        for id in 1..4 {
+            cluster.suspect_primary(id);
+        }
+        cluster.deliver_all();
+        for id in 1..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_view_id(), 1);
+            assert!(state.is_view_active());
+            assert_eq!(state.get_stable_seq(), 2);
+        }
+        // seq 3 executed in view 0 already, re-issuing it in view 1 doesn't run it again
+        for id in 1..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_last_executed(), 3);
+            assert_eq!(state.get_machine().get_tip(), "op2".to_owned());
+        }
+        cluster.take_replies();
+        cluster.submit(client.new_request("after".to_owned()), 1);
+        cluster.deliver_all();
+        let mut result = None;
+        for reply in cluster.take_replies() {
+            result = client.handle_reply(&reply).or(result);
+        }
+        assert_eq!(result, Some("after".to_owned()));
+        // replies from view 1 tell the client about the new primary
+        assert_eq!(client.get_primary(), Some(1));
+    }
+}
+
+#[cfg(test)]
+mod water_mark_test {
+    use crate::config::Config;
+    use crate::dto::{PrePrepare};
+    use crate::error::PbftError;
+    use crate::node::Message;
+    use crate::test_util::{Cluster,new_request};
+    use std::sync::{Arc,RwLock};
+
+    #[test]
+    fn preprepare_above_high_water_mark_should_be_dropped() {
+        let cluster = Cluster::new(4);
+        let preprepare = cluster.sign(0, PrePrepare::new(0, u64::MAX, vec![new_request("exhaust")], 0));
+        assert_eq!(
+            cluster.try_deliver(Message::preprepare(0, 1, Arc::new(RwLock::new(preprepare)))),
+            Err(PbftError::OutsideWaterMarks{seq_id: u64::MAX, low: 0, high: 200}));
+        cluster.broadcast_preprepare(PrePrepare::new(0, u64::MAX, vec![new_request("exhaust")], 0));
+        cluster.deliver_all();
+        for id in 1..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_water_marks(), (0, 200));
+            assert_eq!(state.get_preprepares().get_reqs().len(), 0);
+            assert_eq!(state.get_machine().get_tip(), "genesis".to_owned());
+        }
+    }
+
+    #[test]
+    fn water_marks_should_follow_stable_checkpoint() {
+        let cluster = Cluster::with_config(4, Config{checkpoint_interval: 2, log_size: 4, ..Config::default()});
+        let mut client = cluster.new_client();
+        cluster.run_request(&mut client, "op1");
+        cluster.run_request(&mut client, "op2");
+        let state = cluster.get_state(1);
+        assert_eq!(state.lock().unwrap().get_water_marks(), (2, 6));
+        cluster.broadcast_preprepare(PrePrepare::new(0, 2, vec![new_request("old")], 0));
+        cluster.deliver_all();
+        assert_eq!(state.lock().unwrap().get_preprepares().get_reqs().len(), 0);
+    }
+
+    #[test]
+    fn primary_should_not_assign_above_high_water_mark() {
+        let cluster = Cluster::with_config(4, Config{checkpoint_interval: 10, log_size: 2, ..Config::default()});
+        let mut client = cluster.new_client();
+        assert_eq!(cluster.run_request(&mut client, "op1"), Some("op1".to_owned()));
+        assert_eq!(cluster.run_request(&mut client, "op2"), Some("op2".to_owned()));
+        assert_eq!(cluster.run_request(&mut client, "op3"), None);
+    }
+}
+
+#[cfg(test)]
+mod authenticator_test {
+    use crate::config::{Config,Authentication};
+    use crate::crypto::Signed;
+    use crate::dto::{PrePrepare,Prepare};
+    use crate::error::PbftError;
+    use crate::node::Message;
+    use crate::test_util::{Cluster,new_request};
+    use std::sync::{Arc,RwLock};
+    use crate::state_machine::TipMachine;
+
+    fn cluster() -> Cluster<TipMachine> {
+        Cluster::with_config(4, Config{checkpoint_interval: 2, authentication: Authentication::Authenticators, ..Config::default()})
+    }
+
+    #[test]
+    fn requests_should_commit_with_authenticators() {
+        let cluster = cluster();
+        let mut client = cluster.new_client();
+        for i in 0..3 {
+            assert_eq!(cluster.run_request(&mut client, &format!("op{}", i)), Some(format!("op{}", i)));
+        }
+        for id in 0..4 {
+            assert_eq!(cluster.get_state(id).lock().unwrap().get_stable_seq(), 2);
+        }
+    }
+
+    #[test]
+    fn view_change_should_work_with_authenticators() {
+        let mut cluster = cluster();
+        cluster.silence(0);
+        for id in 1..4 {
+            cluster.suspect_primary(id);
+        }
+        cluster.deliver_all();
+        cluster.submit(new_request("after"), 1);
+        cluster.deliver_all();
+        for id in 1..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_view_id(), 1);
+            assert_eq!(state.get_machine().get_tip(), "after".to_owned());
+        }
+    }
+
+    #[test]
+    fn prepare_should_need_a_signature_next_to_its_authenticator() {
+        let cluster = cluster();
+        // the MAC of 2 is genuine, but prepared certificates carry the prepare to third replicas
+        let prepare = Prepare::new(0, 1, "digest".to_owned(), 2);
+        let message = cluster.authenticate(Message::prepare(2, 1, Arc::new(RwLock::new(prepare))));
+        assert_eq!(cluster.try_deliver(message), Err(PbftError::InvalidSignature{sender_id: 2}));
+    }
+
+    #[test]
+    fn commits_should_only_carry_authenticators() {
+        let cluster = cluster();
+        let mut client = cluster.new_client();
+        assert_eq!(cluster.run_request(&mut client, "op"), Some("op".to_owned()));
+        let state = cluster.get_state(1);
+        let state = state.lock().unwrap();
+        assert!(state.get_commits().iter().all(|commit| commit.read().unwrap().get_signature().is_empty()));
+        assert!(state.get_prepares().iter().all(|prepare| !prepare.read().unwrap().get_signature().is_empty()));
+    }
+
+    #[test]
+    fn message_without_authenticator_should_be_dropped() {
+        let cluster = cluster();
+        let preprepare = Arc::new(RwLock::new(PrePrepare::new(0, 1, vec![new_request("unauthenticated")], 0)));
+        cluster.deliver(Message::preprepare(0, 1, preprepare));
+        assert_eq!(cluster.get_state(1).lock().unwrap().get_preprepares().get_reqs().len(), 0);
+    }
+
+    #[test]
+    fn relayed_message_should_be_dropped() {
+        let cluster = cluster();
+        // node 1 vouches for a pre-prepare that claims to come from the primary
+        cluster.broadcast_preprepare_signed_by(1, PrePrepare::new(0, 1, vec![new_request("relayed")], 0));
+        cluster.deliver_all();
+        for id in 1..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_preprepares().get_reqs().len(), 0);
+            assert_eq!(state.get_machine().get_tip(), "genesis".to_owned());
+        }
+    }
+}
+
+#[cfg(test)]
+mod batch_test {
+    use crate::client::Client;
+    use crate::config::Config;
+    use crate::dto::{ID,Tip,Timestamp};
+    use crate::test_util::Cluster;
+    use crate::state_machine::TipMachine;
+
+    fn cluster(max_batch_size: usize) -> Cluster<TipMachine> {
+        Cluster::with_config(4, Config{max_batch_size, ..Config::default()})
+    }
+
+    // Submits every op right away, nothing is delivered between them
+    fn submit_all(cluster: &Cluster<TipMachine>, client: &mut Client<TipMachine>, ops: &[&str]) {
+        for op in ops {
+            cluster.submit(client.new_request(op.to_string()), 0);
+        }
+    }
+
+    // Results the client accepted, by timestamp
+    fn accepted(cluster: &Cluster<TipMachine>, client: &mut Client<TipMachine>, count: Timestamp) -> Vec<Option<Tip>> {
+        for reply in cluster.take_replies() {
+            client.handle_reply(&reply);
+        }
+        (1..=count).map(|timestamp| client.get_result(timestamp)).collect()
+    }
+
+    fn ordered_seqs(cluster: &Cluster<TipMachine>, id: ID) -> Vec<ID> {
+        let state = cluster.get_state(id);
+        let state = state.lock().unwrap();
+        let mut seqs: Vec<ID> = state.get_preprepares().get_reqs().keys().copied().collect();
+        seqs.sort();
+        seqs
+    }
+
+    #[test]
+    fn full_batch_should_be_ordered_right_away() {
+        let cluster = cluster(3);
+        let mut client = cluster.new_client();
+        submit_all(&cluster, &mut client, &["op1", "op2"]);
+        assert_eq!(ordered_seqs(&cluster, 0), Vec::<ID>::new());
+        submit_all(&cluster, &mut client, &["op3"]);
+        assert_eq!(ordered_seqs(&cluster, 0), vec![1]);
+        cluster.deliver_all();
+        for id in 0..4 {
+            assert_eq!(ordered_seqs(&cluster, id), vec![1]);
+            assert_eq!(cluster.get_state(id).lock().unwrap().get_machine().get_tip(), "op3".to_owned());
+        }
+        assert_eq!(
+            accepted(&cluster, &mut client, 3),
+            vec![Some("op1".to_owned()), Some("op2".to_owned()), Some("op3".to_owned())]);
+    }
+
+    #[test]
+    fn partial_batch_should_be_ordered_after_delay() {
+        let cluster = cluster(3);
+        let mut client = cluster.new_client();
+        submit_all(&cluster, &mut client, &["op1", "op2"]);
+        cluster.deliver_all();
+        for id in 0..4 {
+            assert_eq!(ordered_seqs(&cluster, id), vec![1]);
+        }
+        assert_eq!(accepted(&cluster, &mut client, 2), vec![Some("op1".to_owned()), Some("op2".to_owned())]);
+    }
+
+    #[test]
+    fn requests_above_max_size_should_go_to_next_batch() {
+        let cluster = cluster(2);
+        let mut client = cluster.new_client();
+        submit_all(&cluster, &mut client, &["op1", "op2", "op3", "op4", "op5"]);
+        cluster.deliver_all();
+        for id in 0..4 {
+            assert_eq!(ordered_seqs(&cluster, id), vec![1, 2, 3]);
+            assert_eq!(cluster.get_state(id).lock().unwrap().get_machine().get_tip(), "op5".to_owned());
+        }
+        assert!(accepted(&cluster, &mut client, 5).iter().all(|result| result.is_some()));
+    }
+}
+
+#[cfg(test)]
+mod pipeline_test {
+    use crate::config::Config;
+    use crate::dto::{ID,PrePrepare,Request};
+    use crate::network::Network;
+    use crate::error::PbftError;
+    use crate::test_util::{Cluster,new_request,run_until,CLIENT_ID};
+    use crate::state_machine::TipMachine;
+
+    // one request per sequence number
+    fn config() -> Config {
+        Config{max_batch_size: 1, ..Config::default()}
+    }
+
+    fn all_accepted(net: &Network<TipMachine>, count: ID) -> bool {
+        let client = net.get_client(&CLIENT_ID).unwrap();
+        (1..=count).all(|timestamp| client.get_result(timestamp).is_some())
+    }
+
+    #[test]
+    fn overlapping_requests_should_complete_through_network() {
+        let mut net = Network::with_config(4, config());
+        net.add_client(CLIENT_ID);
+        for i in 1..=5 {
+            net.submit(CLIENT_ID, format!("op{}", i));
+        }
+        assert!(run_until(&mut net, |net| all_accepted(net, 5)));
+        let client = net.get_client(&CLIENT_ID).unwrap();
+        for i in 1..=5 {
+            assert_eq!(client.get_result(i), Some(format!("op{}", i)));
+        }
+    }
+
+    #[test]
+    fn every_backup_should_vote_in_every_instance() {
+        let mut net = Network::with_config(4, config());
+        net.add_client(CLIENT_ID);
+        for i in 1..=3 {
+            net.submit(CLIENT_ID, format!("op{}", i));
+        }
+        // the client is done after f+1 replies, a backup may still be voting
+        assert!(run_until(&mut net, |net| {
+            all_accepted(net, 3) && (0..4).all(|id| net.get_node(&id).unwrap().get_state().lock().unwrap().get_last_executed() == 3)
+        }));
+        for id in 1..4 {
+            let state = net.get_node(&id).unwrap().get_state();
+            let state = state.lock().unwrap();
+            for seq in 1..=3 {
+                let slot = state.get_slots().get(0, seq).unwrap();
+                assert!(slot.has_sent_prepare());
+                assert!(slot.has_sent_commit());
+            }
+        }
+    }
+
+    #[test]
+    fn instances_should_interleave() {
+        let cluster = Cluster::with_config(4, config());
+        // all pre-prepares are out before anyone prepares
+        for i in 1..=3 {
+            cluster.submit(Request::new(format!("op{}", i), i, CLIENT_ID), 0);
+        }
+        assert_eq!(cluster.get_state(1).lock().unwrap().get_slots().len(), 0);
+        cluster.deliver_all();
+        for id in 0..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            let mut committed: Vec<ID> = state.get_commits().get_reqs().keys().copied().collect();
+            committed.sort();
+            assert_eq!(committed, vec![1, 2, 3]);
+        }
+    }
+
+    #[test]
+    fn second_preprepare_for_same_slot_should_be_rejected() {
+        let cluster = Cluster::new(4);
+        cluster.broadcast_preprepare(PrePrepare::new(0, 1, vec![new_request("first")], 0));
+        cluster.deliver_all();
+        let equivocation = PrePrepare::new(0, 1, vec![new_request("second")], 0);
+        assert_eq!(
+            cluster.get_state(1).lock().unwrap().check_slot(&equivocation),
+            Err(PbftError::ConflictingPrePrepare{view_id: 0, seq_id: 1}));
+        cluster.broadcast_preprepare(equivocation);
+        cluster.deliver_all();
+        for id in 1..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_machine().get_tip(), "first".to_owned());
+            assert_eq!(state.get_preprepares().get_reqs()[&1][&0].len(), 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod execution_test {
+    use crate::dto::{ID,PrePrepare,Request};
+    use crate::test_util::{Cluster,new_request,CLIENT_ID};
+    use crate::state_machine::TipMachine;
+
+    fn pending(cluster: &Cluster<TipMachine>, id: ID) -> Vec<ID> {
+        cluster.get_state(id).lock().unwrap().get_committed().keys().copied().collect()
+    }
+
+    #[test]
+    fn committed_request_should_wait_for_lower_seqs() {
+        let cluster = Cluster::new(4);
+        // timestamps grow in the order the client sent the requests
+        cluster.broadcast_preprepare(PrePrepare::new(0, 2, vec![Request::new("second".to_owned(), 2, CLIENT_ID)], 0));
+        cluster.deliver_all();
+        for id in 1..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_last_executed(), 0);
+            assert_eq!(state.get_machine().get_tip(), "genesis".to_owned());
+        }
+        assert_eq!(pending(&cluster, 1), vec![2]);
+        assert_eq!(cluster.take_replies().len(), 0);
+        cluster.broadcast_preprepare(PrePrepare::new(0, 1, vec![Request::new("first".to_owned(), 1, CLIENT_ID)], 0));
+        cluster.deliver_all();
+        for id in 1..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_last_executed(), 2);
+            assert_eq!(state.get_machine().get_tip(), "second".to_owned());
+        }
+        assert_eq!(pending(&cluster, 1), Vec::<ID>::new());
+    }
+
+    #[test]
+    fn request_should_execute_once() {
+        let cluster = Cluster::new(4);
+        cluster.broadcast_preprepare(PrePrepare::new(0, 1, vec![new_request("once")], 0));
+        cluster.deliver_all();
+        // one reply per backup, more commits arriving don't execute it again
+        assert_eq!(cluster.take_replies().len(), 3);
+        assert_eq!(cluster.get_state(1).lock().unwrap().get_last_executed(), 1);
+    }
+
+    #[test]
+    fn null_request_should_fill_the_gap() {
+        let cluster = Cluster::new(4);
+        cluster.broadcast_preprepare(PrePrepare::new(0, 2, vec![new_request("after gap")], 0));
+        cluster.broadcast_preprepare(PrePrepare::null(0, 1, 0));
+        cluster.deliver_all();
+        let state = cluster.get_state(2);
+        let state = state.lock().unwrap();
+        assert_eq!(state.get_last_executed(), 2);
+        assert_eq!(state.get_machine().get_tip(), "after gap".to_owned());
+    }
+}
+
+#[cfg(test)]
+mod kv_store_test {
+    use crate::config::Config;
+    use crate::kv_store::{KvStore,KvOperation,KvResult};
+    use crate::state_machine::StateMachine;
+    use crate::test_util::Cluster;
+
+    #[test]
+    fn replicas_should_agree_on_store() {
+        let cluster: Cluster<KvStore> = Cluster::build(4, Config::default());
+        let mut client = cluster.new_client();
+        let put = KvOperation::Put{key: "k".to_owned(), value: "1".to_owned()};
+        assert_eq!(cluster.run_operation(&mut client, put), Some(KvResult::Previous(None)));
+        let stale = KvOperation::CompareAndSwap{key: "k".to_owned(), expected: None, value: "2".to_owned()};
+        assert_eq!(cluster.run_operation(&mut client, stale), Some(KvResult::Swapped{success: false, current: Some("1".to_owned())}));
+        let get = KvOperation::Get{key: "k".to_owned()};
+        assert_eq!(cluster.run_operation(&mut client, get), Some(KvResult::Value(Some("1".to_owned()))));
+        let digest = cluster.get_state(0).lock().unwrap().get_machine().digest();
+        for id in 1..4 {
+            assert_eq!(cluster.get_state(id).lock().unwrap().get_machine().digest(), digest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod read_only_test {
+    use crate::config::Config;
+    use crate::dto::Request;
+    use crate::kv_store::{KvStore,KvOperation,KvResult};
+    use crate::test_util::{Cluster,CLIENT_ID};
+
+    fn put(value: &str) -> KvOperation {
+        KvOperation::Put{key: "k".to_owned(), value: value.to_owned()}
+    }
+
+    fn get() -> KvOperation {
+        KvOperation::Get{key: "k".to_owned()}
+    }
+
+    fn value(value: &str) -> Option<KvResult> {
+        Some(KvResult::Value(Some(value.to_owned())))
+    }
+
+    #[test]
+    fn read_should_not_be_ordered() {
+        let cluster: Cluster<KvStore> = Cluster::build(4, Config::default());
+        let mut client = cluster.new_client();
+        cluster.run_operation(&mut client, put("1"));
+        assert_eq!(cluster.run_read(&mut client, get()), value("1"));
+        for id in 0..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_last_executed(), 1);
+            assert_eq!(state.get_preprepares().get_reqs().len(), 1);
+        }
+    }
+
+    #[test]
+    fn write_disguised_as_read_should_be_dropped() {
+        let cluster: Cluster<KvStore> = Cluster::build(4, Config::default());
+        for id in 0..4 {
+            cluster.submit(Request::read_only(put("sneaky"), 1, CLIENT_ID), id);
+        }
+        cluster.deliver_all();
+        assert_eq!(cluster.take_replies().len(), 0);
+        for id in 0..4 {
+            assert_eq!(cluster.get_state(id).lock().unwrap().get_machine().get("k"), None);
+        }
+    }
+
+    #[test]
+    fn diverging_replicas_should_make_read_go_through_ordering() {
+        let mut cluster: Cluster<KvStore> = Cluster::build(4, Config::default());
+        let mut client = cluster.new_client();
+        cluster.silence(3);
+        assert_eq!(cluster.run_operation(&mut client, put("1")), Some(KvResult::Previous(None)));
+        cluster.restore(3);
+        cluster.silence(2);
+        assert_eq!(cluster.run_operation(&mut client, put("2")), Some(KvResult::Previous(Some("1".to_owned()))));
+        cluster.restore(2);
+        // 0 and 1 say "2", 2 says "1" and 3 has nothing: no 2f+1
+        assert_eq!(cluster.run_read(&mut client, get()), value("2"));
+        let state = cluster.get_state(0);
+        assert_eq!(state.lock().unwrap().get_last_executed(), 3);
+    }
+
+    #[test]
+    fn read_falling_back_after_later_write_should_not_be_stale() {
+        let cluster: Cluster<KvStore> = Cluster::build(4, Config::default());
+        let mut client = cluster.new_client();
+        let read = client.new_read_request(get());
+        assert_eq!(cluster.run_operation(&mut client, put("1")), Some(KvResult::Previous(None)));
+        // the fast path answers diverge
+        for id in 0..3 {
+            let reply = read.make_reply(0, id, KvResult::Value(Some(format!("x{}", id))));
+            client.handle_reply(&cluster.sign(id, reply));
+        }
+        assert!(client.is_read_stuck(read.get_timestamp()));
+        let ordered = client.fall_back(read.get_timestamp()).unwrap();
+        cluster.submit(ordered, client.get_primary().unwrap());
+        cluster.deliver_all();
+        for reply in cluster.take_replies() {
+            client.handle_reply(&reply);
+        }
+        assert_eq!(client.get_result(read.get_timestamp()), value("1"));
+    }
+}
+
+#[cfg(test)]
+mod tentative_test {
+    use crate::config::Config;
+    use crate::dto::{ID,PrePrepare,Reply,Tip};
+    use crate::state_machine::TipMachine;
+    use crate::test_util::{Cluster,new_request};
+    use std::collections::HashMap;
+
+    fn cluster() -> Cluster<TipMachine> {
+        Cluster::with_config(4, Config{tentative_execution: true, ..Config::default()})
+    }
+
+    #[test]
+    fn replicas_should_reply_tentatively_then_confirm() {
+        let cluster = cluster();
+        cluster.submit(new_request("op"), 0);
+        cluster.deliver_all();
+        let mut replies: HashMap<ID, Vec<Reply<Tip>>> = HashMap::new();
+        for reply in cluster.take_replies() {
+            replies.entry(reply.get_replica_id()).or_default().push(reply);
+        }
+        for id in 0..4 {
+            let kinds: Vec<bool> = replies[&id].iter().map(|reply| reply.is_tentative()).collect();
+            assert_eq!(kinds, vec![true, false]);
+            assert!(replies[&id].iter().all(|reply| reply.get_result() == "op"));
+            assert_eq!(cluster.get_state(id).lock().unwrap().get_last_executed(), 1);
+        }
+    }
+
+    #[test]
+    fn view_change_should_roll_back_tentative_execution() {
+        let mut cluster = cluster();
+        cluster.silence(3);
+        cluster.broadcast_preprepare(PrePrepare::new(0, 1, vec![new_request("tentative")], 0));
+        // only the prepare of 2 reaches 1, every other message is lost
+        cluster.deliver_only(|message| message.get_sender_id() == 2 && message.get_target_id() == 1);
+        cluster.deliver_only(|message| message.get_reply().is_some());
+        let replies = cluster.take_replies();
+        assert_eq!(replies.len(), 1);
+        assert!(replies[0].is_tentative());
+        let state = cluster.get_state(1);
+        assert_eq!(state.lock().unwrap().get_machine().get_tip(), "tentative".to_owned());
+        assert_eq!(state.lock().unwrap().get_last_executed(), 0);
+        cluster.restore(3);
+        cluster.suspect_primary(1);
+        assert_eq!(state.lock().unwrap().get_machine().get_tip(), "genesis".to_owned());
+        // prepared at 1, so the new view orders it again
+        for id in [0, 2, 3] {
+            cluster.suspect_primary(id);
+        }
+        cluster.deliver_all();
+        for id in 0..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_last_executed(), 1);
+            assert_eq!(state.get_machine().get_tip(), "tentative".to_owned());
+        }
+    }
+}
+
+#[cfg(test)]
+mod state_transfer_test {
+    use crate::dto::{ID,StateTransfer};
+    use crate::network::Network;
+    use crate::node::{Message,StateSnapshot};
+    use crate::state_machine::TipMachine;
+    use crate::test_util::{Cluster,config,run_until,CLIENT_ID};
+    use std::sync::{Arc,RwLock};
+
+    fn transfer(cluster: &Cluster<TipMachine>, snapshot: &str) -> Message<TipMachine> {
+        let state = cluster.get_state(0);
+        let proof = state.lock().unwrap().get_stable_proof().clone();
+        let replies = state.lock().unwrap().get_snapshots().get(&2).unwrap().get_replies().clone();
+        let transfer = StateTransfer::new(2, proof, StateSnapshot::new(snapshot.to_owned(), replies), vec![], 0);
+        Message::state_transfer(0, 3, Arc::new(RwLock::new(transfer)))
+    }
+
+    #[test]
+    fn lagging_replica_should_catch_up_from_stable_checkpoint() {
+        let mut cluster = Cluster::with_config(4, config());
+        let mut client = cluster.new_client();
+        cluster.silence(3);
+        for i in 0..4 {
+            cluster.run_request(&mut client, &format!("op{}", i));
+        }
+        cluster.restore(3);
+        // the pre-prepares of 5 and 6 are above the water marks of 3, the checkpoint at 6 isn't
+        for i in 4..6 {
+            cluster.run_request(&mut client, &format!("op{}", i));
+        }
+        let state = cluster.get_state(3);
+        assert_eq!(state.lock().unwrap().get_last_executed(), 6);
+        assert_eq!(state.lock().unwrap().get_machine().get_tip(), "op5".to_owned());
+        // the reply cache came with the snapshot
+        assert_eq!(state.lock().unwrap().get_last_replies().get(&CLIENT_ID).map(|reply| reply.get_timestamp()), Some(6));
+        // and it takes part in ordering again
+        cluster.silence(2);
+        assert_eq!(cluster.run_request(&mut client, "op6"), Some("op6".to_owned()));
+        assert_eq!(state.lock().unwrap().get_machine().get_tip(), "op6".to_owned());
+    }
+
+    #[test]
+    fn snapshot_should_match_checkpoint_digest() {
+        let mut cluster = Cluster::with_config(4, config());
+        let mut client = cluster.new_client();
+        cluster.silence(3);
+        cluster.run_request(&mut client, "op0");
+        cluster.run_request(&mut client, "op1");
+        cluster.restore(3);
+        cluster.deliver(transfer(&cluster, "forged"));
+        let state = cluster.get_state(3);
+        assert_eq!(state.lock().unwrap().get_machine().get_tip(), "genesis".to_owned());
+        assert_eq!(state.lock().unwrap().get_last_executed(), 0);
+        cluster.deliver(transfer(&cluster, "op1"));
+        assert_eq!(state.lock().unwrap().get_machine().get_tip(), "op1".to_owned());
+        assert_eq!(state.lock().unwrap().get_last_executed(), 2);
+        assert_eq!(state.lock().unwrap().get_stable_seq(), 2);
+    }
+
+    fn last_executed(net: &Network<TipMachine>, id: ID) -> ID {
+        net.get_node(&id).unwrap().get_state().lock().unwrap().get_last_executed()
+    }
+
+    fn stable_seq(net: &Network<TipMachine>, id: ID) -> ID {
+        net.get_node(&id).unwrap().get_state().lock().unwrap().get_stable_seq()
+    }
+
+    #[test]
+    fn restarted_node_should_catch_up() {
+        let mut net: Network<TipMachine> = Network::with_config(4, config());
+        net.add_client(CLIENT_ID);
+        net.remove_node(3).unwrap().join().unwrap().unwrap();
+        for i in 1..=4 {
+            net.submit(CLIENT_ID, format!("op{}", i));
+        }
+        // 5 and 6 are above the water marks of a replica that hasn't seen 4 become stable
+        assert!(run_until(&mut net, |net| (0..3).all(|id| stable_seq(net, id) == 4)));
+        assert!(net.restart_node(3));
+        assert!(!net.restart_node(3));
+        for i in 5..=6 {
+            net.submit(CLIENT_ID, format!("op{}", i));
+        }
+        assert!(run_until(&mut net, |net| last_executed(net, 3) == 6));
+        let state = net.get_node(&3).unwrap().get_state();
+        assert_eq!(state.lock().unwrap().get_machine().get_tip(), "op6".to_owned());
+    }
+}
+
+#[cfg(test)]
+mod reconfiguration_test {
+    use crate::config::Config;
+    use crate::dto::{ID,PrePrepare,Reconfiguration};
+    use crate::error::PbftError;
+    use crate::network::Network;
+    use crate::node::Message;
+    use crate::state_machine::TipMachine;
+    use crate::test_util::{Cluster,config,tip,temp_dir,new_nodes,new_request,run_until,CLIENT_ID};
+    use crate::wal::WriteAheadLog;
+    use std::collections::HashSet;
+    use std::fs;
+    use std::sync::{Arc,RwLock};
+    use std::sync::mpsc;
+
+    #[test]
+    fn added_replica_should_catch_up_and_take_part() {
+        let mut cluster = Cluster::with_config(4, config());
+        let mut client = cluster.new_client();
+        cluster.run_request(&mut client, "op0");
+        cluster.add_node(4);
+        // ordered at seq 2, the checkpoint there already goes to the new replica
+        cluster.reconfigure(Reconfiguration::AddReplica(4));
+        for id in 0..5 {
+            let state = cluster.get_state(id);
+            assert_eq!(state.lock().unwrap().get_all_nodes(), &new_nodes(5));
+            assert_eq!(state.lock().unwrap().get_last_executed(), 2);
+        }
+        client.set_nodes(new_nodes(5));
+        // 2f+1 of 5 are 4 replicas, the new one makes up for a silent one
+        cluster.silence(3);
+        assert_eq!(cluster.run_request(&mut client, "op1"), Some("op1".to_owned()));
+        let state = cluster.get_state(4);
+        assert_eq!(state.lock().unwrap().get_machine().get_tip(), "op1".to_owned());
+        assert_eq!(state.lock().unwrap().get_last_executed(), 3);
+    }
+
+    #[test]
+    fn removed_primary_should_hand_over() {
+        let mut cluster = Cluster::with_config(5, config());
+        let mut client = cluster.new_client();
+        cluster.run_request(&mut client, "op0");
+        cluster.reconfigure(Reconfiguration::RemoveReplica(0));
+        let remaining: HashSet<ID> = (1..5).collect();
+        assert!(!cluster.get_state(0).lock().unwrap().is_member(0));
+        assert_eq!(cluster.get_state(1).lock().unwrap().get_all_nodes(), &remaining);
+        client.set_nodes(remaining);
+        assert_eq!(client.get_primary(), Some(1));
+        // 2f+1 of 4 are 3 replicas
+        cluster.silence(4);
+        assert_eq!(cluster.run_request(&mut client, "op1"), Some("op1".to_owned()));
+        assert_eq!(cluster.get_state(1).lock().unwrap().get_last_executed(), 3);
+        assert_eq!(cluster.get_state(0).lock().unwrap().get_last_executed(), 2);
+        assert_eq!(cluster.get_state(0).lock().unwrap().get_machine().get_tip(), "op0".to_owned());
+    }
+
+    #[test]
+    fn requests_should_wait_for_reconfiguration() {
+        let cluster = Cluster::with_config(4, config());
+        cluster.deliver(Message::reconfiguration(0, 0, Arc::new(RwLock::new(Reconfiguration::AddReplica(4)))));
+        cluster.deliver_only(|_| false);
+        cluster.submit(new_request("op"), 0);
+        let state = cluster.get_state(0);
+        assert_eq!(state.lock().unwrap().get_preprepares().get_reqs().len(), 1);
+        assert!(!state.lock().unwrap().flush_batch(0, mpsc::channel().0));
+    }
+
+    #[test]
+    fn unrequested_reconfiguration_should_not_be_prepared() {
+        let cluster = Cluster::with_config(4, config());
+        // a faulty primary tries to get rid of a correct replica
+        let removal = PrePrepare::reconfigure(0, 1, Reconfiguration::RemoveReplica(3), 0);
+        assert_eq!(
+            cluster.get_state(1).lock().unwrap().check_reconfiguration(&removal),
+            Err(PbftError::UnrequestedReconfiguration{seq_id: 1}));
+        cluster.broadcast_preprepare(removal);
+        cluster.deliver_all();
+        for id in 1..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_all_nodes(), &new_nodes(4));
+            assert_eq!(state.get_prepares().get_reqs().len(), 0);
+        }
+    }
+
+    #[test]
+    fn removal_below_fault_threshold_should_be_ignored() {
+        let cluster = Cluster::with_config(4, Config{fault_threshold: Some(1), ..config()});
+        // ordered and executed, but 3 replicas can't tolerate f = 1
+        cluster.reconfigure(Reconfiguration::RemoveReplica(3));
+        for id in 0..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_last_executed(), 1);
+            assert_eq!(state.get_all_nodes(), &new_nodes(4));
+        }
+        // nothing is left to order, the next request goes right after it
+        let mut client = cluster.new_client();
+        assert_eq!(cluster.run_request(&mut client, "op"), Some("op".to_owned()));
+        assert_eq!(cluster.get_state(3).lock().unwrap().get_last_executed(), 2);
+    }
+
+    #[test]
+    fn network_should_spawn_and_retire_replicas() {
+        let mut net: Network<TipMachine> = Network::with_config(4, config());
+        net.add_client(CLIENT_ID);
+        net.submit(CLIENT_ID, "op1".to_owned());
+        assert!(run_until(&mut net, |net| tip(net, 0) == Some("op1".to_owned())));
+        assert!(net.add_replica(4));
+        assert!(!net.add_replica(4));
+        net.submit(CLIENT_ID, "op2".to_owned());
+        assert!(run_until(&mut net, |net| tip(net, 4) == Some("op2".to_owned())));
+        // the removal is ordered at 4, op3 goes above it
+        assert!(net.remove_replica(0));
+        assert!(run_until(&mut net, |net| {
+            net.get_node(&0).is_none() && (1..5).all(|id| net.get_node(&id).unwrap().get_state().lock().unwrap().get_stable_seq() == 4)
+        }));
+        net.submit(CLIENT_ID, "op3".to_owned());
+        assert!(run_until(&mut net, |net| tip(net, 1) == Some("op3".to_owned())));
+        assert_eq!(net.get_client(&CLIENT_ID).unwrap().get_primary(), Some(1));
+    }
+
+    #[test]
+    fn failed_addition_should_leave_nothing_behind() {
+        let dir = temp_dir("add-replica-fails");
+        let mut net: Network<TipMachine> = Network::with_config(4, Config{wal_dir: Some(dir.clone()), ..config()});
+        // the new replica can't open a log that is a directory
+        let path = WriteAheadLog::path(&dir, 4);
+        fs::create_dir(&path).unwrap();
+        assert!(!net.add_replica(4));
+        assert_eq!(net.get_all_nodes(), &new_nodes(4));
+        assert!(net.get_signer(4).is_none());
+        fs::remove_dir(&path).unwrap();
+        assert!(net.add_replica(4));
+        assert_eq!(net.get_all_nodes(), &new_nodes(5));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod recovery_test {
+    use crate::config::Config;
+    use crate::network::Network;
+    use crate::node::StateSnapshot;
+    use crate::state_machine::TipMachine;
+    use crate::test_util::{Cluster,config,tip,run_until,CLIENT_ID};
+    use std::time::Duration;
+
+    #[test]
+    fn recovered_replica_should_reload_checkpoint_and_catch_up() {
+        let mut cluster = Cluster::with_config(4, config());
+        let mut client = cluster.new_client();
+        for i in 0..3 {
+            cluster.run_request(&mut client, &format!("op{}", i));
+        }
+        let checkpoint = cluster.get_state(3).lock().unwrap().get_stable_checkpoint();
+        assert_eq!(checkpoint.as_ref().map(|(seq_id, _, _)| *seq_id), Some(2));
+        cluster.recover(3, checkpoint);
+        let state = cluster.get_state(3);
+        assert_eq!(state.lock().unwrap().get_last_executed(), 3);
+        assert_eq!(state.lock().unwrap().get_machine().get_tip(), "op2".to_owned());
+        // recovering until the checkpoint at 4 is stable
+        assert!(state.lock().unwrap().is_recovering());
+        cluster.silence(2);
+        assert_eq!(cluster.run_request(&mut client, "op3"), Some("op3".to_owned()));
+        assert!(!state.lock().unwrap().is_recovering());
+    }
+
+    #[test]
+    fn corrupted_checkpoint_should_not_be_reloaded() {
+        let mut cluster = Cluster::with_config(4, config());
+        let mut client = cluster.new_client();
+        for i in 0..3 {
+            cluster.run_request(&mut client, &format!("op{}", i));
+        }
+        let checkpoint = cluster.get_state(3).lock().unwrap().get_stable_checkpoint()
+            .map(|(seq_id, proof, snapshot)| (seq_id, proof, StateSnapshot::new("forged".to_owned(), snapshot.get_replies().clone())));
+        // nothing reaches the replica, it stays at genesis rather than trusting the snapshot
+        cluster.silence(3);
+        cluster.recover(3, checkpoint);
+        let state = cluster.get_state(3);
+        assert!(state.lock().unwrap().is_recovering());
+        assert_eq!(state.lock().unwrap().get_machine().get_tip(), "genesis".to_owned());
+        assert_eq!(state.lock().unwrap().get_last_executed(), 0);
+    }
+
+    #[test]
+    fn network_should_recover_at_most_f_at_a_time() {
+        let mut net: Network<TipMachine> = Network::with_config(4, config());
+        net.add_client(CLIENT_ID);
+        for i in 1..=3 {
+            net.submit(CLIENT_ID, format!("op{}", i));
+        }
+        assert!(run_until(&mut net, |net| tip(net, 2) == Some("op3".to_owned())));
+        assert!(net.schedule_recovery(1));
+        assert!(net.schedule_recovery(2));
+        assert!(!net.schedule_recovery(2));
+        // a recovery is over once the next checkpoint is stable, that takes requests.
+        // A recovering replica may miss some of them until its state transfer is through
+        let mut count = 3;
+        while net.get_recovering().len() + net.get_recovery_queue().count() > 0 && count < 20 {
+            count += 1;
+            let op = format!("op{}", count);
+            net.submit(CLIENT_ID, op.clone());
+            let done = run_until(&mut net, |net| {
+                assert!(net.get_recovering().len() <= 1);
+                (0..4).all(|id| net.get_recovering().contains(&id) || tip(net, id) == Some(op.clone()))
+            });
+            assert!(done);
+        }
+        assert!(count < 20);
+        // both came back with new keys the others accept
+        net.remove_node(3).unwrap().join().unwrap().unwrap();
+        net.submit(CLIENT_ID, "last".to_owned());
+        assert!(run_until(&mut net, |net| (0..3).all(|id| tip(net, id) == Some("last".to_owned()))));
+    }
+
+    #[test]
+    fn recovery_round_should_queue_every_replica() {
+        let mut net: Network<TipMachine> = Network::with_config(4, Config{recovery_period: Some(Duration::from_millis(0)), ..config()});
+        net.queue_update();
+        assert_eq!(net.get_recovering().len(), 1);
+        assert_eq!(net.get_recovery_queue().count(), 3);
+    }
+}
+
+#[cfg(test)]
+mod exactly_once_test {
+    use crate::dto::{PrePrepare,Request};
+    use crate::network::Network;
+    use crate::state_machine::TipMachine;
+    use crate::test_util::{Cluster,config,tip,run_until,CLIENT_ID};
+
+    fn tips(cluster: &Cluster<TipMachine>) -> Vec<String> {
+        (0..4).map(|id| cluster.get_state(id).lock().unwrap().get_machine().get_tip()).collect()
+    }
+
+    #[test]
+    fn retry_should_get_cached_reply() {
+        let cluster = Cluster::new(4);
+        let mut client = cluster.new_client();
+        cluster.run_request(&mut client, "a");
+        cluster.run_request(&mut client, "b");
+        cluster.take_replies();
+        // "b" again: nothing executes, every replica answers from its cache
+        for id in 0..4 {
+            cluster.submit(Request::new("b".to_owned(), 2, CLIENT_ID), id);
+        }
+        cluster.deliver_all();
+        let replies = cluster.take_replies();
+        assert_eq!(replies.len(), 4);
+        assert!(replies.iter().all(|reply| reply.get_timestamp() == 2 && reply.get_result() == "b"));
+        assert_eq!(cluster.get_state(0).lock().unwrap().get_last_executed(), 2);
+        // "a" is older than the cached reply, it is dropped
+        cluster.submit(Request::new("a".to_owned(), 1, CLIENT_ID), 0);
+        cluster.deliver_all();
+        assert_eq!(cluster.take_replies().len(), 0);
+        assert_eq!(tips(&cluster), vec!["b".to_owned(); 4]);
+    }
+
+    #[test]
+    fn request_ordered_twice_should_execute_once() {
+        let cluster = Cluster::new(4);
+        let a = Request::new("a".to_owned(), 1, CLIENT_ID);
+        let b = Request::new("b".to_owned(), 2, CLIENT_ID);
+        // e.g. a retry that reached the primary before the original executed
+        cluster.broadcast_preprepare(PrePrepare::new(0, 1, vec![a.clone(), a.clone()], 0));
+        cluster.broadcast_preprepare(PrePrepare::new(0, 2, vec![b], 0));
+        cluster.broadcast_preprepare(PrePrepare::new(0, 3, vec![a], 0));
+        cluster.deliver_all();
+        for id in 1..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_last_executed(), 3);
+            assert_eq!(state.get_machine().get_tip(), "b".to_owned());
+            assert_eq!(state.get_last_replies().get(&CLIENT_ID).map(|reply| reply.get_timestamp()), Some(2));
+        }
+    }
+
+    #[test]
+    fn client_retries_should_not_double_apply() {
+        let mut net: Network<TipMachine> = Network::with_config(4, config());
+        net.add_client(CLIENT_ID);
+        let first = net.submit(CLIENT_ID, "a".to_owned()).unwrap();
+        assert!(net.retry(CLIENT_ID, first));
+        assert!(run_until(&mut net, |net| net.get_client(&CLIENT_ID).unwrap().get_result(first).is_some()));
+        assert!(!net.retry(CLIENT_ID, first));
+        let second = net.submit(CLIENT_ID, "b".to_owned()).unwrap();
+        assert!(run_until(&mut net, |net| {
+            net.get_client(&CLIENT_ID).unwrap().get_result(second).is_some() && (0..4).all(|id| tip(net, id) == Some("b".to_owned()))
+        }));
+    }
+}
+
+#[cfg(test)]
+mod liveness_test {
+    use crate::config::Config;
+    use crate::dto::{ID,Request};
+    use crate::network::Network;
+    use crate::state_machine::TipMachine;
+    use crate::test_util::{Cluster,run_until,CLIENT_ID};
+    use std::time::Duration;
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    fn config() -> Config {
+        Config{view_change_timeout: Some(TIMEOUT), ..Config::default()}
+    }
+
+    fn views(cluster: &Cluster<TipMachine>, ids: &[ID]) -> Vec<(ID, bool)> {
+        ids.iter()
+            .map(|id| {
+                let state = cluster.get_state(*id);
+                let state = state.lock().unwrap();
+                (state.get_view_id(), state.is_view_active())
+            })
+            .collect()
+    }
+
+    fn highest_view(net: &Network<TipMachine>) -> ID {
+        net.get_statuses().map(|(_, state)| state.lock().unwrap().get_view_id()).max().unwrap_or(0)
+    }
+
+    fn submit_to_backups(cluster: &Cluster<TipMachine>, request: &Request<String>, backups: &[ID]) {
+        for id in backups {
+            cluster.submit(request.clone(), *id);
+        }
+        cluster.deliver_all();
+    }
+
+    #[test]
+    fn idle_backups_should_not_suspect_the_primary() {
+        let mut cluster = Cluster::with_config(4, config());
+        cluster.silence(0);
+        assert!(!cluster.expire_timers(TIMEOUT));
+        assert_eq!(views(&cluster, &[1, 2, 3]), vec![(0, true); 3]);
+    }
+
+    #[test]
+    fn request_that_doesnt_execute_should_start_view_change() {
+        let mut cluster = Cluster::with_config(4, config());
+        cluster.silence(0);
+        let request = Request::new("op".to_owned(), 1, CLIENT_ID);
+        submit_to_backups(&cluster, &request, &[1, 2, 3]);
+        assert!(!cluster.expire_timers(TIMEOUT / 2));
+        assert_eq!(views(&cluster, &[1, 2, 3]), vec![(0, true); 3]);
+        assert!(cluster.expire_timers(TIMEOUT));
+        assert_eq!(views(&cluster, &[1, 2, 3]), vec![(1, true); 3]);
+        // the client retries with the new primary, executing it stops the timer
+        cluster.submit(request, 1);
+        cluster.deliver_all();
+        for id in 1..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_machine().get_tip(), "op".to_owned());
+            assert!(!state.is_timer_running());
+            assert_eq!(state.get_timeout(), TIMEOUT);
+        }
+    }
+
+    #[test]
+    fn successive_view_changes_should_back_off() {
+        let mut cluster = Cluster::with_config(7, config());
+        // the primaries of views 0 and 1 are both down
+        cluster.silence(0);
+        cluster.silence(1);
+        let backups = [2, 3, 4, 5, 6];
+        let request = Request::new("op".to_owned(), 1, CLIENT_ID);
+        submit_to_backups(&cluster, &request, &backups);
+        assert!(cluster.expire_timers(TIMEOUT));
+        assert_eq!(views(&cluster, &backups), vec![(1, false); 5]);
+        // view 1 gets twice as long to come up
+        assert!(!cluster.expire_timers(TIMEOUT));
+        assert!(cluster.expire_timers(TIMEOUT * 2));
+        assert_eq!(views(&cluster, &backups), vec![(2, true); 5]);
+        assert_eq!(cluster.get_state(3).lock().unwrap().get_timeout(), TIMEOUT * 4);
+        cluster.submit(request, 2);
+        cluster.deliver_all();
+        assert_eq!(cluster.get_state(3).lock().unwrap().get_timeout(), TIMEOUT);
+    }
+
+    #[test]
+    fn disabled_timer_should_never_expire() {
+        let mut cluster = Cluster::with_config(4, Config{view_change_timeout: None, ..Config::default()});
+        cluster.silence(0);
+        submit_to_backups(&cluster, &Request::new("op".to_owned(), 1, CLIENT_ID), &[1, 2, 3]);
+        assert!(!cluster.expire_timers(Duration::from_secs(3600)));
+        assert_eq!(views(&cluster, &[1, 2, 3]), vec![(0, true); 3]);
+    }
+
+    #[test]
+    fn network_should_replace_silent_primary() {
+        let mut net: Network<TipMachine> = Network::with_config(4, Config{view_change_timeout: Some(Duration::from_millis(50)), ..Config::default()});
+        net.add_client(CLIENT_ID);
+        net.remove_node(0).unwrap().join().unwrap().unwrap();
+        let timestamp = net.submit(CLIENT_ID, "op".to_owned()).unwrap();
+        // nobody hears back from the primary, the client asks every replica again
+        // whenever their timers moved them on to another view
+        let mut attempts = 0;
+        while net.retry(CLIENT_ID, timestamp) && attempts < 20 {
+            attempts += 1;
+            let view_id = highest_view(&net);
+            run_until(&mut net, |net| net.get_client(&CLIENT_ID).unwrap().get_result(timestamp).is_some() || highest_view(net) > view_id);
+        }
+        assert_eq!(net.get_client(&CLIENT_ID).unwrap().get_result(timestamp), Some("op".to_owned()));
+        for id in 1..4 {
+            assert!(net.get_node(&id).unwrap().get_state().lock().unwrap().get_view_id() >= 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod weighted_quorum_test {
+    use crate::config::Config;
+    use crate::dto::ID;
+    use crate::test_util::Cluster;
+
+    // 0 holds 5 of the 8 votes: it and any other replica make a quorum
+    fn config() -> Config {
+        Config{voting_weights: Some(vec![(0 as ID, 5)].into_iter().collect()), ..Config::default()}
+    }
+
+    #[test]
+    fn heavy_replicas_should_commit_without_head_count_quorum() {
+        let mut cluster = Cluster::with_config(4, config());
+        let mut client = cluster.new_client();
+        cluster.silence(2);
+        cluster.silence(3);
+        assert_eq!(cluster.run_request(&mut client, "op"), Some("op".to_owned()));
+        for id in 0..2 {
+            assert_eq!(cluster.get_state(id).lock().unwrap().get_last_executed(), 1);
+        }
+    }
+
+    #[test]
+    fn light_replicas_should_not_commit_on_their_own() {
+        let mut cluster = Cluster::with_config(4, config());
+        cluster.silence(0);
+        for id in 1..4 {
+            cluster.suspect_primary(id);
+        }
+        cluster.deliver_all();
+        // three heads of four, but only 3 of 8 votes
+        for id in 1..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_view_id(), 1);
+            assert!(!state.is_view_active());
+        }
+    }
+
+    #[test]
+    fn light_replicas_should_not_drag_others_into_view_change() {
+        let cluster = Cluster::with_config(4, config());
+        cluster.suspect_primary(1);
+        cluster.suspect_primary(2);
+        cluster.deliver_all();
+        // f+1 heads, but 2 of 8 votes could all be faulty
+        for id in [0, 3].iter() {
+            let state = cluster.get_state(*id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_view_id(), 0);
+            assert!(state.is_view_active());
+        }
+    }
+}
+
+#[cfg(test)]
+mod fault_threshold_test {
+    use crate::config::Config;
+    use crate::network::Network;
+    use crate::state_machine::TipMachine;
+    use crate::test_util::Cluster;
+    use std::time::Duration;
+
+    // seven replicas that only promise to survive one fault: quorums of 5
+    fn config() -> Config {
+        Config{fault_threshold: Some(1), ..Config::default()}
+    }
+
+    #[test]
+    fn non_tight_configuration_should_commit_with_quorum() {
+        let mut cluster = Cluster::with_config(7, config());
+        let mut client = cluster.new_client();
+        cluster.silence(5);
+        cluster.silence(6);
+        assert_eq!(cluster.run_request(&mut client, "op"), Some("op".to_owned()));
+        cluster.silence(4);
+        cluster.run_request(&mut client, "lost");
+        for id in 0..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_last_executed(), 1);
+            assert_eq!(state.get_machine().get_tip(), "op".to_owned());
+        }
+    }
+
+    #[test]
+    fn f_plus_one_suspicions_should_follow_explicit_f() {
+        let mut cluster = Cluster::with_config(7, config());
+        cluster.silence(0);
+        // f+1 = 2 rather than the 3 that 7 replicas could tolerate
+        cluster.suspect_primary(1);
+        cluster.suspect_primary(2);
+        cluster.deliver_all();
+        for id in 1..7 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_view_id(), 1);
+            assert!(state.is_view_active());
+        }
+    }
+
+    #[test]
+    fn recovery_should_follow_explicit_f() {
+        let mut net: Network<TipMachine> = Network::with_config(7, Config{recovery_period: Some(Duration::from_millis(0)), ..config()});
+        net.queue_update();
+        assert_eq!(net.get_recovering().len(), 1);
+        assert_eq!(net.get_recovery_queue().count(), 6);
+    }
+}
+
+#[cfg(test)]
+mod rejection_test {
+    use crate::dto::{NewView,PrePrepare,Prepare,Request};
+    use crate::error::PbftError;
+    use crate::node::Message;
+    use crate::test_util::{Cluster,new_request,CLIENT_ID};
+    use std::sync::{Arc,RwLock};
+
+    #[test]
+    fn unsigned_prepare_should_be_rejected() {
+        let cluster = Cluster::new(4);
+        let prepare = Prepare::new(0, 1, "digest".to_owned(), 2);
+        assert_eq!(
+            cluster.try_deliver(Message::prepare(2, 1, Arc::new(RwLock::new(prepare)))),
+            Err(PbftError::InvalidSignature{sender_id: 2}));
+    }
+
+    #[test]
+    fn prepare_without_preprepare_should_wait_for_approvers() {
+        let cluster = Cluster::new(4);
+        let prepare = cluster.sign(2, Prepare::new(0, 1, "digest".to_owned(), 2));
+        assert_eq!(
+            cluster.try_deliver(Message::prepare(2, 1, Arc::new(RwLock::new(prepare)))),
+            Err(PbftError::InsufficientApprovers));
+    }
+
+    #[test]
+    fn prepare_beyond_log_should_name_the_water_marks() {
+        let cluster = Cluster::new(4);
+        let prepare = cluster.sign(2, Prepare::new(0, 500, "digest".to_owned(), 2));
+        assert_eq!(
+            cluster.try_deliver(Message::prepare(2, 1, Arc::new(RwLock::new(prepare)))),
+            Err(PbftError::OutsideWaterMarks{seq_id: 500, low: 0, high: 200}));
+    }
+
+    #[test]
+    fn preprepare_from_backup_should_name_the_primary() {
+        let cluster = Cluster::new(4);
+        let preprepare = cluster.sign(1, PrePrepare::new(0, 1, vec![new_request("forged")], 1));
+        assert_eq!(
+            cluster.try_deliver(Message::preprepare(1, 2, Arc::new(RwLock::new(preprepare)))),
+            Err(PbftError::NotPrimary{view_id: 0, sender_id: 1, primary_id: Some(0)}));
+    }
+
+    #[test]
+    fn new_view_for_current_view_should_be_stale() {
+        let cluster = Cluster::new(4);
+        let new_view = cluster.sign(0, NewView::<String>::new(0, vec![], vec![], 0));
+        assert_eq!(
+            cluster.try_deliver(Message::new_view(0, 1, Arc::new(RwLock::new(new_view)))),
+            Err(PbftError::StaleView{view_id: 0, current_view_id: 0}));
+    }
+
+    #[test]
+    fn requests_should_be_rejected_with_their_reason() {
+        let mut cluster = Cluster::new(4);
+        let request = |op: &str, timestamp| Arc::new(RwLock::new(Request::new(op.to_owned(), timestamp, CLIENT_ID)));
+        // nothing ordered yet: the same request twice is one too many
+        assert_eq!(cluster.try_deliver(Message::request(CLIENT_ID, 0, request("a", 1))), Ok(()));
+        assert_eq!(
+            cluster.try_deliver(Message::request(CLIENT_ID, 0, request("a", 1))),
+            Err(PbftError::DuplicateRequest{client_id: CLIENT_ID, timestamp: 1}));
+        cluster.submit(Request::new("b".to_owned(), 2, CLIENT_ID), 0);
+        cluster.deliver_all();
+        assert_eq!(
+            cluster.try_deliver(Message::request(CLIENT_ID, 0, request("a", 1))),
+            Err(PbftError::StaleTimestamp{client_id: CLIENT_ID, timestamp: 1}));
+        cluster.silence(0);
+        for id in 1..4 {
+            cluster.suspect_primary(id);
+        }
+        cluster.silence(3);
+        cluster.deliver_all();
+        // view 1 never comes up without 2f+1 view changes
+        assert_eq!(
+            cluster.try_deliver(Message::request(CLIENT_ID, 1, request("c", 3))),
+            Err(PbftError::ViewNotActive{view_id: 1}));
+    }
+}
+
+#[cfg(test)]
+mod message_test {
+    use crate::dto::{Prepare,Shutdown};
+    use crate::error::PbftError;
+    use crate::node::{Message,Payload};
+    use crate::state_machine::TipMachine;
+    use crate::test_util::{Cluster,new_request,CLIENT_ID};
+    use std::sync::{Arc,RwLock};
+
+    #[test]
+    fn payload_should_tell_signed_messages_apart() {
+        let cluster = Cluster::new(4);
+        let prepare = cluster.sign(2, Prepare::new(0, 1, "digest".to_owned(), 2));
+        let message: Message<TipMachine> = Message::prepare(2, 1, Arc::new(RwLock::new(prepare)));
+        assert!(matches!(message.get_payload(), Payload::Prepare(_)));
+        assert_eq!(message.get_signed_bytes().map(|(signer_id, _)| signer_id), Some(2));
+        let request: Message<TipMachine> = Message::request(CLIENT_ID, 1, Arc::new(RwLock::new(new_request("op"))));
+        assert_eq!(request.get_signed_bytes(), None);
+        assert!(request.get_reply().is_none());
+        let shutdown: Message<TipMachine> = Message::shutdown(0, 1, Arc::new(RwLock::new(Shutdown{})));
+        assert!(shutdown.is_shutdown());
+    }
+
+    #[test]
+    fn replica_should_reject_what_isnt_for_replicas() {
+        let cluster = Cluster::new(4);
+        let reply = new_request("op").make_reply(0, 1, "op".to_owned());
+        let message: Message<TipMachine> = Message::reply(0, 1, Arc::new(RwLock::new(reply)));
+        assert!(message.get_reply().is_some());
+        // the node loop stops on a shutdown before the state ever sees it
+        let shutdown = Message::shutdown(0, 1, Arc::new(RwLock::new(Shutdown{})));
+        assert_eq!(cluster.try_deliver(shutdown), Err(PbftError::UnknownMessage));
+    }
+}
+
+#[cfg(test)]
+mod write_ahead_log_test {
+    use crate::config::Config;
+    use crate::dto::{PrePrepare,Request,Reconfiguration,NodeRequest};
+    use crate::error::PbftError;
+    use crate::node::{Message,Payload};
+    use crate::network::Network;
+    use crate::state_machine::TipMachine;
+    use crate::test_util::{Cluster,config,temp_dir,new_request,run_until,CLIENT_ID};
+    use crate::wal::WriteAheadLog;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::{Arc,RwLock};
+
+    fn logged(dir: &Path) -> Config {
+        Config{wal_dir: Some(dir.to_path_buf()), ..Config::default()}
+    }
+
+    fn equivocate(cluster: &Cluster<TipMachine>, target: u64) -> Result<(), PbftError> {
+        let preprepare = cluster.sign(0, PrePrepare::new(0, 1, vec![new_request("second")], 0));
+        cluster.try_deliver(Message::preprepare(0, target, Arc::new(RwLock::new(preprepare))))
+    }
+
+    #[test]
+    fn restarted_backup_should_not_prepare_twice() {
+        let dir = temp_dir("restart-backup");
+        let mut cluster = Cluster::with_config(4, logged(&dir));
+        cluster.broadcast_preprepare(PrePrepare::new(0, 1, vec![new_request("first")], 0));
+        cluster.deliver_all();
+        cluster.restart(1);
+        {
+            let state = cluster.get_state(1);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_last_executed(), 1);
+            assert_eq!(state.get_machine().get_tip(), "first".to_owned());
+            let slot = state.get_slots().get(0, 1).unwrap();
+            assert!(slot.has_sent_prepare());
+            assert!(slot.has_sent_commit());
+        }
+        assert_eq!(equivocate(&cluster, 1), Err(PbftError::ConflictingPrePrepare{view_id: 0, seq_id: 1}));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restarted_backup_without_log_should_forget() {
+        let mut cluster = Cluster::new(4);
+        cluster.broadcast_preprepare(PrePrepare::new(0, 1, vec![new_request("first")], 0));
+        cluster.deliver_all();
+        cluster.restart(1);
+        assert_eq!(cluster.get_state(1).lock().unwrap().get_last_executed(), 0);
+        assert_eq!(equivocate(&cluster, 1), Ok(()));
+    }
+
+    #[test]
+    fn restarted_primary_should_not_reuse_seqs() {
+        let dir = temp_dir("restart-primary");
+        let mut cluster = Cluster::with_config(4, logged(&dir));
+        cluster.submit(Request::new("first".to_owned(), 1, CLIENT_ID), 0);
+        cluster.deliver_all();
+        cluster.restart(0);
+        cluster.submit(Request::new("second".to_owned(), 2, CLIENT_ID), 0);
+        cluster.deliver_all();
+        for id in 0..4 {
+            let state = cluster.get_state(id);
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_last_executed(), 2);
+            assert_eq!(state.get_machine().get_tip(), "second".to_owned());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restarted_replica_should_stay_in_the_view_it_moved_to() {
+        let dir = temp_dir("restart-view-change");
+        let mut cluster = Cluster::with_config(4, logged(&dir));
+        cluster.silence(0);
+        cluster.suspect_primary(1);
+        cluster.deliver_all();
+        cluster.restart(1);
+        let state = cluster.get_state(1);
+        let state = state.lock().unwrap();
+        assert_eq!(state.get_view_id(), 1);
+        assert!(!state.is_view_active());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restarted_backup_should_remember_requested_reconfigurations() {
+        let dir = temp_dir("restart-reconfiguration");
+        let mut cluster = Cluster::with_config(4, logged(&dir));
+        cluster.deliver(Message::reconfiguration(1, 1, Arc::new(RwLock::new(Reconfiguration::AddReplica(4)))));
+        cluster.restart(1);
+        let addition = PrePrepare::reconfigure(0, 1, Reconfiguration::AddReplica(4), 0);
+        assert_eq!(cluster.get_state(1).lock().unwrap().check_reconfiguration(&addition), Ok(()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restarted_backup_behind_stable_checkpoint_should_fetch_state() {
+        let dir = temp_dir("restart-behind");
+        let mut cluster = Cluster::with_config(4, Config{wal_dir: Some(dir.clone()), ..config()});
+        let mut client = cluster.new_client();
+        cluster.silence(3);
+        cluster.run_request(&mut client, "op1");
+        cluster.run_request(&mut client, "op2");
+        cluster.restore(3);
+        // 3 learns the checkpoint is stable, but crashes before its fetch goes out
+        let (_, proof, _) = cluster.get_state(0).lock().unwrap().get_stable_checkpoint().unwrap();
+        for checkpoint in proof {
+            let sender_id = checkpoint.get_sender_id();
+            cluster.deliver(Message::checkpoint(sender_id, 3, Arc::new(RwLock::new(checkpoint))));
+        }
+        cluster.deliver_only(|_| false);
+        cluster.restart(3);
+        cluster.deliver_all();
+        let state = cluster.get_state(3);
+        let state = state.lock().unwrap();
+        assert_eq!(state.get_last_executed(), 2);
+        assert_eq!(state.get_machine().get_tip(), "op2".to_owned());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn log_should_start_from_stable_checkpoint() {
+        let dir = temp_dir("restart-compacted");
+        let mut cluster = Cluster::with_config(4, Config{wal_dir: Some(dir.clone()), ..config()});
+        let mut client = cluster.new_client();
+        for op in &["op1", "op2", "op3", "op4", "op5"] {
+            cluster.run_request(&mut client, op);
+        }
+        let (_, checkpoint, entries) = WriteAheadLog::open::<TipMachine>(&dir, 1).unwrap();
+        let (seq_id, proof, _) = checkpoint.unwrap();
+        assert_eq!(seq_id, 4);
+        assert!(!proof.is_empty());
+        for entry in &entries {
+            match entry.get_payload() {
+                Payload::PrePrepare(m) => assert_eq!(m.read().unwrap().get_seq_id(), 5),
+                Payload::Prepare(m) => assert_eq!(m.read().unwrap().get_seq_id(), 5),
+                Payload::Commit(m) => assert_eq!(m.read().unwrap().get_seq_id(), 5),
+                // a vote for the stable checkpoint that came in after it was stable
+                Payload::Checkpoint(m) => assert_eq!(m.read().unwrap().get_seq_id(), 4),
+                other => panic!("{:?}", other),
+            }
+        }
+        cluster.restart(1);
+        let state = cluster.get_state(1);
+        let state = state.lock().unwrap();
+        assert_eq!(state.get_stable_seq(), 4);
+        assert_eq!(state.get_last_executed(), 5);
+        assert_eq!(state.get_machine().get_tip(), "op5".to_owned());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn respawned_node_should_replay_its_log() {
+        let dir = temp_dir("respawn");
+        let mut net: Network<TipMachine> = Network::with_config(4, logged(&dir));
+        net.add_client(CLIENT_ID);
+        net.submit(CLIENT_ID, "first".to_owned());
+        let executed = |net: &Network<TipMachine>| net.get_node(&1).unwrap().get_state().lock().unwrap().get_last_executed() == 1;
+        assert!(run_until(&mut net, executed));
+        net.remove_node(1).unwrap().join().unwrap().unwrap();
+        assert!(net.restart_node(1));
+        // back before it took a single message
+        {
+            let state = net.get_node(&1).unwrap().get_state();
+            let state = state.lock().unwrap();
+            assert_eq!(state.get_last_executed(), 1);
+            assert_eq!(state.get_machine().get_tip(), "first".to_owned());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unreadable_log_should_keep_node_down() {
+        let dir = temp_dir("respawn-corrupt");
+        let mut net: Network<TipMachine> = Network::with_config(4, logged(&dir));
+        net.remove_node(1).unwrap().join().unwrap().unwrap();
+        fs::write(WriteAheadLog::path(&dir, 1), [0, 0, 0, 0, 0, 0, 0, 2, 7, 7]).unwrap();
+        assert!(!net.restart_node(1));
+        assert!(net.get_node(&1).is_none());
+        // nor does a network start from it
+        assert_eq!(Network::<TipMachine>::try_with_config(4, logged(&dir)).err(), Some(PbftError::CorruptLog{offset: 0}));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recovery_should_not_rekey_node_whose_log_stays() {
+        let dir = temp_dir("recover-stuck-log");
+        let mut net: Network<TipMachine> = Network::with_config(4, logged(&dir));
+        let signer = net.get_signer(1).unwrap();
+        // a directory in place of the log can't be removed like a file, nor read
+        let path = WriteAheadLog::path(&dir, 1);
+        fs::remove_file(&path).unwrap();
+        fs::create_dir(&path).unwrap();
+        assert!(net.schedule_recovery(1));
+        net.queue_update();
+        assert!(net.get_recovering().is_empty());
+        assert!(net.get_node(&1).is_none());
+        assert_eq!(net.get_signer(1).unwrap().sign(b"key"), signer.sign(b"key"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recovering_node_should_be_stopped_before_its_log_goes() {
+        let dir = temp_dir("recover-stopped");
+        let mut net: Network<TipMachine> = Network::with_config(4, logged(&dir));
+        assert!(net.schedule_recovery(1));
+        net.queue_update();
+        assert!(net.get_recovering().contains(&1));
+        // the log the recovered replica writes starts over, nothing of the old one came back
+        let (_, checkpoint, entries) = WriteAheadLog::open::<TipMachine>(&dir, 1).unwrap();
+        assert!(checkpoint.is_none());
+        assert!(entries.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -3,17 +3,20 @@ use std::collections::{HashMap,HashSet};
 use std::option::Option;
 use crate::dto::{ID,Digest,NodeRequest};
 use crate::util::{ensure_hm_val,convert_err};
-use crate::sufficiency::SufficiencyChecker;
+use crate::error::PbftError;
+use crate::sufficiency::{SufficiencyChecker,SufficiencyFn};
 
 pub type ViewID = ID;
 pub type SeqID = ID;
 pub type NodeID = ID;
+pub type Approvers<M> = HashMap<NodeID, Arc<RwLock<M>>>; // who sent a message with the same (n, v, d)
+type Table<M> = HashMap<SeqID, HashMap<ViewID, HashMap<Digest, Approvers<M>>>>;
 
 // Debug can't print functions: https://stackoverflow.com/a/52030021/2159808
 pub struct RequestTable<M: NodeRequest> {
     // Arc need: Ms are shared between nodes
-    reqs: HashMap<SeqID, HashMap<ViewID, HashMap<Digest, HashMap<NodeID, Arc<RwLock<M>>>>>>,
-    check_sufficiency: SufficiencyChecker,
+    reqs: Table<M>,
+    check_sufficiency: Arc<dyn SufficiencyChecker>,
 }
 
 impl <M> std::fmt::Debug for RequestTable<M>
@@ -26,48 +29,52 @@ where M: NodeRequest + std::fmt::Debug {
 }
 
 impl <M>RequestTable<M> where M: NodeRequest {
-    pub fn new(sufficiency_fn: SufficiencyChecker) -> RequestTable<M> {
+    pub fn new(sufficiency_fn: SufficiencyFn) -> RequestTable<M> {
+        RequestTable::with_checker(Arc::new(sufficiency_fn))
+    }
+
+    pub fn with_checker(checker: Arc<dyn SufficiencyChecker>) -> RequestTable<M> {
         RequestTable{
             reqs: HashMap::new(),
-            check_sufficiency: sufficiency_fn,
+            check_sufficiency: checker,
         }
     }
 
     pub fn is_sufficient<N>(&self, ri: &N, all_nodes: &HashSet<ID>) -> bool
     where N: NodeRequest {
-        (&self.check_sufficiency)(all_nodes, &self.find_approvers(ri))
+        self.check_sufficiency.is_sufficient(all_nodes, &self.find_approvers(ri))
     }
 
     // Unit test backdoor
     #[cfg(test)]
-    pub fn get_reqs(&self) -> &HashMap<SeqID, HashMap<ViewID, HashMap<Digest, HashMap<NodeID, Arc<RwLock<M>>>>>> {
+    pub fn get_reqs(&self) -> &Table<M> {
         &self.reqs
     }
 
-    fn find_approvers<N>(&self, message: &N) -> HashSet<ID>
+    pub fn find_approvers<N>(&self, message: &N) -> HashSet<ID>
     where N: NodeRequest {
         match self.get_approvers(message) {
             Some(approvers) => {
-                approvers.iter().map(|(k, _)| *k).collect()
+                approvers.keys().copied().collect()
             }
             None => HashSet::new(),
         }
     }
 
-    pub fn append<'a>(&mut self, rwarc: Arc<RwLock<M>>) -> Result<(),String> {
+    pub fn append(&mut self, rwarc: Arc<RwLock<M>>) -> Result<(), PbftError> {
         convert_err(rwarc.read()).map(|_m| {
-            let m: &M = &*_m;
-            ensure_hm_val(&mut self.reqs, m.get_seq_id(), || HashMap::new());
-            let mut in_seq = self.reqs.get_mut(&m.get_seq_id()).unwrap();
-            ensure_hm_val(&mut in_seq, m.get_view_id(), || HashMap::new());
-            let mut in_view = in_seq.get_mut(&m.get_view_id()).unwrap();
-            ensure_hm_val(&mut in_view, m.get_digest(), || HashMap::new());
-            let in_digest: &mut HashMap<NodeID, Arc<RwLock<M>>> = in_view.get_mut(&m.get_digest()).unwrap();
+            let m: &M = &_m;
+            ensure_hm_val(&mut self.reqs, m.get_seq_id(), HashMap::new);
+            let in_seq = self.reqs.get_mut(&m.get_seq_id()).unwrap();
+            ensure_hm_val(in_seq, m.get_view_id(), HashMap::new);
+            let in_view = in_seq.get_mut(&m.get_view_id()).unwrap();
+            ensure_hm_val(in_view, m.get_digest(), HashMap::new);
+            let in_digest: &mut Approvers<M> = in_view.get_mut(&m.get_digest()).unwrap();
             in_digest.insert(m.get_sender_id(), rwarc.clone());
         })
     }
 
-    fn get_approvers<N>(&self, ri: &N) -> Option<&HashMap<ID, Arc<RwLock<M>>>>
+    fn get_approvers<N>(&self, ri: &N) -> Option<&Approvers<M>>
     where N: NodeRequest {
         self.reqs.get(&ri.get_seq_id())
             .map(|views| views.get(&ri.get_view_id()))
@@ -76,19 +83,35 @@ impl <M>RequestTable<M> where M: NodeRequest {
             .unwrap_or(Option::None)
     }
 
-    fn get_by_arc<N>(&self, rw: Arc<RwLock<N>>) -> Result<Option<&HashMap<ID, Arc<RwLock<M>>>>, String>
+    fn get_by_arc<N>(&self, rw: Arc<RwLock<N>>) -> Result<Option<&Approvers<M>>, PbftError>
     where N: NodeRequest {
         convert_err(rw.read()).map(|m| self.get_approvers(&*m))
     }
 
     pub fn find<N>(&self, message: &N) -> Option<&Arc<RwLock<M>>>
     where N: NodeRequest {
-        let maybe_approvers = self.get_approvers(message);
-        if maybe_approvers.is_none() {
-            return Option::None;
+        self.get_approvers(message)?.values().next()
+    }
+
+    // Every stored message that matches the (seq, view, digest) of the given one
+    pub fn find_all<N>(&self, message: &N) -> Vec<Arc<RwLock<M>>>
+    where N: NodeRequest {
+        match self.get_approvers(message) {
+            Some(approvers) => approvers.values().cloned().collect(),
+            None => Vec::new(),
         }
-        let approvers = maybe_approvers.unwrap();
-        approvers.values().next()
+    }
+
+    // Garbage collection: forget everything at or below seq_id
+    pub fn discard_through(&mut self, seq_id: SeqID) {
+        self.reqs.retain(|seq, _| *seq > seq_id)
+    }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = &'a Arc<RwLock<M>>> + 'a {
+        self.reqs.values()
+            .flat_map(|views| views.values())
+            .flat_map(|digests| digests.values())
+            .flat_map(|approvers| approvers.values())
     }
 
 }
@@ -102,15 +125,16 @@ fn get_by_arc_hit() {
     let ppre = Commit::new(
         400,
         400,
+        "digest".to_owned(),
         400);
     let arc = Arc::new(RwLock::new(ppre));
     let mut rt: RequestTable<Commit> = RequestTable::new(two_thirds);
     let append_res = rt.append(arc.clone());
-    assert_eq!(append_res.is_ok(), true);
+    assert!(append_res.is_ok());
     let get_res = rt.get_by_arc(arc.clone());
-    assert_eq!(get_res.is_ok(), true);
+    assert!(get_res.is_ok());
     let get_unwrap = get_res.unwrap();
-    assert_eq!(get_unwrap.is_some(), true);
+    assert!(get_unwrap.is_some());
     assert_eq!(get_unwrap.unwrap().len(), 1);
 }
 
@@ -120,10 +144,11 @@ fn get_by_arc_miss() {
     let ppre = Commit::new(
         400,
         400,
+        "digest".to_owned(),
         400);
     let arc = Arc::new(RwLock::new(ppre));
     let rt: RequestTable<Commit> = RequestTable::new(two_thirds);
     let result = rt.get_by_arc(arc.clone());
-    assert_eq!(result.is_ok(), true);
-    assert_eq!(result.unwrap().is_none(), true);
+    assert!(result.is_ok());
+    assert!(result.unwrap().is_none());
 }
@@ -1,5 +1,6 @@
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod reqtable_test {
     use std::sync::{RwLock,Arc};
     use crate::dto::{Commit,ID};
@@ -10,7 +11,7 @@ mod reqtable_test {
     #[test]
     fn is_sufficient_empty() {
         let pp: RequestTable<Commit> = RequestTable::new(two_thirds);
-        assert_eq!(pp.is_sufficient(&new_req(10, 15, 1), &new_nodes(0)), false);
+        assert!(!pp.is_sufficient(&new_req(10, 15, 1), &new_nodes(0)));
     }
 
     struct ConfirmationType {
@@ -25,11 +26,11 @@ mod reqtable_test {
         }
     }
 
-    fn add_confirmations<'a>(table: &'a mut RequestTable<Commit>, view: ID, seq: ID, count: usize) {
+    fn add_confirmations(table: &mut RequestTable<Commit>, view: ID, seq: ID, count: usize) {
         for i in 0..count as ID {
             let res = table.append(Arc::new(RwLock::new(new_req(view, seq, i))));
             if res.is_err() {
-                panic!(res)
+                panic!("{:?}", res)
             }
         }
     }
@@ -49,13 +50,13 @@ mod reqtable_test {
             let mut confirm_progress = RequestTable::new(two_thirds);
             add_confirmations(&mut confirm_progress, 0, 0, progress_below);
             println!("is_sufficient_threshold below {:?}/{:?}", progress_below, node_count);
-            assert_eq!(confirm_progress.is_sufficient(&test_req, &nodes), false);
+            assert!(!confirm_progress.is_sufficient(&test_req, &nodes));
         }
-        for progress_over in (41..(node_count + 1)).into_iter().rev() {
+        for progress_over in (41..(node_count + 1)).rev() {
             let mut confirm_progress = RequestTable::new(two_thirds);
             add_confirmations(&mut confirm_progress, 0, 0, progress_over);
             println!("is_sufficient_threshold over {:?}/{:?}", progress_over, node_count);
-            assert_eq!(confirm_progress.is_sufficient(&test_req, &nodes), true);
+            assert!(confirm_progress.is_sufficient(&test_req, &nodes));
         }
     }
 
@@ -80,10 +81,10 @@ mod reqtable_test {
                     ConfirmationType{view: 0, seq: 1, count: progress_below - 10}
                 ]);
             println!("is_sufficient_threshold below {:?}/{:?}", progress_below, node_count);
-            assert_eq!(confirm_progress.is_sufficient(&test_req0, &nodes), false);
-            assert_eq!(confirm_progress.is_sufficient(&test_req1, &nodes), false);
+            assert!(!confirm_progress.is_sufficient(&test_req0, &nodes));
+            assert!(!confirm_progress.is_sufficient(&test_req1, &nodes));
         }
-        for progress_over in (41..(node_count + 1)).into_iter().rev() {
+        for progress_over in (41..(node_count + 1)).rev() {
             let mut confirm_progress = RequestTable::new(two_thirds);
             add_confirmations_complex(
                 &mut confirm_progress,
@@ -92,8 +93,8 @@ mod reqtable_test {
                     ConfirmationType{view: 0, seq: 1, count: progress_over - 40}
                 ]);
             println!("is_sufficient_threshold over {:?}/{:?}", progress_over, node_count);
-            assert_eq!(confirm_progress.is_sufficient(&test_req0, &nodes), true);
-            assert_eq!(confirm_progress.is_sufficient(&test_req1, &nodes), false);
+            assert!(confirm_progress.is_sufficient(&test_req0, &nodes));
+            assert!(!confirm_progress.is_sufficient(&test_req1, &nodes));
         }
     }
 
@@ -0,0 +1,74 @@
+use std::collections::BTreeMap;
+use crate::dto::{Digest};
+use crate::reqtable::{ViewID,SeqID};
+
+// What this node did in one instance of the protocol, (v, n)
+#[derive(Debug,Clone,Default)]
+pub struct Slot {
+    accepted: Option<Digest>, // d of the pre-prepare accepted for (v, n), there can be only one
+    sent_prepare: bool,
+    sent_commit: bool,
+}
+
+impl Slot {
+    pub fn get_accepted(&self) -> Option<&Digest> {
+        self.accepted.as_ref()
+    }
+
+    // A different d for the same (v, n) means the primary equivocates
+    pub fn conflicts_with(&self, digest: &Digest) -> bool {
+        self.accepted.as_ref().map(|accepted| accepted != digest).unwrap_or(false)
+    }
+
+    pub fn accept(&mut self, digest: Digest) {
+        if self.accepted.is_none() {
+            self.accepted = Some(digest);
+        }
+    }
+
+    pub fn has_sent_prepare(&self) -> bool {
+        self.sent_prepare
+    }
+
+    pub fn mark_sent_prepare(&mut self) {
+        self.sent_prepare = true;
+    }
+
+    pub fn has_sent_commit(&self) -> bool {
+        self.sent_commit
+    }
+
+    pub fn mark_sent_commit(&mut self) {
+        self.sent_commit = true;
+    }
+}
+
+// Many instances run at the same time, one slot for each of them
+#[derive(Debug,Default)]
+pub struct Slots {
+    slots: BTreeMap<(ViewID, SeqID), Slot>,
+}
+
+impl Slots {
+    pub fn new() -> Slots {
+        Slots{
+            slots: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&self, view_id: ViewID, seq_id: SeqID) -> Option<&Slot> {
+        self.slots.get(&(view_id, seq_id))
+    }
+
+    pub fn get_mut(&mut self, view_id: ViewID, seq_id: SeqID) -> &mut Slot {
+        self.slots.entry((view_id, seq_id)).or_default()
+    }
+
+    pub fn discard_through(&mut self, seq_id: SeqID) {
+        self.slots.retain(|(_, seq), _| *seq > seq_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+}
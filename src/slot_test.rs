@@ -0,0 +1,39 @@
+#[cfg(test)]
+mod slot_tracking_test {
+    use crate::slot::Slots;
+
+    #[test]
+    fn slot_should_keep_first_digest() {
+        let mut slots = Slots::new();
+        let slot = slots.get_mut(0, 1);
+        slot.accept("a".to_owned());
+        slot.accept("b".to_owned());
+        assert_eq!(slot.get_accepted(), Some(&"a".to_owned()));
+        assert!(!slot.conflicts_with(&"a".to_owned()));
+        assert!(slot.conflicts_with(&"b".to_owned()));
+    }
+
+    #[test]
+    fn slots_should_be_independent() {
+        let mut slots = Slots::new();
+        slots.get_mut(0, 1).mark_sent_prepare();
+        slots.get_mut(0, 2).mark_sent_commit();
+        assert!(slots.get(0, 1).unwrap().has_sent_prepare());
+        assert!(!slots.get(0, 1).unwrap().has_sent_commit());
+        assert!(!slots.get(0, 2).unwrap().has_sent_prepare());
+        assert!(slots.get(1, 1).is_none());
+    }
+
+    #[test]
+    fn slots_should_be_discarded_through_seq() {
+        let mut slots = Slots::new();
+        for seq in 1..5 {
+            slots.get_mut(0, seq);
+            slots.get_mut(1, seq);
+        }
+        slots.discard_through(2);
+        assert_eq!(slots.len(), 4);
+        assert!(slots.get(1, 2).is_none());
+        assert!(slots.get(1, 3).is_some());
+    }
+}
@@ -0,0 +1,70 @@
+use crate::dto::{Digest,Tip};
+use crate::encoding::{Encode,Persist};
+use crate::util::digest;
+use std::fmt::Debug;
+
+// The application replicated by the nodes. Every correct node applies the same
+// operations in the same order, so it has to be deterministic.
+pub trait StateMachine: Debug + Send + 'static {
+    type Operation: Debug + Clone + PartialEq + Encode + Persist + Send + Sync + 'static; // o -- kept in the write-ahead log
+    type Result: Debug + Clone + PartialEq + Encode + Persist + Send + Sync + 'static; // r -- sent back to the client, cached for retries
+    type Snapshot: Debug + Clone + Persist + Send + Sync + 'static; // kept for every checkpoint, the stable one in the write-ahead log
+
+    fn apply(&mut self, operation: &Self::Operation) -> Self::Result;
+    // Executes o without changing the state, None when o isn't read-only
+    fn read(&self, _operation: &Self::Operation) -> Option<Self::Result> {
+        None
+    }
+    fn snapshot(&self) -> Self::Snapshot;
+    // Goes back to a state returned by `snapshot`
+    fn restore(&mut self, snapshot: Self::Snapshot);
+    // d of checkpoints: equal states have to produce equal digests
+    fn digest(&self) -> Digest;
+}
+
+// The demo application: the state is the last operation, the result is the new state
+#[derive(Debug,Clone)]
+pub struct TipMachine {
+    tip: Tip,
+}
+
+impl TipMachine {
+    pub fn new() -> TipMachine {
+        TipMachine{
+            tip: "genesis".to_owned(),
+        }
+    }
+
+    pub fn get_tip(&self) -> Tip {
+        self.tip.clone()
+    }
+}
+
+impl Default for TipMachine {
+    fn default() -> TipMachine {
+        TipMachine::new()
+    }
+}
+
+impl StateMachine for TipMachine {
+    type Operation = Tip;
+    type Result = Tip;
+    type Snapshot = Tip;
+
+    fn apply(&mut self, operation: &Tip) -> Tip {
+        self.tip = operation.clone();
+        self.tip.clone()
+    }
+
+    fn snapshot(&self) -> Tip {
+        self.tip.clone()
+    }
+
+    fn restore(&mut self, snapshot: Tip) {
+        self.tip = snapshot;
+    }
+
+    fn digest(&self) -> Digest {
+        digest(&self.tip)
+    }
+}
@@ -0,0 +1,25 @@
+#[cfg(test)]
+mod tip_machine_test {
+    use crate::state_machine::{StateMachine,TipMachine};
+
+    #[test]
+    fn apply_should_return_new_tip() {
+        let mut machine = TipMachine::new();
+        assert_eq!(machine.get_tip(), "genesis".to_owned());
+        assert_eq!(machine.apply(&"op".to_owned()), "op".to_owned());
+        assert_eq!(machine.get_tip(), "op".to_owned());
+        assert_eq!(machine.snapshot(), "op".to_owned());
+    }
+
+    #[test]
+    fn equal_states_should_have_equal_digests() {
+        let mut a = TipMachine::new();
+        let mut b = TipMachine::new();
+        assert_eq!(a.digest(), b.digest());
+        a.apply(&"op".to_owned());
+        assert_ne!(a.digest(), b.digest());
+        b.apply(&"other".to_owned());
+        b.apply(&"op".to_owned());
+        assert_eq!(a.digest(), b.digest());
+    }
+}
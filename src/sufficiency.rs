@@ -1,8 +1,130 @@
 use crate::dto::{ID};
-use std::collections::{HashSet};
+use std::collections::{HashMap,HashSet};
+use std::fmt::Debug;
 use std::iter::Iterator;
 
-pub type SufficiencyChecker = fn(&HashSet<ID>, &HashSet<ID>) -> bool;
+// Decides whether the approvers among all_nodes are enough, e.g. 2f+1 of them
+pub trait SufficiencyChecker: Debug + Send + Sync {
+    fn is_sufficient(&self, all_nodes: &HashSet<ID>, approver_nodes: &HashSet<ID>) -> bool;
+}
+
+// The plain checkers below count heads
+pub type SufficiencyFn = fn(&HashSet<ID>, &HashSet<ID>) -> bool;
+
+impl SufficiencyChecker for SufficiencyFn {
+    fn is_sufficient(&self, all_nodes: &HashSet<ID>, approver_nodes: &HashSet<ID>) -> bool {
+        self(all_nodes, approver_nodes)
+    }
+}
+
+// Stake-based 2f+1: the approvers have to hold more than two thirds of the
+// total weight, for f+1 more than a third. Replicas without a weight of their own weigh 1
+#[derive(Debug,Clone,PartialEq)]
+pub struct WeightedQuorum {
+    weights: HashMap<ID, u64>,
+    share: WeightShare,
+}
+
+// What part of the total weight the approvers have to hold more of
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum WeightShare {
+    TwoThirds,
+    OneThird,
+}
+
+impl WeightedQuorum {
+    pub fn new(weights: HashMap<ID, u64>) -> WeightedQuorum {
+        WeightedQuorum{
+            weights,
+            share: WeightShare::TwoThirds,
+        }
+    }
+
+    // f+1 -- the faulty replicas hold less than a third of the weight, so at least one approver is correct
+    pub fn weak(weights: HashMap<ID, u64>) -> WeightedQuorum {
+        WeightedQuorum{
+            weights,
+            share: WeightShare::OneThird,
+        }
+    }
+
+    pub fn get_weight(&self, id: &ID) -> u64 {
+        self.weights.get(id).copied().unwrap_or(1)
+    }
+
+    fn total_weight<'a>(&self, nodes: impl Iterator<Item = &'a ID>) -> u128 {
+        nodes.map(|id| self.get_weight(id) as u128).sum()
+    }
+}
+
+impl SufficiencyChecker for WeightedQuorum {
+    fn is_sufficient(&self, all_nodes: &HashSet<ID>, approver_nodes: &HashSet<ID>) -> bool {
+        let approved = self.total_weight(clean_noise_approvers(all_nodes, approver_nodes));
+        let total = self.total_weight(all_nodes.iter());
+        match self.share {
+            WeightShare::TwoThirds => approved * 3 > total * 2,
+            WeightShare::OneThird => approved * 3 > total,
+        }
+    }
+}
+
+// f -- how many replicas may be faulty at once: stated by the configuration,
+// otherwise the most |R| = 3f + 1 tolerates
+pub fn fault_threshold(f: Option<usize>, all_nodes: &HashSet<ID>) -> usize {
+    f.unwrap_or_else(|| max_faulty(all_nodes))
+}
+
+// |R| >= 3f + 1, with fewer replicas f faulty ones can't be outvoted
+pub fn tolerates(node_count: usize, f: usize) -> bool {
+    node_count > 3 * f
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum CertificateKind {
+    Quorum,
+    Weak,
+    Single,
+}
+
+// Counts heads against a threshold that follows from f rather than from |R| alone
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Certificate {
+    f: Option<usize>,
+    kind: CertificateKind,
+}
+
+// 2f+1 -- any two quorums share a correct replica. With more than 3f+1 replicas
+// that takes more than 2f+1 of them: half of |R| + f + 1
+pub fn quorum(f: Option<usize>) -> Certificate {
+    Certificate{f, kind: CertificateKind::Quorum}
+}
+
+// f+1 -- at least one of the approvers is correct
+pub fn weak_certificate(f: Option<usize>) -> Certificate {
+    Certificate{f, kind: CertificateKind::Weak}
+}
+
+// 1 -- e.g. the pre-prepare of the primary
+pub fn single() -> Certificate {
+    Certificate{f: None, kind: CertificateKind::Single}
+}
+
+impl Certificate {
+    pub fn required(&self, all_nodes: &HashSet<ID>) -> usize {
+        let f = fault_threshold(self.f, all_nodes);
+        match self.kind {
+            CertificateKind::Quorum => (2 * f + 1).max((all_nodes.len() + f + 2) / 2),
+            CertificateKind::Weak => f + 1,
+            CertificateKind::Single => 1,
+        }
+    }
+}
+
+impl SufficiencyChecker for Certificate {
+    fn is_sufficient(&self, all_nodes: &HashSet<ID>, approver_nodes: &HashSet<ID>) -> bool {
+        clean_noise_approvers(all_nodes, approver_nodes).count() >= self.required(all_nodes)
+    }
+}
 
 // |R| = 3f + 1
 // accepted = 2f + 1
@@ -17,13 +139,18 @@ pub fn two_thirds(all_nodes: &HashSet<ID>, approver_nodes: &HashSet<ID>) -> bool
     clean_noise_approvers(all_nodes, approver_nodes).count() > (((all_nodes.len()) * 2) / 3)
 }
 
+// f = (|R| - 1) / 3 -- how many replicas may be faulty (or recovering) at once
+pub fn max_faulty(all_nodes: &HashSet<ID>) -> usize {
+    all_nodes.len().saturating_sub(1) / 3
+}
+
 pub fn one(all_nodes: &HashSet<ID>, approver_nodes: &HashSet<ID>) -> bool {
     //let approvers = clean_noise_approvers(all_nodes, approver_nodes).count();
     //println!("[approve] one: {}/1; approvers-dirty: {:?}; all: {:?}", approvers, approver_nodes, all_nodes);
     let clean: Vec<&ID> = clean_noise_approvers(all_nodes, approver_nodes)
         .take(1)
         .collect();
-    clean.first().is_some()
+    !clean.is_empty()
 }
 
 fn clean_noise_approvers<'a>(all_nodes: &'a HashSet<ID>, approver_nodes: &'a HashSet<ID>) -> impl Iterator<Item = &'a ID> + 'a {
@@ -36,7 +163,7 @@ use crate::test_util::{new_nodes};
 #[test]
 fn should_clean_noise_approvers() {
     let nodes = new_nodes(20);
-    let approvers: HashSet<ID> = [101, 102, 103, 105, 19, 1337, 1338, 20].iter().map(|i| *i).collect();
+    let approvers: HashSet<ID> = [101, 102, 103, 105, 19, 1337, 1338, 20].iter().copied().collect();
     assert_eq!(clean_noise_approvers(&nodes, &approvers).collect::<Vec<&ID>>(), vec![&19]);
 }
 
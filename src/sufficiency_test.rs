@@ -1,9 +1,9 @@
 #[cfg(test)]
 mod reqtable_test {
-    use std::collections::HashSet;
+    use std::collections::{HashMap,HashSet};
     use crate::dto::{ID};
     use crate::test_util::new_nodes;
-    use crate::sufficiency::{one,two_thirds};
+    use crate::sufficiency::{SufficiencyChecker,WeightedQuorum,one,two_thirds,max_faulty,quorum,weak_certificate,single,tolerates};
     #[test]
     fn test_approval_of_two_thirds_61() {
         // |R| = 3f + 1
@@ -17,11 +17,11 @@ mod reqtable_test {
         let nodes = new_nodes(node_count);
         for progress_below in 0..node_approver_count {
             println!("two_thirds_threshold {:?}/{:?} should be invalid", progress_below, node_count);
-            assert_eq!(two_thirds(&nodes, &new_nodes(progress_below)), false);
+            assert!(!two_thirds(&nodes, &new_nodes(progress_below)));
         }
         for progress_above in node_approver_count..node_count {
             println!("two_thirds_threshold {:?}/{:?} should be valid", progress_above, node_count);
-            assert_eq!(two_thirds(&nodes, &new_nodes(progress_above)), true);
+            assert!(two_thirds(&nodes, &new_nodes(progress_above)));
         }
     }
 
@@ -34,39 +34,120 @@ mod reqtable_test {
         nodes.remove(&0);
         for progress_below in 0..node_approver_count+1 {
             println!("two_thirds_threshold {:?}/{:?} should be invalid", progress_below, node_count);
-            assert_eq!(two_thirds(&nodes, &new_nodes(progress_below)), false);
+            assert!(!two_thirds(&nodes, &new_nodes(progress_below)));
         }
         for progress_above in node_approver_count+1..node_count+1 {
             println!("two_thirds_threshold {:?}/{:?} should be valid", progress_above, node_count);
-            assert_eq!(two_thirds(&nodes, &new_nodes(progress_above)), true);
+            assert!(two_thirds(&nodes, &new_nodes(progress_above)));
         }
     }
 
     #[test]
     fn approval_of_at_least_one_positive() {
         let nodes = new_nodes(20);
-        let approvers: HashSet<ID> = vec![5].iter().map(|i| *i).collect();
-        assert_eq!(one(&nodes, &approvers), true);
+        let approvers: HashSet<ID> = [5].iter().copied().collect();
+        assert!(one(&nodes, &approvers));
     }
 
     #[test]
     fn test_approval_of_at_least_one_negative() {
         let nodes = new_nodes(20);
-        let approvers: HashSet<ID> = vec![100].iter().map(|i| *i).collect();
-        assert_eq!(one(&nodes, &approvers), false);
+        let approvers: HashSet<ID> = [100].iter().copied().collect();
+        assert!(!one(&nodes, &approvers));
     }
 
     #[test]
     fn test_approval_of_at_least_one_noise_no_approval() {
         let nodes = new_nodes(20);
-        let approvers: HashSet<ID> = vec![101, 102, 103, 105].iter().map(|i| *i).collect();
-        assert_eq!(one(&nodes, &approvers), false);
+        let approvers: HashSet<ID> = [101, 102, 103, 105].iter().copied().collect();
+        assert!(!one(&nodes, &approvers));
     }
 
     #[test]
     fn test_approval_of_at_least_one_noise() {
         let nodes = new_nodes(20);
-        let approvers: HashSet<ID> = vec![101, 102, 103, 105, 19].iter().map(|i| *i).collect();
-        assert_eq!(one(&nodes, &approvers), true);
+        let approvers: HashSet<ID> = [101, 102, 103, 105, 19].iter().copied().collect();
+        assert!(one(&nodes, &approvers));
+    }
+
+    #[test]
+    fn test_max_faulty() {
+        assert_eq!(max_faulty(&new_nodes(0)), 0);
+        assert_eq!(max_faulty(&new_nodes(3)), 0);
+        assert_eq!(max_faulty(&new_nodes(4)), 1);
+        assert_eq!(max_faulty(&new_nodes(61)), 20);
+    }
+
+    #[test]
+    fn test_weighted_approval_of_two_thirds() {
+        // total weight 8: more than 16/3 means at least 6
+        let weights: HashMap<ID, u64> = vec![(0, 5)].into_iter().collect();
+        let quorum = WeightedQuorum::new(weights);
+        let nodes = new_nodes(4);
+        assert_eq!(quorum.get_weight(&0), 5);
+        assert_eq!(quorum.get_weight(&3), 1);
+        assert!(quorum.is_sufficient(&nodes, &vec![0, 1].into_iter().collect()));
+        assert!(!quorum.is_sufficient(&nodes, &vec![0].into_iter().collect()));
+        assert!(!quorum.is_sufficient(&nodes, &vec![1, 2, 3].into_iter().collect()));
+        // noise weighs nothing
+        assert!(!quorum.is_sufficient(&nodes, &vec![0, 101].into_iter().collect()));
+    }
+
+    #[test]
+    fn test_weighted_approval_of_one_third() {
+        // total weight 8: more than 8/3 means at least 3
+        let weak = WeightedQuorum::weak(vec![(0, 5)].into_iter().collect());
+        let nodes = new_nodes(4);
+        assert!(weak.is_sufficient(&nodes, &vec![0].into_iter().collect()));
+        assert!(!weak.is_sufficient(&nodes, &vec![1, 2].into_iter().collect()));
+        assert!(weak.is_sufficient(&nodes, &vec![1, 2, 3].into_iter().collect()));
+    }
+
+    #[test]
+    fn test_equal_weights_should_count_heads() {
+        let quorum = WeightedQuorum::new(HashMap::new());
+        let nodes = new_nodes(61);
+        for approver_count in 0..=61 {
+            assert_eq!(quorum.is_sufficient(&nodes, &new_nodes(approver_count)), two_thirds(&nodes, &new_nodes(approver_count)));
+        }
+    }
+
+    #[test]
+    fn test_quorum_of_tight_configuration() {
+        for f in 0..20 {
+            let nodes = new_nodes(3 * f + 1);
+            assert_eq!(quorum(Some(f)).required(&nodes), 2 * f + 1);
+            assert_eq!(quorum(None).required(&nodes), 2 * f + 1);
+            assert_eq!(weak_certificate(Some(f)).required(&nodes), f + 1);
+            assert!(!quorum(Some(f)).is_sufficient(&nodes, &new_nodes(2 * f)));
+            assert!(quorum(Some(f)).is_sufficient(&nodes, &new_nodes(2 * f + 1)));
+        }
+    }
+
+    #[test]
+    fn test_quorum_of_non_tight_configuration() {
+        // f = 1 of 7: 2f+1 = 3 wouldn't do, two quorums of 3 may not overlap at all
+        let nodes = new_nodes(7);
+        assert_eq!(quorum(Some(1)).required(&nodes), 5);
+        assert_eq!(weak_certificate(Some(1)).required(&nodes), 2);
+        assert_eq!(weak_certificate(None).required(&nodes), 3);
+        assert_eq!(single().required(&nodes), 1);
+        for node_count in 1..40 {
+            let nodes = new_nodes(node_count);
+            for f in 0..=max_faulty(&nodes) {
+                // any two quorums share f+1 replicas, one of them correct
+                let required = quorum(Some(f)).required(&nodes);
+                assert!(required <= node_count - f);
+                assert!(2 * required - node_count > f);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tolerates() {
+        assert!(!tolerates(3, 1));
+        assert!(tolerates(4, 1));
+        assert!(tolerates(7, 1));
+        assert!(tolerates(1, 0));
     }
 }
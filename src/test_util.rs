@@ -1,25 +1,55 @@
-use crate::dto::{PrePrepare,Commit,ID};
-use std::collections::HashSet;
-use std::time::Instant;
+use crate::dto::{Request,Reply,PrePrepare,Commit,ID,Tip,Reconfiguration,SuspectPrimary,NodeRequest};
+use crate::state_machine::{StateMachine,TipMachine};
+use crate::client::Client;
+use crate::network::Network;
+use crate::node::{Message,State,StableCheckpoint};
+use crate::config::{Config,Authentication};
+use crate::crypto::{KeyRing,Signed};
+use crate::encoding::Encode;
+use crate::error::PbftError;
+use crate::util::find_others;
+use std::cell::RefCell;
+use std::collections::{HashMap,HashSet};
+use std::sync::{Arc,Mutex,RwLock};
+use std::sync::mpsc;
+use std::sync::mpsc::{Sender,Receiver};
+use std::time::{Duration,Instant,SystemTime,UNIX_EPOCH};
+use std::path::PathBuf;
+use std::{env,fs,process};
 
 fn random() -> ID {
     Instant::now().elapsed().as_secs() as ID
 }
 
-pub fn new_random_preprepare() -> PrePrepare {
+pub fn new_random_preprepare() -> PrePrepare<Tip> {
     let sender_id = random();
     PrePrepare::new(
         random(),    // v
         random(),     // n
-        "Tip message".to_owned(),    // m
+        vec![new_request("Tip message")],    // m
         sender_id,
     )
 }
 
+pub const CLIENT_ID: ID = 100;
+
+// How long the replicas may stay silent before `run_until` gives up. Above the
+// view change timeouts the threaded tests rely on
+const QUIET_PERIOD: Duration = Duration::from_secs(5);
+
+// Some state changes come without a message, e.g. a replica executing its own
+// removal: `run_until` looks at the network at least this often
+const WAKE_UP: Duration = Duration::from_millis(10);
+
+pub fn new_request(op: &str) -> Request<Tip> {
+    Request::new(op.to_owned(), 1, CLIENT_ID)
+}
+
 pub fn new_req(view: ID, seq: ID, sender: ID) -> Commit {
     Commit::new(
         view as ID,
         seq as ID,
+        "digest".to_owned(),
         sender as ID)
 }
 
@@ -28,5 +58,321 @@ pub fn new_nodes(count: usize) -> HashSet<ID> {
     for i in 0..count as ID {
         nodes.insert(i);
     }
-    return nodes;
+    nodes
+}
+
+// A fresh directory for write-ahead logs, unique to the calling test
+pub fn temp_dir(name: &str) -> PathBuf {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    let dir = env::temp_dir().join(format!("pbft-{}-{}-{}", name, process::id(), nanos));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Checkpoints every 2 seqs in a window of 4, one request per batch: a few requests
+// are enough to reach stable checkpoints, water marks and state transfers
+pub fn config() -> Config {
+    Config{checkpoint_interval: 2, log_size: 4, max_batch_size: 1, ..Config::default()}
+}
+
+// Tip of a running replica, none while it is down
+pub fn tip(net: &Network<TipMachine>, id: ID) -> Option<Tip> {
+    net.get_node(&id).map(|node| node.get_state().lock().unwrap().get_machine().get_tip())
+}
+
+// Drives the network like `main` does until `done`. Every round waits for the
+// next message of a replica rather than for a fixed budget, so a slow machine
+// only makes the run slower. It gives up once the replicas fall silent
+pub fn run_until<S, F>(net: &mut Network<S>, done: F) -> bool
+where S: StateMachine + Default,
+      F: Fn(&Network<S>) -> bool {
+    let mut last_message = Instant::now();
+    loop {
+        net.queue_update();
+        net.tick_queue_all();
+        net.tick_until_empty_skip_queue();
+        if done(net) {
+            return true;
+        }
+        if net.queue_next(WAKE_UP) {
+            last_message = Instant::now();
+        } else if last_message.elapsed() >= QUIET_PERIOD {
+            return false;
+        }
+    }
+}
+
+// Synchronous stand-in for `Network`: every message is delivered right away
+// on the test thread, so protocol runs are deterministic
+pub struct Cluster<S: StateMachine> {
+    states: HashMap<ID, Arc<Mutex<State<S>>>>,
+    sender: Sender<Message<S>>,
+    receiver: Receiver<Message<S>>,
+    silenced: HashSet<ID>,
+    replies: RefCell<Vec<Reply<S::Result>>>,
+    key_ring: KeyRing,
+    config: Config,
+}
+
+// Most tests replicate the demo `TipMachine`
+impl Cluster<TipMachine> {
+    pub fn new(size: usize) -> Cluster<TipMachine> {
+        Cluster::with_config(size, Config::default())
+    }
+
+    pub fn with_config(size: usize, config: Config) -> Cluster<TipMachine> {
+        Cluster::build(size, config)
+    }
+
+    pub fn run_request(&self, client: &mut Client<TipMachine>, op: &str) -> Option<Tip> {
+        self.run_operation(client, op.to_owned())
+    }
+}
+
+impl<S: StateMachine + Default> Cluster<S> {
+    pub fn build(size: usize, config: Config) -> Cluster<S> {
+        let (sender, receiver) = mpsc::channel();
+        let nodes = new_nodes(size);
+        let key_ring = KeyRing::generate(&nodes, 0);
+        let states: HashMap<ID, Arc<Mutex<State<S>>>> = nodes.iter()
+            .map(|id| (*id, State::genesis(*id, nodes.clone(), &config, key_ring.get_credentials(*id).unwrap(), S::default())))
+            .collect();
+        if let Some(dir) = &config.wal_dir {
+            for (id, state) in states.iter() {
+                state.lock().unwrap().open_log(*id, dir, sender.clone()).unwrap();
+            }
+        }
+        Cluster{
+            states,
+            sender,
+            receiver,
+            silenced: HashSet::new(),
+            replies: RefCell::new(Vec::new()),
+            key_ring,
+            config,
+        }
+    }
+
+    // The new replica starts from genesis in the new configuration, the others
+    // learn its key right away and count it in once `reconfigure` executes
+    pub fn add_node(&mut self, id: ID) {
+        self.key_ring.add_key(id, 0);
+        for (node_id, state) in self.states.iter() {
+            state.lock().unwrap().set_credentials(&self.key_ring.get_credentials(*node_id).unwrap());
+        }
+        let mut nodes: HashSet<ID> = self.states.iter()
+            .filter(|(node_id, state)| state.lock().unwrap().is_member(**node_id))
+            .map(|(node_id, _)| *node_id)
+            .collect();
+        nodes.insert(id);
+        let credentials = self.key_ring.get_credentials(id).unwrap();
+        self.states.insert(id, State::genesis(id, nodes, &self.config, credentials, S::default()));
+    }
+
+    // A crashed replica comes back with a fresh state, the log it kept is all it remembers
+    pub fn restart(&mut self, id: ID) {
+        let nodes = self.get_state(id).lock().unwrap().get_all_nodes().clone();
+        let state = State::genesis(id, nodes, &self.config, self.key_ring.get_credentials(id).unwrap(), S::default());
+        if let Some(dir) = &self.config.wal_dir {
+            state.lock().unwrap().open_log(id, dir, self.sender.clone()).unwrap();
+        }
+        self.states.insert(id, state);
+    }
+
+    // A silenced node neither receives nor sends anything (crashed or faulty)
+    pub fn silence(&mut self, id: ID) {
+        self.silenced.insert(id);
+    }
+
+    pub fn restore(&mut self, id: ID) {
+        self.silenced.remove(&id);
+    }
+
+    pub fn get_state(&self, id: ID) -> Arc<Mutex<State<S>>> {
+        self.states.get(&id).unwrap().clone()
+    }
+
+    pub fn deliver(&self, message: Message<S>) {
+        let target = message.get_target_id();
+        if let Err(e) = self.try_deliver(message) {
+            println!("[{:?}] Cluster delivery err: {}", target, e);
+        }
+    }
+
+    // Like `deliver`, with the reason the target dropped the message
+    pub fn try_deliver(&self, message: Message<S>) -> Result<(), PbftError> {
+        if let Some(reply) = message.get_reply() {
+            if !self.silenced.contains(&message.get_sender_id()) {
+                self.replies.borrow_mut().push(reply.read().unwrap().clone());
+            }
+            return Ok(());
+        }
+        let target = message.get_target_id();
+        if self.silenced.contains(&target) || self.silenced.contains(&message.get_sender_id()) {
+            return Ok(());
+        }
+        match self.states.get(&target) {
+            Some(state) => state.lock().unwrap().handle_protocol_message(target, message, self.sender.clone()),
+            None => Err(PbftError::UnknownNode{node_id: target}),
+        }
+    }
+
+    // Once nothing is in flight the batch delay is considered over
+    pub fn deliver_all(&self) {
+        loop {
+            while let Ok(message) = self.receiver.try_recv() {
+                self.deliver(message);
+            }
+            if !self.flush_batches() {
+                break;
+            }
+        }
+    }
+
+    // Delivers the in-flight messages `keep` picks, the network loses the others
+    pub fn deliver_only<F>(&self, keep: F)
+    where F: Fn(&Message<S>) -> bool {
+        let in_flight: Vec<Message<S>> = self.receiver.try_iter().collect();
+        for message in in_flight {
+            if keep(&message) {
+                self.deliver(message);
+            }
+        }
+    }
+
+    fn flush_batches(&self) -> bool {
+        let mut flushed = false;
+        for (id, state) in self.states.iter() {
+            if !self.silenced.contains(id) {
+                flushed |= state.lock().unwrap().flush_batch(*id, self.sender.clone());
+            }
+        }
+        flushed
+    }
+
+    // Replies that reached clients so far
+    pub fn take_replies(&self) -> Vec<Reply<S::Result>> {
+        self.replies.replace(Vec::new())
+    }
+
+    pub fn submit(&self, request: Request<S::Operation>, target: ID) {
+        self.deliver(Message::request(request.get_client_id(), target, Arc::new(RwLock::new(request))));
+    }
+
+    // Runs a request from the client to completion, returns what the client accepted
+    // A client of this cluster that checks the replies against its keys
+    pub fn new_client(&self) -> Client<S> {
+        let nodes: HashSet<ID> = self.states.keys().copied().collect();
+        Client::from_config(CLIENT_ID, nodes, &self.config, self.key_ring.get_verifier())
+    }
+
+    pub fn run_operation(&self, client: &mut Client<S>, op: S::Operation) -> Option<S::Result> {
+        let request = client.new_request(op);
+        let primary_id = client.get_primary().unwrap();
+        self.submit(request, primary_id);
+        self.deliver_all();
+        self.hand_replies_to(client)
+    }
+
+    // Read-only fast path; once everything is delivered there is nothing more
+    // to wait for, so without 2f+1 matching replies the read is ordered
+    pub fn run_read(&self, client: &mut Client<S>, op: S::Operation) -> Option<S::Result> {
+        let request = client.new_read_request(op);
+        let timestamp = request.get_timestamp();
+        for id in self.states.keys() {
+            self.submit(request.clone(), *id);
+        }
+        self.deliver_all();
+        if let Some(result) = self.hand_replies_to(client) {
+            return Some(result);
+        }
+        let request = client.fall_back(timestamp)?;
+        self.submit(request, client.get_primary().unwrap());
+        self.deliver_all();
+        self.hand_replies_to(client)
+    }
+
+    fn hand_replies_to(&self, client: &mut Client<S>) -> Option<S::Result> {
+        let mut result = None;
+        for reply in self.take_replies() {
+            result = client.handle_reply(&reply).or(result);
+        }
+        result
+    }
+
+    // Proactive recovery like `Network` does it, minus rekeying: a fresh state
+    // reloads `checkpoint` and catches up with what the others executed
+    pub fn recover(&mut self, id: ID, checkpoint: Option<StableCheckpoint<S>>) {
+        let target = self.states.iter()
+            .filter(|(other, _)| **other != id)
+            .map(|(_, state)| state.lock().unwrap().get_last_executed())
+            .max()
+            .unwrap_or(0);
+        let nodes = self.get_state(id).lock().unwrap().get_all_nodes().clone();
+        let state = State::genesis(id, nodes, &self.config, self.key_ring.get_credentials(id).unwrap(), S::default());
+        state.lock().unwrap().recover(id, checkpoint, target, self.sender.clone());
+        self.states.insert(id, state);
+        self.deliver_all();
+    }
+
+    // The administrator asks every replica, the primary orders the change
+    pub fn reconfigure(&self, reconfiguration: Reconfiguration) {
+        let reconfiguration = Arc::new(RwLock::new(reconfiguration));
+        for id in self.states.keys() {
+            self.deliver(Message::reconfiguration(*id, *id, reconfiguration.clone()));
+        }
+        self.deliver_all();
+    }
+
+    pub fn suspect_primary(&self, id: ID) {
+        self.deliver(Message::suspect(id, id, Arc::new(RwLock::new(SuspectPrimary{}))));
+    }
+
+    // The liveness timers of every node as if `elapsed` had passed since they started
+    pub fn expire_timers(&self, elapsed: Duration) -> bool {
+        let now = Instant::now() + elapsed;
+        let mut expired = false;
+        for (id, state) in self.states.iter() {
+            if !self.silenced.contains(id) {
+                expired |= state.lock().unwrap().check_timers(*id, now, self.sender.clone());
+            }
+        }
+        self.deliver_all();
+        expired
+    }
+
+    pub fn sign<M: Signed>(&self, signer_id: ID, mut message: M) -> M {
+        message.sign(&*self.key_ring.get_signer(signer_id).unwrap());
+        message
+    }
+
+    // The MAC vector of the sender, whatever the signature of the message says
+    pub fn authenticate(&self, message: Message<S>) -> Message<S> {
+        let (_, bytes) = message.get_signed_bytes().unwrap();
+        let nodes: HashSet<ID> = find_others(message.get_sender_id(), self.states.keys()).collect();
+        let authenticator = self.key_ring.get_session_keys(message.get_sender_id()).unwrap()
+            .authenticate(&bytes, nodes.iter());
+        message.with_authenticator(Arc::new(authenticator))
+    }
+
+    // Faulty primaries still sign with their own key
+    pub fn broadcast_preprepare(&self, preprepare: PrePrepare<S::Operation>) {
+        let sender_id = preprepare.get_sender_id();
+        self.broadcast_preprepare_signed_by(sender_id, preprepare);
+    }
+
+    // Impersonation: `signer_id` signs a pre-prepare that claims to be from someone else
+    pub fn broadcast_preprepare_signed_by(&self, signer_id: ID, mut preprepare: PrePrepare<S::Operation>) {
+        preprepare.sign(&*self.key_ring.get_signer(signer_id).unwrap());
+        let sender_id = preprepare.get_sender_id();
+        let nodes: HashSet<ID> = find_others(sender_id, self.states.keys()).collect();
+        let authenticator = Arc::new(self.key_ring.get_session_keys(signer_id).unwrap()
+            .authenticate(&preprepare.to_bytes(), nodes.iter()));
+        for m in Message::multiply(Message::preprepare, Arc::new(RwLock::new(preprepare)), sender_id, &nodes) {
+            match self.config.authentication {
+                Authentication::Signatures => self.deliver(m),
+                Authentication::Authenticators => self.deliver(m.with_authenticator(authenticator.clone())),
+            }
+        }
+    }
 }
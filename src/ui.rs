@@ -1,10 +1,10 @@
 use crate::network::Network;
+use crate::kv_store::{KvStore,KvOperation};
+use crate::state_machine::StateMachine;
 use std::thread;
 use std::time::Duration;
 use std::io;
-use std::sync::{RwLock,Arc};
-use crate::dto::{PrePrepare};
-use crate::node::{Message};
+use crate::dto::{ID};
 
 fn print_line() {
     println!("----------------------------------------------------------------------------------------------------");
@@ -26,10 +26,11 @@ fn print_menu() {
     println!("2. propagate first packet from queue");
     println!("2a. propagate all packets from queue (not from channel)");
     println!("2b. propagate everything until channel is exhausted");
-    println!("3. new PrePrepare request (from 1st node to 2nd)");
+    println!("3. new client request (sent to the primary)");
+    println!("4. suspect the primary (all nodes start a view change)");
 }
 
-pub fn print_statuses(net: &Network) {
+pub fn print_statuses<S: StateMachine>(net: &Network<S>) {
     println!("----- Statuses: ------");
     net.get_statuses().for_each(|(id, v)| {
         println!("{:?} {:?}", id, v);
@@ -37,43 +38,47 @@ pub fn print_statuses(net: &Network) {
     println!("----------------------");
 }
 
-pub fn print_queue<'l>(net: &Network) {
+pub fn print_clients<S: StateMachine>(net: &Network<S>) {
+    println!("----- Clients: -------");
+    net.get_clients().for_each(|(id, client)| {
+        println!("{:?} {:?}", id, client);
+    });
+    println!("----------------------");
+}
+
+pub fn print_queue<S: StateMachine>(net: &Network<S>) {
     println!("------- Queue: -------");
     net.get_queue().for_each(|i| println!("{:?}", i));
     println!("----------------------");
 }
 
-pub fn new_preprepare<'l>(net: &mut Network) {
-    println!("------- PrePrepare input: -------\n ");
-    println!("Please enter seq_id:");
-    let seq_id = read_number();
-    if seq_id.is_none() {
-        println!("Bad number. Ending");
-        return;
-    }
-    println!("Please enter your message:");
-    let message = readln().trim().to_owned();
+pub fn new_request(net: &mut Network<KvStore>, client_id: ID) {
+    println!("------- Request input: -------\n ");
+    println!("Please enter an operation (get k | put k v | del k | cas k expected|- v):");
+    let operation = match KvOperation::parse(&readln()) {
+        Some(operation) => operation,
+        None => {
+            println!("Unknown operation");
+            return;
+        },
+    };
     println!("Adding the request to queue");
-    println!("---------------------------------");
-    let sender_id = 0;
-    // force (almost (bug: 0 -> 0 will filter it out)) all nodes to get the requests
-    for m in Message::multiply(
-        Message::preprepare,
-        Arc::new(RwLock::new(PrePrepare::new(
-            0,
-            seq_id.unwrap(),
-            message,
-            sender_id))),
-        sender_id,
-        &net.get_nodes()) {
-        net.queue_add(m)
+    println!("------------------------------");
+    let submitted = match operation {
+        KvOperation::Get{..} => net.submit_read(client_id, operation),
+        _ => net.submit(client_id, operation),
+    };
+    match submitted {
+        Some(timestamp) => println!("Request timestamp: {:?}", timestamp),
+        None => println!("Client {:?} is not known. Ending", client_id),
     }
 }
 
-pub fn interactive_mode(mut net: &mut Network) {
+pub fn interactive_mode(net: &mut Network<KvStore>, client_id: ID) {
     loop {
-        print_queue(&net);
-        print_statuses(&net);
+        print_queue(net);
+        print_statuses(net);
+        print_clients(net);
         print_menu();
         match readln().trim() {
             "1" => {
@@ -84,15 +89,21 @@ pub fn interactive_mode(mut net: &mut Network) {
                 thread::sleep(Duration::from_millis(100));
             },
             "2a" => {
-                let _res = net.tick_queue_all();
+                net.tick_queue_all();
                 thread::sleep(Duration::from_millis(100));
             },
             "2b" => {
-                let _res = net.tick_until_empty_skip_queue();
+                net.tick_until_empty_skip_queue();
                 thread::sleep(Duration::from_millis(100));
             },
             "3" => {
-                new_preprepare(&mut net);
+                new_request(net, client_id);
+            }
+            "4" => {
+                for id in net.get_nodes() {
+                    let _res = net.suspect_primary(id);
+                }
+                thread::sleep(Duration::from_millis(100));
             }
             _ => println!("Unknown command")
         }
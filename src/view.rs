@@ -0,0 +1,137 @@
+use crate::dto::{ID,NodeID,PrePrepare,PreparedCert,CommittedCert,ViewChange,Checkpoint,NodeRequest};
+use crate::encoding::Encode;
+use crate::sufficiency::SufficiencyChecker;
+use crate::crypto::{Signed,Verifier};
+use std::collections::{BTreeMap,HashSet};
+
+// p = v mod |R|
+// Nodes are ordered by ID so that every replica picks the same primary
+pub fn primary(view_id: ID, all_nodes: &HashSet<ID>) -> Option<NodeID> {
+    if all_nodes.is_empty() {
+        return None;
+    }
+    let mut nodes: Vec<&ID> = all_nodes.iter().collect();
+    nodes.sort();
+    Some(*nodes[(view_id % nodes.len() as ID) as usize])
+}
+
+fn matches<M, N>(a: &M, b: &N) -> bool
+where M: NodeRequest,
+      N: NodeRequest {
+    a.get_view_id() == b.get_view_id()
+        && a.get_seq_id() == b.get_seq_id()
+        && a.get_digest() == b.get_digest()
+}
+
+// A certificate is valid when the pre-prepare came from the primary of its view
+// and together with matching prepares from backups it forms a 2f+1 quorum
+pub fn is_valid_cert<O>(cert: &PreparedCert<O>, all_nodes: &HashSet<ID>, verifier: &dyn Verifier, quorum: &dyn SufficiencyChecker) -> bool {
+    let preprepare = cert.get_preprepare();
+    if primary(preprepare.get_view_id(), all_nodes) != Some(preprepare.get_sender_id()) {
+        return false;
+    }
+    if !preprepare.verify(verifier) || !cert.get_prepares().iter().all(|prepare| prepare.verify(verifier)) {
+        return false;
+    }
+    let mut approvers: HashSet<ID> = HashSet::new();
+    approvers.insert(preprepare.get_sender_id());
+    for prepare in cert.get_prepares() {
+        if !matches(prepare, preprepare) || prepare.get_sender_id() == preprepare.get_sender_id() {
+            return false;
+        }
+        approvers.insert(prepare.get_sender_id());
+    }
+    quorum.is_sufficient(all_nodes, &approvers)
+}
+
+// A committed certificate is valid when the pre-prepare came from the primary of its view,
+// carries the batch its digest promises and 2f+1 replicas committed it. The commits
+// vouch for the digest, so signatures of replicas rekeyed since then don't matter
+pub fn is_valid_committed_cert<O: Clone + Encode>(cert: &CommittedCert<O>, all_nodes: &HashSet<ID>, verifier: &dyn Verifier, quorum: &dyn SufficiencyChecker) -> bool {
+    let preprepare = cert.get_preprepare();
+    if primary(preprepare.get_view_id(), all_nodes) != Some(preprepare.get_sender_id()) {
+        return false;
+    }
+    if !preprepare.is_digest_valid() {
+        return false;
+    }
+    let all_match = cert.get_commits().iter().all(|commit| matches(commit, preprepare));
+    let senders: HashSet<ID> = cert.get_commits().iter()
+        .filter(|commit| commit.verify(verifier))
+        .map(|commit| commit.get_sender_id())
+        .collect();
+    all_match && quorum.is_sufficient(all_nodes, &senders)
+}
+
+// C -- 2f+1 checkpoints for n with the same state digest
+pub fn is_valid_checkpoint_proof(seq_id: ID, checkpoints: &[Checkpoint], all_nodes: &HashSet<ID>, verifier: &dyn Verifier, quorum: &dyn SufficiencyChecker) -> bool {
+    // genesis is stable by definition
+    if seq_id == 0 {
+        return true;
+    }
+    let digest = match checkpoints.first() {
+        Some(checkpoint) => checkpoint.get_digest(),
+        None => return false,
+    };
+    let all_match = checkpoints.iter().all(|checkpoint| {
+        checkpoint.get_seq_id() == seq_id && checkpoint.get_digest() == digest
+    });
+    // a replica rekeyed by proactive recovery signed with a key nobody accepts
+    // anymore, the others still have to add up to 2f+1
+    let senders: HashSet<ID> = checkpoints.iter()
+        .filter(|checkpoint| checkpoint.verify(verifier))
+        .map(|checkpoint| checkpoint.get_sender_id())
+        .collect();
+    all_match && quorum.is_sufficient(all_nodes, &senders)
+}
+
+pub fn is_valid_view_change<O>(view_change: &ViewChange<O>, all_nodes: &HashSet<ID>, verifier: &dyn Verifier, quorum: &dyn SufficiencyChecker) -> bool {
+    if !all_nodes.contains(&view_change.get_sender_id()) || !view_change.verify(verifier) {
+        return false;
+    }
+    if !is_valid_checkpoint_proof(view_change.get_seq_id(), view_change.get_checkpoints(), all_nodes, verifier, quorum) {
+        return false;
+    }
+    view_change.get_prepared().iter().all(|cert| {
+        let preprepare = cert.get_preprepare();
+        preprepare.get_view_id() < view_change.get_view_id()
+            && preprepare.get_seq_id() > view_change.get_seq_id()
+            && is_valid_cert(cert, all_nodes, verifier, quorum)
+    })
+}
+
+// O -- for every seq between the latest stable checkpoint (min-s) and the highest
+// prepared seq (max-s) re-issue the request prepared in the highest view,
+// fill the gaps with null requests
+pub fn new_view_preprepares<O: Clone + Encode>(view_id: ID, view_changes: &[ViewChange<O>], primary_id: NodeID) -> Vec<PrePrepare<O>> {
+    let min_s = view_changes.iter().map(|vc| vc.get_seq_id()).max().unwrap_or(0);
+    let mut highest: BTreeMap<ID, &PrePrepare<O>> = BTreeMap::new();
+    for cert in view_changes.iter().flat_map(|vc| vc.get_prepared().iter()) {
+        let preprepare = cert.get_preprepare();
+        if preprepare.get_seq_id() <= min_s {
+            continue;
+        }
+        let is_higher = highest.get(&preprepare.get_seq_id())
+            .map(|found| found.get_view_id() < preprepare.get_view_id())
+            .unwrap_or(true);
+        if is_higher {
+            highest.insert(preprepare.get_seq_id(), preprepare);
+        }
+    }
+    let max_s = highest.keys().next_back().copied().unwrap_or(min_s);
+    ((min_s + 1)..=max_s).map(|seq_id| {
+        match highest.get(&seq_id) {
+            Some(preprepare) => preprepare.reissue(view_id, primary_id),
+            None => PrePrepare::null(view_id, seq_id, primary_id),
+        }
+    }).collect()
+}
+
+// The O set sent by the primary has to be exactly the one we would compute from V
+pub fn is_same_preprepares<O: Clone + Encode + PartialEq>(expected: &[PrePrepare<O>], received: &[PrePrepare<O>]) -> bool {
+    expected.len() == received.len()
+        && expected.iter().zip(received.iter()).all(|(e, r)| {
+            matches(e, r) && e.get_sender_id() == r.get_sender_id() && e.get_batch() == r.get_batch()
+                && e.get_reconfiguration() == r.get_reconfiguration()
+        })
+}
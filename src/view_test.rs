@@ -0,0 +1,75 @@
+#[cfg(test)]
+mod view_change_proof_test {
+    use crate::crypto::{KeyRing,Signed};
+    use crate::dto::{ID,Tip,PrePrepare,PreparedCert,ViewChange,NodeRequest};
+    use crate::test_util::{new_nodes,new_request};
+    use crate::sufficiency::{SufficiencyFn,two_thirds};
+    use crate::view::{primary,is_valid_cert,new_view_preprepares};
+
+    fn key_ring() -> KeyRing {
+        KeyRing::generate(&new_nodes(4), 0)
+    }
+
+    fn signed<M: Signed>(mut message: M, signer_id: ID) -> M {
+        message.sign(&*key_ring().get_signer(signer_id).unwrap());
+        message
+    }
+
+    fn cert(view: ID, seq: ID, message: &str, preparers: Vec<ID>) -> PreparedCert<Tip> {
+        let pp = signed(PrePrepare::new(view, seq, vec![new_request(message)], view % 4), view % 4);
+        let prepares = preparers.iter().map(|i| signed(pp.make_prepare(*i), *i)).collect();
+        PreparedCert::new(pp, prepares)
+    }
+
+    #[test]
+    fn primary_should_rotate_with_view() {
+        let nodes = new_nodes(4);
+        assert_eq!(primary(0, &nodes), Some(0));
+        assert_eq!(primary(1, &nodes), Some(1));
+        assert_eq!(primary(5, &nodes), Some(1));
+        assert_eq!(primary(0, &new_nodes(0)), None);
+    }
+
+    #[test]
+    fn cert_needs_quorum_of_backups() {
+        let nodes = new_nodes(4);
+        let verifier = key_ring().get_verifier();
+        assert!(is_valid_cert(&cert(0, 1, "m", vec![1, 2]), &nodes, &*verifier, &(two_thirds as SufficiencyFn)));
+        assert!(!is_valid_cert(&cert(0, 1, "m", vec![1]), &nodes, &*verifier, &(two_thirds as SufficiencyFn)));
+        // the primary's own prepare doesn't count
+        assert!(!is_valid_cert(&cert(0, 1, "m", vec![0, 1]), &nodes, &*verifier, &(two_thirds as SufficiencyFn)));
+    }
+
+    #[test]
+    fn cert_with_unsigned_prepare_should_be_invalid() {
+        let nodes = new_nodes(4);
+        let pp = signed(PrePrepare::new(0, 1, vec![new_request("m")], 0), 0);
+        let prepares = vec![signed(pp.make_prepare(1), 1), pp.make_prepare(2)];
+        assert!(!is_valid_cert(&PreparedCert::new(pp, prepares), &nodes, &*key_ring().get_verifier(), &(two_thirds as SufficiencyFn)));
+    }
+
+    #[test]
+    fn new_view_should_keep_highest_prepared_and_fill_gaps() {
+        let view_changes = vec![
+            ViewChange::new(2, 0, vec![], vec![cert(0, 1, "old", vec![1, 2]), cert(0, 3, "three", vec![1, 2])], 1),
+            ViewChange::new(2, 0, vec![], vec![cert(1, 1, "new", vec![2, 3])], 2),
+            ViewChange::new(2, 0, vec![], vec![], 3),
+        ];
+        let o = new_view_preprepares(2, &view_changes, 2);
+        assert_eq!(o.len(), 3);
+        assert_eq!(o.iter().map(|pp| pp.get_seq_id()).collect::<Vec<ID>>(), vec![1, 2, 3]);
+        assert_eq!(o[0].get_batch()[0].get_op(), "new");
+        assert!(o[1].is_null());
+        assert_eq!(o[2].get_batch()[0].get_op(), "three");
+        assert!(o.iter().all(|pp| pp.get_view_id() == 2 && pp.get_sender_id() == 2));
+    }
+
+    #[test]
+    fn new_view_should_skip_stable_seqs() {
+        let view_changes = vec![
+            ViewChange::new(1, 0, vec![], vec![cert(0, 1, "m", vec![1, 2])], 2),
+            ViewChange::new(1, 1, vec![], vec![], 3),
+        ];
+        assert_eq!(new_view_preprepares(1, &view_changes, 1).len(), 0);
+    }
+}
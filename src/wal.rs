@@ -0,0 +1,239 @@
+use crate::dto::{ID,NodeID,PrePrepare,Prepare,Commit,Checkpoint,ViewChange,NewView,Reconfiguration};
+use crate::encoding::{Persist,take};
+use crate::error::PbftError;
+use crate::node::{Payload,StableCheckpoint,StateSnapshot};
+use crate::state_machine::StateMachine;
+use crate::util::convert_err;
+use std::convert::TryFrom;
+use std::fs;
+use std::fs::{File,OpenOptions};
+use std::io::{ErrorKind,Read,Write};
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,RwLock};
+
+// Which way a logged message went
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Direction {
+    Received, // from a peer, logged before it is handled
+    Sent, // our own, logged before it leaves
+}
+
+#[derive(Debug)]
+pub struct Entry<S: StateMachine> {
+    direction: Direction,
+    sender_id: NodeID,
+    payload: Payload<S>,
+}
+
+impl<S: StateMachine> Entry<S> {
+    pub fn get_direction(&self) -> Direction {
+        self.direction
+    }
+    pub fn get_sender_id(&self) -> NodeID {
+        self.sender_id
+    }
+    pub fn get_payload(&self) -> &Payload<S> {
+        &self.payload
+    }
+    pub fn into_payload(self) -> Payload<S> {
+        self.payload
+    }
+}
+
+// What a record holds: a message, or the stable checkpoint the log was compacted to
+enum Record<S: StateMachine> {
+    Entry(Entry<S>),
+    Stable(StableCheckpoint<S>),
+}
+
+// An opened log with the stable checkpoint it was compacted to and the entries after it
+pub type Opened<S> = (WriteAheadLog, Option<StableCheckpoint<S>>, Vec<Entry<S>>);
+
+// Append-only file of one replica. Every record is its length followed by the
+// direction, the sender, the kind of message and the message itself. A compacted
+// log starts with the stable checkpoint instead
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    path: PathBuf,
+    file: File,
+}
+
+fn log_failed(e: std::io::Error) -> PbftError {
+    PbftError::LogFailed{reason: e.to_string()}
+}
+
+impl WriteAheadLog {
+    pub fn path(dir: &Path, id: NodeID) -> PathBuf {
+        dir.join(format!("node-{}.wal", id))
+    }
+
+    // Opens the log of `id`, an empty one if there is none yet, with everything it holds
+    pub fn open<S: StateMachine>(dir: &Path, id: NodeID) -> Result<Opened<S>, PbftError> {
+        fs::create_dir_all(dir).map_err(log_failed)?;
+        let path = WriteAheadLog::path(dir, id);
+        let file = OpenOptions::new().read(true).append(true).create(true).open(&path).map_err(log_failed)?;
+        let mut bytes = Vec::new();
+        (&file).read_to_end(&mut bytes).map_err(log_failed)?;
+        let (records, valid) = read_records(&bytes)?;
+        // a record cut short by a crash was never acted upon, the next one takes its place
+        if valid < bytes.len() {
+            file.set_len(valid as u64).map_err(log_failed)?;
+        }
+        let mut checkpoint = None;
+        let mut entries = Vec::new();
+        for record in records {
+            match record {
+                Record::Entry(entry) => entries.push(entry),
+                Record::Stable(stable) => checkpoint = Some(stable),
+            }
+        }
+        Ok((WriteAheadLog{path, file}, checkpoint, entries))
+    }
+
+    // Proactive recovery starts over from a checkpoint with a new key
+    pub fn remove(dir: &Path, id: NodeID) -> Result<(), PbftError> {
+        match fs::remove_file(WriteAheadLog::path(dir, id)) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(log_failed(e)),
+        }
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    // On disk once it returns, the message is acted upon only then. Payloads that
+    // aren't logged are skipped
+    pub fn append<S: StateMachine>(&self, direction: Direction, sender_id: NodeID, payload: &Payload<S>) -> Result<(), PbftError> {
+        let record = match encode_entry(direction, sender_id, payload)? {
+            Some(record) => record,
+            None => return Ok(()),
+        };
+        let mut bytes = Vec::with_capacity(8 + record.len());
+        push_record(&record, &mut bytes);
+        (&self.file).write_all(&bytes).map_err(log_failed)?;
+        self.file.sync_data().map_err(log_failed)
+    }
+
+    // Starts the log over from a stable checkpoint: it stands for everything before it,
+    // of the entries only the ones `keep` picks stay. The new log is written next to the
+    // old one and renamed over it, a crash leaves one of the two behind
+    pub fn compact<S: StateMachine>(&mut self, checkpoint: &StableCheckpoint<S>, keep: impl Fn(&Entry<S>) -> bool) -> Result<(), PbftError> {
+        let bytes = fs::read(&self.path).map_err(log_failed)?;
+        let (records, _) = read_records::<S>(&bytes)?;
+        let mut compacted = Vec::new();
+        push_record(&encode_checkpoint(checkpoint), &mut compacted);
+        for record in records {
+            let entry = match record {
+                Record::Entry(entry) if keep(&entry) => entry,
+                _ => continue,
+            };
+            if let Some(record) = encode_entry(entry.direction, entry.sender_id, &entry.payload)? {
+                push_record(&record, &mut compacted);
+            }
+        }
+        let next = self.path.with_extension("wal.next");
+        let mut file = File::create(&next).map_err(log_failed)?;
+        file.write_all(&compacted).map_err(log_failed)?;
+        file.sync_all().map_err(log_failed)?;
+        fs::rename(&next, &self.path).map_err(log_failed)?;
+        self.file = OpenOptions::new().read(true).append(true).open(&self.path).map_err(log_failed)?;
+        Ok(())
+    }
+}
+
+fn push_record(record: &[u8], out: &mut Vec<u8>) {
+    (record.len() as u64).persist(out);
+    out.extend_from_slice(record);
+}
+
+fn persist_locked<M: Persist>(kind: u8, message: &Arc<RwLock<M>>, out: &mut Vec<u8>) -> Result<(), PbftError> {
+    kind.persist(out);
+    convert_err(message.read())?.persist(out);
+    Ok(())
+}
+
+fn load_locked<M: Persist>(input: &mut &[u8]) -> Option<Arc<RwLock<M>>> {
+    M::load(input).map(|message| Arc::new(RwLock::new(message)))
+}
+
+// Only the protocol messages the state is built from. Clients retry their requests,
+// a state transfer is fetched again and the other control packets come from outside
+fn encode_entry<S: StateMachine>(direction: Direction, sender_id: NodeID, payload: &Payload<S>) -> Result<Option<Vec<u8>>, PbftError> {
+    let mut out = Vec::new();
+    match direction {
+        Direction::Received => 0u8,
+        Direction::Sent => 1u8,
+    }.persist(&mut out);
+    sender_id.persist(&mut out);
+    match payload {
+        Payload::PrePrepare(preprepare) => persist_locked(0, preprepare, &mut out)?,
+        Payload::Prepare(prepare) => persist_locked(1, prepare, &mut out)?,
+        Payload::Commit(commit) => persist_locked(2, commit, &mut out)?,
+        Payload::Checkpoint(checkpoint) => persist_locked(3, checkpoint, &mut out)?,
+        Payload::ViewChange(view_change) => persist_locked(4, view_change, &mut out)?,
+        Payload::NewView(new_view) => persist_locked(5, new_view, &mut out)?,
+        // a backup prepares only membership changes it was asked for
+        Payload::Reconfiguration(reconfiguration) => persist_locked(6, reconfiguration, &mut out)?,
+        Payload::Request(_) | Payload::Reply(_) | Payload::FetchState(_) | Payload::StateTransfer(_)
+            | Payload::Shutdown(_) | Payload::SuspectPrimary(_) | Payload::Credentials(_) => return Ok(None),
+    }
+    Ok(Some(out))
+}
+
+fn encode_checkpoint<S: StateMachine>(checkpoint: &StableCheckpoint<S>) -> Vec<u8> {
+    let (seq_id, proof, snapshot) = checkpoint;
+    let mut out = Vec::new();
+    2u8.persist(&mut out);
+    seq_id.persist(&mut out);
+    proof.persist(&mut out);
+    snapshot.persist(&mut out);
+    out
+}
+
+fn decode_record<S: StateMachine>(input: &mut &[u8]) -> Option<Record<S>> {
+    let direction = match u8::load(input)? {
+        0 => Direction::Received,
+        1 => Direction::Sent,
+        2 => return Some(Record::Stable((ID::load(input)?, Vec::<Checkpoint>::load(input)?, StateSnapshot::load(input)?))),
+        _ => return None,
+    };
+    let sender_id = NodeID::load(input)?;
+    let payload = match u8::load(input)? {
+        0 => Payload::PrePrepare(load_locked::<PrePrepare<S::Operation>>(input)?),
+        1 => Payload::Prepare(load_locked::<Prepare>(input)?),
+        2 => Payload::Commit(load_locked::<Commit>(input)?),
+        3 => Payload::Checkpoint(load_locked::<Checkpoint>(input)?),
+        4 => Payload::ViewChange(load_locked::<ViewChange<S::Operation>>(input)?),
+        5 => Payload::NewView(load_locked::<NewView<S::Operation>>(input)?),
+        6 => Payload::Reconfiguration(load_locked::<Reconfiguration>(input)?),
+        _ => return None,
+    };
+    Some(Record::Entry(Entry{
+        direction,
+        sender_id,
+        payload,
+    }))
+}
+
+// Records up to the first one a crash cut short, and the offset that one starts at.
+// A whole record that doesn't decode is corruption, not a crash
+fn read_records<S: StateMachine>(bytes: &[u8]) -> Result<(Vec<Record<S>>, usize), PbftError> {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    loop {
+        let mut input = &bytes[offset..];
+        let record = match u64::load(&mut input).and_then(|len| take(&mut input, usize::try_from(len).ok()?)) {
+            Some(record) => record,
+            None => break,
+        };
+        let mut record_input = record;
+        match decode_record(&mut record_input) {
+            Some(record) if record_input.is_empty() => records.push(record),
+            _ => return Err(PbftError::CorruptLog{offset: offset as u64}),
+        }
+        offset = bytes.len() - input.len();
+    }
+    Ok((records, offset))
+}
@@ -0,0 +1,170 @@
+#[cfg(test)]
+mod log_file_test {
+    use crate::dto::{Request,PrePrepare,Prepare,Checkpoint,ViewChange,PreparedCert,Reconfiguration,NodeRequest};
+    use crate::encoding::Persist;
+    use crate::error::PbftError;
+    use crate::kv_store::KvOperation;
+    use crate::node::{Payload,Node,StateSnapshot};
+    use crate::config::Config;
+    use crate::crypto::KeyRing;
+    use crate::state_machine::{StateMachine,TipMachine};
+    use crate::test_util::{temp_dir,new_request,new_nodes};
+    use crate::wal::{WriteAheadLog,Direction,Entry};
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::sync::{Arc,RwLock};
+    use std::sync::mpsc;
+
+    fn preprepare() -> PrePrepare<String> {
+        PrePrepare::new(0, 1, vec![new_request("a"), new_request("b")], 0)
+    }
+
+    fn reopen(dir: &std::path::Path) -> Vec<Entry<TipMachine>> {
+        WriteAheadLog::open(dir, 1).unwrap().2
+    }
+
+    #[test]
+    fn entries_should_survive_reopening() {
+        let dir = temp_dir("wal-reopen");
+        let (wal, _, entries) = WriteAheadLog::open::<TipMachine>(&dir, 1).unwrap();
+        assert!(entries.is_empty());
+        let preprepare = Arc::new(RwLock::new(preprepare()));
+        wal.append::<TipMachine>(Direction::Received, 0, &Payload::PrePrepare(preprepare.clone())).unwrap();
+        let prepare = preprepare.read().unwrap().make_prepare(1);
+        wal.append::<TipMachine>(Direction::Sent, 1, &Payload::Prepare(Arc::new(RwLock::new(prepare)))).unwrap();
+        let entries = reopen(&dir);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].get_direction(), Direction::Received);
+        assert_eq!(entries[0].get_sender_id(), 0);
+        match entries[0].get_payload() {
+            Payload::PrePrepare(found) => {
+                let found = found.read().unwrap();
+                assert_eq!(found.get_batch(), preprepare.read().unwrap().get_batch());
+                assert!(found.is_digest_valid());
+            },
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(entries[1].get_direction(), Direction::Sent);
+        match entries[1].get_payload() {
+            Payload::Prepare(found) => assert_eq!(found.read().unwrap().get_digest(), preprepare.read().unwrap().get_digest()),
+            other => panic!("{:?}", other),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn client_traffic_should_not_be_logged() {
+        let dir = temp_dir("wal-requests");
+        let (wal, _, _) = WriteAheadLog::open::<TipMachine>(&dir, 1).unwrap();
+        wal.append::<TipMachine>(Direction::Received, 100, &Payload::Request(Arc::new(RwLock::new(new_request("a"))))).unwrap();
+        assert!(reopen(&dir).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reconfigurations_should_be_logged() {
+        let dir = temp_dir("wal-reconfiguration");
+        let (wal, _, _) = WriteAheadLog::open::<TipMachine>(&dir, 1).unwrap();
+        let reconfiguration = Arc::new(RwLock::new(Reconfiguration::RemoveReplica(3)));
+        wal.append::<TipMachine>(Direction::Received, 1, &Payload::Reconfiguration(reconfiguration)).unwrap();
+        match reopen(&dir)[0].get_payload() {
+            Payload::Reconfiguration(found) => assert_eq!(*found.read().unwrap(), Reconfiguration::RemoveReplica(3)),
+            other => panic!("{:?}", other),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn record_cut_short_should_be_dropped() {
+        let dir = temp_dir("wal-torn");
+        let (wal, _, _) = WriteAheadLog::open::<TipMachine>(&dir, 1).unwrap();
+        let checkpoint = Arc::new(RwLock::new(Checkpoint::new(100, "digest".to_owned(), 1)));
+        wal.append::<TipMachine>(Direction::Sent, 1, &Payload::Checkpoint(checkpoint.clone())).unwrap();
+        let length = fs::metadata(wal.get_path()).unwrap().len();
+        // the crash hit in the middle of the next record
+        OpenOptions::new().append(true).open(wal.get_path()).unwrap().write_all(&[0, 0, 0, 0, 0, 0, 1, 0, 1]).unwrap();
+        let (wal, _, entries) = WriteAheadLog::open::<TipMachine>(&dir, 1).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(fs::metadata(wal.get_path()).unwrap().len(), length);
+        wal.append::<TipMachine>(Direction::Sent, 1, &Payload::Checkpoint(checkpoint)).unwrap();
+        assert_eq!(reopen(&dir).len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compacted_log_should_start_from_checkpoint() {
+        let dir = temp_dir("wal-compact");
+        let (mut wal, _, _) = WriteAheadLog::open::<TipMachine>(&dir, 1).unwrap();
+        for seq_id in 1..=3 {
+            let checkpoint = Checkpoint::new(seq_id, "digest".to_owned(), 1);
+            wal.append::<TipMachine>(Direction::Sent, 1, &Payload::Checkpoint(Arc::new(RwLock::new(checkpoint)))).unwrap();
+        }
+        let mut machine = TipMachine::new();
+        machine.apply(&"b".to_owned());
+        let proof = vec![Checkpoint::new(2, "digest".to_owned(), 0)];
+        let snapshot = StateSnapshot::<TipMachine>::new(machine.snapshot(), Default::default());
+        let later = |entry: &Entry<TipMachine>| match entry.get_payload() {
+            Payload::Checkpoint(checkpoint) => checkpoint.read().unwrap().get_seq_id() > 2,
+            _ => true,
+        };
+        wal.compact(&(2, proof, snapshot), later).unwrap();
+        // appends go to the compacted log
+        wal.append::<TipMachine>(Direction::Received, 0, &Payload::Reconfiguration(Arc::new(RwLock::new(Reconfiguration::RemoveReplica(3))))).unwrap();
+        let (_, checkpoint, entries) = WriteAheadLog::open::<TipMachine>(&dir, 1).unwrap();
+        let (seq_id, found, snapshot) = checkpoint.unwrap();
+        assert_eq!(seq_id, 2);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].get_seq_id(), 2);
+        assert_eq!(*snapshot.get_machine(), machine.snapshot());
+        assert_eq!(entries.len(), 2);
+        match entries[0].get_payload() {
+            Payload::Checkpoint(found) => assert_eq!(found.read().unwrap().get_seq_id(), 3),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(entries[1].get_sender_id(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_record_should_be_reported() {
+        let dir = temp_dir("wal-corrupt");
+        fs::write(WriteAheadLog::path(&dir, 1), [0, 0, 0, 0, 0, 0, 0, 2, 7, 7]).unwrap();
+        assert_eq!(WriteAheadLog::open::<TipMachine>(&dir, 1).err(), Some(PbftError::CorruptLog{offset: 0}));
+        // a replica doesn't start from a log it can't read
+        let nodes = new_nodes(4);
+        let key_ring = KeyRing::generate(&nodes, 0);
+        let config = Config{wal_dir: Some(dir.clone()), ..Config::default()};
+        let (sender, _receiver) = mpsc::channel();
+        let res = Node::try_spawn(1, &nodes, sender, &config, key_ring.get_credentials(1).unwrap(), TipMachine::new());
+        assert_eq!(res.err(), Some(PbftError::CorruptLog{offset: 0}));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn persisted_messages_should_load_back() {
+        let view_change: ViewChange<KvOperation> = ViewChange::new(
+            2,
+            0,
+            vec![Checkpoint::new(0, "digest".to_owned(), 3)],
+            vec![PreparedCert::new(
+                PrePrepare::new(1, 1, vec![Request::new(KvOperation::CompareAndSwap{
+                    key: "k".to_owned(),
+                    expected: None,
+                    value: "v".to_owned(),
+                }, 1, 100)], 1),
+                vec![Prepare::new(1, 1, "digest".to_owned(), 2)],
+            )],
+            3);
+        let mut bytes = Vec::new();
+        view_change.persist(&mut bytes);
+        let mut input = bytes.as_slice();
+        let loaded: ViewChange<KvOperation> = ViewChange::load(&mut input).unwrap();
+        assert!(input.is_empty());
+        let mut reloaded = Vec::new();
+        loaded.persist(&mut reloaded);
+        assert_eq!(reloaded, bytes);
+        // anything cut short doesn't load
+        assert!(ViewChange::<KvOperation>::load(&mut &bytes[..bytes.len() - 1]).is_none());
+    }
+}